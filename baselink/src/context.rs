@@ -41,3 +41,22 @@ pub(crate) fn set_module_config(ctx: Config) {
 pub(crate) fn remove_module_config() {
     context_provider_mod::remove()
 }
+
+mod ids {
+    use fml::{context_provider, IdMap};
+
+    context_provider! {IdMap}
+}
+
+/// Identifiers of the traits and the methods, given by the host
+pub fn get_id_map() -> &'static fml::IdMap {
+    ids::context_provider_mod::get()
+}
+
+pub(crate) fn set_id_map(ctx: fml::IdMap) {
+    ids::context_provider_mod::set(ctx)
+}
+
+pub(crate) fn remove_id_map() {
+    ids::context_provider_mod::remove()
+}
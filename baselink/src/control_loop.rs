@@ -18,17 +18,20 @@ use crate::bootstrap::*;
 use crate::context::*;
 use cbsb::execution::executee;
 use cbsb::ipc::{intra, servo_channel::ServoChannel as DefaultIpc, Ipc};
+use fml::codec::{Cbor, Codec};
 use fml::*;
 use parking_lot::RwLock;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+// Messages from and to the host are always in CBOR, regardless of the codecs negotiated for the ports.
+
 pub fn recv<I: Ipc, T: serde::de::DeserializeOwned>(ctx: &executee::Context<I>) -> T {
-    serde_cbor::from_slice(&ctx.ipc.as_ref().unwrap().recv(None).unwrap()).unwrap()
+    Cbor.decode(&ctx.ipc.as_ref().unwrap().recv(None).unwrap()).unwrap()
 }
 
 pub fn send<I: Ipc, T: serde::Serialize>(ctx: &executee::Context<I>, data: &T) {
-    ctx.ipc.as_ref().unwrap().send(&serde_cbor::to_vec(data).unwrap());
+    ctx.ipc.as_ref().unwrap().send(&Cbor.to_vec(data).unwrap());
 }
 
 fn create_port(
@@ -38,8 +41,8 @@ fn create_port(
     dispatcher: Arc<PortDispatcher>,
     instance_key: InstanceKey,
     config_fml: &FmlConfig,
-) -> Port {
-    let ipc_type: String = serde_cbor::from_slice(&ipc_type).unwrap();
+) -> Result<Port, ProtocolError> {
+    let ipc_type: String = Cbor.decode(&ipc_type).unwrap();
 
     if ipc_type == "DomainSocket" {
         let ipc = DefaultIpc::new(ipc_config);
@@ -57,9 +60,10 @@ fn create_port(
 pub type DebugFunction = Box<dyn Fn(Vec<u8>) -> Vec<u8>>;
 /// initializer will be called after the module configuration is setup.
 /// Please initialize your own custom context using it.
+/// If it fails, the reason is reported to the host in place of "done".
 pub fn run_control_loop<I: Ipc, H: HandlePreset>(
     args: Vec<String>,
-    initializer: Box<dyn Fn() -> ()>,
+    initializer: Box<dyn Fn() -> Result<(), String>>,
     debug: Option<DebugFunction>,
 ) {
     let ctx = executee::start::<I>(args);
@@ -75,10 +79,13 @@ pub fn run_control_loop<I: Ipc, H: HandlePreset>(
     let ports = RwLock::new(PortTable {
         config_fml: config_fml.clone(),
         map: HashMap::new(),
+        names: Default::default(),
     });
     global::set(ports);
     crate::context::set_module_config(config);
-    initializer();
+    crate::context::set_id_map(id_map);
+    crate::exchange::set_context(Default::default());
+    let initialized = initializer();
     termination::set(std::sync::atomic::AtomicBool::new(false));
 
     loop {
@@ -86,16 +93,18 @@ pub fn run_control_loop<I: Ipc, H: HandlePreset>(
         if message == "link" {
             let (port_id, counter_port_id, counter_module_id, ipc_type, ipc_config) = recv(&ctx);
             let dispather = Arc::new(PortDispatcher::new(port_id, 128));
+            // This waits for the counterparty, so it must not hold the port table.
+            let port = match create_port(port_id, ipc_type, ipc_config, dispather, instance_key, &config_fml) {
+                Ok(port) => port,
+                Err(e) => {
+                    // Reported instead of "done", so that the host can tell why.
+                    send(&ctx, &format!("Failed to link port {}: {}", port_id, e));
+                    continue
+                }
+            };
             let mut port_table = global::get().write();
 
-            let old = port_table.map.insert(
-                port_id,
-                (
-                    counter_module_id,
-                    counter_port_id,
-                    create_port(port_id, ipc_type, ipc_config, dispather, instance_key, &config_fml),
-                ),
-            );
+            let old = port_table.map.insert(port_id, (counter_module_id, counter_port_id, port));
             // we assert before drop old to avoid (hard-to-debug) blocking.
             assert!(old.is_none(), "You must unlink first to link an existing port");
         } else if message == "unlink" {
@@ -111,6 +120,13 @@ pub fn run_control_loop<I: Ipc, H: HandlePreset>(
             // import a default, preset handles for a specific port
             let (handles,) = recv(&ctx);
             H::import(handles);
+        } else if message == "handle_offers" {
+            // hand the handles offered since the last time over to the host
+            send(&ctx, &crate::exchange::take_offers());
+        } else if message == "handle_deliver" {
+            // deliver a handle offered by another module
+            let (offer,) = recv(&ctx);
+            crate::exchange::deliver(offer);
         } else if message == "debug" {
             // temporarily give the execution flow to module, and the module
             // may do whatever it wants but must return a result to report back
@@ -121,9 +137,14 @@ pub fn run_control_loop<I: Ipc, H: HandlePreset>(
         } else {
             panic!("Unexpected message: {}", message)
         }
-        send(&ctx, &"done".to_owned());
+        match &initialized {
+            Ok(()) => send(&ctx, &"done".to_owned()),
+            Err(reason) => send(&ctx, &format!("Failed to initialize: {}", reason)),
+        }
     }
     termination::get().store(true, std::sync::atomic::Ordering::Relaxed);
+    crate::exchange::remove_context();
     crate::context::remove_module_config();
+    crate::context::remove_id_map();
     ctx.terminate();
 }
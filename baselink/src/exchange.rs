@@ -0,0 +1,158 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Handles offered by modules at runtime, after the preset exchange at the bootstrap.
+//!
+//! A module offers a handle on a topic to another module at any time, and the host collects the offers and
+//! delivers them to the importers whenever it brokers.
+//! The importer accepts a topic with a callback, which is called for each handle offered on it.
+//! The offers that arrive before the topic is accepted are kept until it is.
+
+use crate::bootstrap::find_port_id;
+use crate::context::get_module_config;
+use fml::context_provider;
+use fml::env::service_context::delete;
+use fml::env::{ExportService, IdOfService, ImportService};
+use fml::{HandleInstance, Service, TraitId};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A handle offered by a module at runtime
+#[derive(PartialEq, Serialize, Deserialize, Debug)]
+pub struct HandleOffer {
+    /// Id of exporter (same as that in Config)
+    pub exporter: String,
+    /// Id of importer (same as that in Config)
+    pub importer: String,
+    /// What the handle is for, by which the importer accepts it
+    pub topic: String,
+    /// Id of the trait the handle is exported as
+    pub trait_id: TraitId,
+    pub handle: HandleInstance,
+}
+
+/// Reason why a handle couldn't be offered
+#[derive(PartialEq, Debug, Clone)]
+pub enum OfferError {
+    /// This module is not linked to the importer.
+    NotLinked(String),
+}
+
+impl std::fmt::Display for OfferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OfferError::NotLinked(importer) => write!(f, "Not linked to {}", importer),
+        }
+    }
+}
+
+impl std::error::Error for OfferError {}
+
+type Callback = Arc<dyn Fn(String, HandleInstance) + Send + Sync>;
+
+#[derive(Default)]
+pub struct Exchanges {
+    /// Offers of this module, which the host hasn't collected yet
+    offers: Mutex<Vec<HandleOffer>>,
+    /// (Trait id, Callback) for each topic accepted
+    acceptors: Mutex<HashMap<String, (TraitId, Callback)>>,
+    /// Offers delivered to this module, of which the topics aren't accepted yet
+    pending: Mutex<Vec<HandleOffer>>,
+}
+
+context_provider! {Exchanges}
+fn get_context() -> &'static Exchanges {
+    context_provider_mod::get()
+}
+
+pub(crate) fn set_context(ctx: Exchanges) {
+    context_provider_mod::set(ctx)
+}
+
+pub(crate) fn remove_context() {
+    context_provider_mod::remove()
+}
+
+/// Offers the service to the importer on the topic. It is delivered when the host brokers next time.
+///
+/// Returns Err if this module is not linked to the importer.
+pub fn offer<T>(importer: &str, topic: &str, object: Arc<T>) -> Result<(), OfferError>
+where
+    T: ?Sized + Service + ExportService<T> + IdOfService<T>, {
+    let port_id = find_port_id(importer).map_err(|_| OfferError::NotLinked(importer.to_owned()))?;
+    let handle = T::export(port_id, object);
+    get_context().offers.lock().push(HandleOffer {
+        exporter: get_module_config().id.clone(),
+        importer: importer.to_owned(),
+        topic: topic.to_owned(),
+        trait_id: T::id(),
+        handle,
+    });
+    Ok(())
+}
+
+/// Accepts the services offered on the topic, calling the callback with the exporter and the service for each.
+///
+/// The ones offered already are given to the callback right away.
+/// Accepting the same topic again replaces the callback.
+pub fn accept<T, F>(topic: &str, callback: F)
+where
+    T: ?Sized + Service + ImportService<T> + IdOfService<T>,
+    F: Fn(String, Arc<T>) + Send + Sync + 'static, {
+    let callback: Callback = Arc::new(move |exporter, handle| callback(exporter, T::import(handle)));
+    get_context().acceptors.lock().insert(topic.to_owned(), (T::id(), callback));
+
+    let pending = {
+        let mut guard = get_context().pending.lock();
+        let (accepted, pending) = guard.drain(..).partition(|offer: &HandleOffer| offer.topic == topic);
+        *guard = pending;
+        accepted
+    };
+    for offer in pending {
+        deliver(offer)
+    }
+}
+
+/// Takes the offers of this module for the host to deliver.
+pub(crate) fn take_offers() -> Vec<HandleOffer> {
+    std::mem::take(&mut *get_context().offers.lock())
+}
+
+/// Gives the offer to the callback of its topic, or keeps it until the topic is accepted.
+///
+/// An offer that can't be taken is dropped, deleting the handle so that the exporter releases the object.
+pub(crate) fn deliver(offer: HandleOffer) {
+    let id = &get_module_config().id;
+    if offer.importer != *id {
+        log::warn!("Dropped the offer from {} to {}, delivered to {}", offer.exporter, offer.importer, id);
+        delete(&offer.handle);
+        return
+    }
+    let acceptor = get_context().acceptors.lock().get(&offer.topic).cloned();
+    match acceptor {
+        Some((trait_id, _)) if trait_id != offer.trait_id => {
+            log::warn!("Dropped the offer from {} as another trait on the topic {}", offer.exporter, offer.topic);
+            delete(&offer.handle);
+        }
+        Some((_, callback)) => {
+            // The callback may offer or accept by itself, so it is called without the locks.
+            callback(offer.exporter, offer.handle)
+        }
+        None => get_context().pending.lock().push(offer),
+    }
+}
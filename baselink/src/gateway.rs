@@ -0,0 +1,264 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A module that lets the scripts outside call the services of the modules linked to it,
+//! without being built with their traits.
+//!
+//! It listens on a Unix socket, where each line is a request in JSON,
+//! answered by a line of `{"Ok": result}` or `{"Err": "reason"}`.
+//!
+//! ```text
+//! {"Lookup": {"name": "Module0/factory", "trait": "HelloFactory"}}
+//! {"Call": {"handle": 1, "method": "create", "arguments": ["Robot"]}}
+//! {"Release": {"handle": 2}}
+//! "Services"
+//! ```
+//!
+//! A handle is given as `{"handle": 1, "trait": "HelloFactory"}`. See `fml::Invoker` for the details.
+//! The traits are known from the schemas that the module binaries write with `--schema`.
+
+use crate::bootstrap::{HandleExchange, HandlePreset};
+use crate::context::{get_id_map, get_module_config};
+use crate::control_loop::run_control_loop;
+use fml::{context_provider, InvokeError, Invoker, Schema};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Arguments of the gateway, given by the host in `Config::args`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GatewayConfig {
+    /// Path of the Unix socket to listen on
+    pub socket: String,
+    /// Paths of the schemas of the modules to call
+    pub schemas: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum GatewayRequest {
+    /// Finds the service published under the name, which is null if there is none.
+    Lookup {
+        name: String,
+        #[serde(rename = "trait")]
+        trait_name: String,
+    },
+    Call {
+        handle: u64,
+        method: String,
+        arguments: Vec<Value>,
+    },
+    Release {
+        handle: u64,
+    },
+    /// Lists the traits with their methods.
+    Services,
+}
+
+/// How long the listener waits after failing to accept a client, before trying again
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// A stream to shut down and the thread serving it, for each client connected
+type Clients = Arc<Mutex<HashMap<usize, (UnixStream, thread::JoinHandle<()>)>>>;
+
+struct Serving {
+    socket: String,
+    /// The handles it holds are released when the gateway stops.
+    _invoker: Arc<Invoker>,
+    stop: Arc<AtomicBool>,
+    listener: thread::JoinHandle<()>,
+    clients: Clients,
+}
+
+pub struct Gateway {
+    /// None if it has failed to start, or has stopped
+    serving: Mutex<Option<Serving>>,
+}
+
+context_provider! {Gateway}
+fn get_context() -> &'static Gateway {
+    context_provider_mod::get()
+}
+
+fn handle(invoker: &Invoker, request: GatewayRequest) -> Result<Value, InvokeError> {
+    match request {
+        GatewayRequest::Lookup {
+            name,
+            trait_name,
+        } => Ok(invoker.lookup(&name, &trait_name)?.unwrap_or(Value::Null)),
+        GatewayRequest::Call {
+            handle,
+            method,
+            arguments,
+        } => invoker.invoke(handle, &method, &arguments),
+        GatewayRequest::Release {
+            handle,
+        } => invoker.release(handle).map(|_| Value::Null),
+        GatewayRequest::Services => {
+            Ok(serde_json::to_value(invoker.services()).expect("Descriptors are always serializable"))
+        }
+    }
+}
+
+/// Answers the requests of a client until it disconnects.
+fn serve(invoker: &Invoker, stream: UnixStream) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let response: Result<Value, String> = serde_json::from_str(&line)
+            .map_err(|e| format!("Invalid request: {}", e))
+            .and_then(|request| handle(invoker, request).map_err(|e| e.to_string()));
+        let mut response = serde_json::to_string(&response).expect("Response is always serializable");
+        response.push('\n');
+        if writer.write_all(response.as_bytes()).is_err() {
+            return
+        }
+    }
+}
+
+/// Listens on the socket, replacing the one left over by a gateway that has gone.
+fn listen(path: &str) -> std::io::Result<UnixListener> {
+    if std::path::Path::new(path).exists() && UnixStream::connect(path).is_err() {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+fn start() -> Result<Serving, String> {
+    let config: GatewayConfig =
+        serde_cbor::from_slice(&get_module_config().args).map_err(|e| format!("Invalid gateway config: {}", e))?;
+    let schemas = config
+        .schemas
+        .iter()
+        .map(|path| {
+            let json = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            serde_json::from_slice(&json).map_err(|e| format!("Invalid schema {}: {}", path, e))
+        })
+        .collect::<Result<Vec<Schema>, String>>()?;
+    let invoker = Arc::new(Invoker::new(schemas, get_id_map().clone()));
+    let listener = listen(&config.socket).map_err(|e| format!("Failed to listen on {}: {}", config.socket, e))?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let clients: Clients = Default::default();
+
+    // The clients are served in their own threads, which must find the context of this instance.
+    let key = fml::get_key();
+    let invoker_ = invoker.clone();
+    let stop_ = stop.clone();
+    let clients_ = clients.clone();
+    let listener = thread::spawn(move || {
+        fml::set_key(key);
+        for (index, stream) in listener.incoming().enumerate() {
+            if stop_.load(Ordering::SeqCst) {
+                break
+            }
+            let (shutter, stream) = match stream.and_then(|stream| Ok((stream.try_clone()?, stream))) {
+                Ok(streams) => streams,
+                Err(e) => {
+                    // Such as running out of file descriptors, which takes a while to recover from.
+                    log::warn!("Failed to accept a gateway client: {}", e);
+                    thread::sleep(ACCEPT_BACKOFF);
+                    continue
+                }
+            };
+            let invoker = invoker_.clone();
+            let clients = clients_.clone();
+            // Held until the client is added, so that it is removed only after that.
+            let mut guard = clients_.lock();
+            let client = thread::spawn(move || {
+                fml::set_key(key);
+                serve(&invoker, stream);
+                clients.lock().remove(&index);
+            });
+            guard.insert(index, (shutter, client));
+        }
+    });
+    Ok(Serving {
+        socket: config.socket,
+        _invoker: invoker,
+        stop,
+        listener,
+        clients,
+    })
+}
+
+fn initializer() -> Result<(), String> {
+    // The context is set even if it fails to start, for the shutdown to find.
+    let (serving, result) = match start() {
+        Ok(serving) => (Some(serving), Ok(())),
+        Err(reason) => (None, Err(reason)),
+    };
+    context_provider_mod::set(Gateway {
+        serving: Mutex::new(serving),
+    });
+    result
+}
+
+fn shutdown() {
+    if let Some(serving) = get_context().serving.lock().take() {
+        serving.stop.store(true, Ordering::SeqCst);
+        // Wakes the listener up to see that it has stopped.
+        UnixStream::connect(&serving.socket).ok();
+        serving.listener.join().unwrap();
+        // No client comes anymore, and the ones connected stop being served once their streams are shut down.
+        let clients: Vec<_> = serving.clients.lock().drain().map(|(_, client)| client).collect();
+        for (stream, client) in clients {
+            stream.shutdown(Shutdown::Both).ok();
+            client.join().unwrap();
+        }
+        std::fs::remove_file(&serving.socket).ok();
+    }
+    context_provider_mod::remove();
+}
+
+/// The gateway finds the services by the names, so it neither exports nor imports at the bootstrap.
+pub struct Preset;
+
+impl HandlePreset for Preset {
+    fn export() -> Vec<HandleExchange> {
+        Vec::new()
+    }
+
+    fn import(_exchange: HandleExchange) {
+        panic!("The gateway doesn't import any handle at the bootstrap")
+    }
+}
+
+#[cfg(feature = "single_process")]
+pub fn gateway_main(args: Vec<String>) {
+    run_control_loop::<cbsb::ipc::intra::Intra, Preset>(args, Box::new(initializer), None);
+    shutdown();
+    fml::global::remove();
+}
+
+#[cfg(not(feature = "single_process"))]
+pub fn gateway_main(args: Vec<String>) {
+    run_control_loop::<cbsb::ipc::servo_channel::ServoChannel, Preset>(args, Box::new(initializer), None);
+    shutdown();
+    fml::global::remove();
+}
@@ -20,8 +20,14 @@ extern crate codechain_fml as fml;
 mod bootstrap;
 mod context;
 mod control_loop;
+mod exchange;
+#[cfg(unix)]
+mod gateway;
 pub mod prelude;
 
 pub use bootstrap::{find_port_id, HandleExchange, HandlePreset};
-pub use context::{get_module_config, Config};
+pub use context::{get_id_map, get_module_config, Config};
 pub use control_loop::run_control_loop;
+pub use exchange::{accept, offer, HandleOffer, OfferError};
+#[cfg(unix)]
+pub use gateway::{gateway_main, GatewayConfig, GatewayRequest};
@@ -2,7 +2,11 @@ extern crate fml_tests;
 
 #[cfg(all(unix, target_arch = "x86_64"))]
 fn main() -> Result<(), String> {
-    let args = std::env::args().collect();
+    let args: Vec<String> = std::env::args().collect();
+    // Asked so, the module writes the schema of its services instead of running.
+    if args.len() == 3 && args[1] == "--schema" {
+        return codechain_fml::write_schema(&args[2]).map_err(|e| format!("Failed to write the schema: {}", e))
+    }
     fml_tests::mod_hello_main(args);
     Ok(())
 }
@@ -30,6 +30,10 @@ impl HelloFactory for Factory {
             name: name.to_string(),
         }))
     }
+
+    fn echo(&self, robot: SArc<dyn HelloRobot>) -> SArc<dyn HelloRobot> {
+        robot
+    }
 }
 
 #[fml_macro::service_impl(HelloRobot)]
@@ -21,12 +21,15 @@ use baselink::*;
 use fml::*;
 use impls::*;
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
 
 pub struct MyContext {
     number: usize,
     factories: RwLock<HashMap<String, Arc<dyn HelloFactory>>>,
+    /// Factories offered by the others after the bootstrap
+    offered: RwLock<HashMap<String, Arc<dyn HelloFactory>>>,
 }
 
 context_provider! {MyContext}
@@ -40,7 +43,7 @@ pub fn remove_context() {
     context_provider_mod::remove()
 }
 
-pub fn initializer() {
+pub fn initializer() -> Result<(), String> {
     let config = get_module_config();
     let number = serde_cbor::from_slice(&config.args).unwrap();
     let mut factories = HashMap::new();
@@ -53,7 +56,18 @@ pub fn initializer() {
     set_context(MyContext {
         number,
         factories: RwLock::new(factories),
+        offered: Default::default(),
     });
+    accept("factory", |exporter, factory: Arc<dyn HelloFactory>| {
+        get_context().offered.write().insert(exporter, factory);
+    });
+    assert!(publish::<dyn HelloFactory>(
+        &format!("{}/factory", config.id),
+        Arc::new(Factory {
+            handle: Default::default(),
+        })
+    ));
+    Ok(())
 }
 
 pub struct Preset;
@@ -95,7 +109,27 @@ impl HandlePreset for Preset {
     }
 }
 
-pub fn initiate(_arg: Vec<u8>) -> Vec<u8> {
+/// What the host asks this module to do, given to `initiate()` in CBOR
+#[derive(Serialize, Deserialize)]
+pub enum Command {
+    /// Calls the others through the handles exchanged at the bootstrap and the names they publish
+    Hello,
+    /// Offers a factory to each of the others
+    Offer,
+    /// Calls the others through the factories they have offered
+    CheckOffered,
+}
+
+pub fn initiate(arg: Vec<u8>) -> Vec<u8> {
+    match serde_cbor::from_slice(&arg).unwrap() {
+        Command::Hello => hello(),
+        Command::Offer => offer_factories(),
+        Command::CheckOffered => check_offered(),
+    }
+    Vec::new()
+}
+
+fn hello() {
     let ctx = get_context();
     let guard = ctx.factories.read();
 
@@ -106,7 +140,61 @@ pub fn initiate(_arg: Vec<u8>) -> Vec<u8> {
             assert_eq!(robot.hello(10 - i), format!("Robot{}{}", i, 10 - i));
         }
     }
-    Vec::new()
+
+    // A robot made by one module comes back through another one, which forwards the calls to it.
+    for n in 0..ctx.number {
+        let factory = guard.get(&format!("Module{}", n)).unwrap();
+        let relay = guard.get(&format!("Module{}", (n + 1) % ctx.number)).unwrap();
+        let robot = relay.echo(factory.create("Echoed")).unwrap();
+        assert_eq!(robot.hello(n as i32), format!("Echoed{}", n));
+
+        // Sent back to the module that made it, it is resolved to the robot itself there.
+        let robot = factory.echo(factory.create("Returned")).unwrap();
+        assert_eq!(robot.hello(n as i32), format!("Returned{}", n));
+    }
+
+    // A factory can be found by its name as well, without the handles exchanged at the bootstrap.
+    for n in 0..ctx.number {
+        let factory = service_lookup!(HelloFactory, &format!("Module{}/factory", n)).unwrap().unwrap();
+        let robot = factory.create("Found").unwrap();
+        assert_eq!(robot.hello(n as i32), format!("Found{}", n));
+    }
+    assert!(service_lookup!(HelloFactory, "Nothing").unwrap().is_none());
+}
+
+fn offer_factories() {
+    let ctx = get_context();
+    for n in 0..ctx.number {
+        let importer = format!("Module{}", n);
+        if importer == get_module_config().id {
+            continue
+        }
+        let factory = Arc::new(Factory {
+            handle: Default::default(),
+        });
+        offer::<dyn HelloFactory>(&importer, "factory", factory).unwrap();
+    }
+    assert_eq!(
+        offer::<dyn HelloFactory>(
+            "Nobody",
+            "factory",
+            Arc::new(Factory {
+                handle: Default::default(),
+            })
+        ),
+        Err(OfferError::NotLinked("Nobody".to_owned()))
+    );
+}
+
+fn check_offered() {
+    let ctx = get_context();
+    let offered = ctx.offered.read();
+    assert_eq!(offered.len(), ctx.number - 1);
+    for (exporter, factory) in &*offered {
+        let robot = factory.create("Offered").unwrap();
+        assert_eq!(robot.hello(0), "Offered0");
+        assert_ne!(exporter, &get_module_config().id);
+    }
 }
 
 #[cfg(feature = "single_process")]
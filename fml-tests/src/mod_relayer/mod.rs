@@ -47,7 +47,7 @@ pub fn remove_context() {
     context_provider_mod::remove()
 }
 
-pub fn initializer() {
+pub fn initializer() -> Result<(), String> {
     let config = get_module_config();
     let (number, index) = serde_cbor::from_slice(&config.args).unwrap();
     let mut factories = HashMap::new();
@@ -63,7 +63,8 @@ pub fn initializer() {
         schedule: Default::default(),
         factories: RwLock::new(factories),
         answers: Default::default(),
-    })
+    });
+    Ok(())
 }
 pub struct Preset;
 
@@ -41,7 +41,7 @@ pub fn remove_context() {
     context_provider_mod::remove()
 }
 
-pub fn initializer() {
+pub fn initializer() -> Result<(), String> {
     let config = baselink::get_module_config();
     let (number, threads): (usize, usize) = serde_cbor::from_slice(&config.args).unwrap();
     let map = new_avail_map(number, threads);
@@ -51,6 +51,7 @@ pub fn initializer() {
         lock: Mutex::new(true),
         cvar: Condvar::new(),
     });
+    Ok(())
 }
 
 pub struct Preset;
@@ -59,19 +60,17 @@ impl HandlePreset for Preset {
     fn export() -> Vec<HandleExchange> {
         let ctx = get_context();
         let mut result = Vec::new();
+        // All the modules share a single schedule.
+        let schedule = Arc::new(MySchedule {
+            handle: Default::default(),
+        });
         for i in 0..ctx.number {
             let importer = format!("Module{}", i);
 
             result.push(HandleExchange {
                 exporter: "Schedule".to_owned(),
                 importer: importer.clone(),
-                handles: vec![service_export!(
-                    Schedule,
-                    find_port_id(&importer).unwrap(),
-                    Arc::new(MySchedule {
-                        handle: Default::default(),
-                    })
-                )],
+                handles: vec![service_export!(Schedule, find_port_id(&importer).unwrap(), schedule.clone())],
                 argument: Vec::new(),
             })
         }
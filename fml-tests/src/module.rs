@@ -44,6 +44,9 @@ impl<I: Ipc, E: Executor> FmlModule<I, E> {
         let config_fml = FmlConfig {
             server_threads: SERVER_THREADS,
             call_slots: 128,
+            codec: CodecKind::Cbor,
+            chunk_size: 64 * 1024,
+            max_message_size: 1024 * 1024 * 1024,
         };
 
         //let module_name = generate_random_name();
@@ -178,3 +181,19 @@ pub fn exchange<I: Ipc, E: Executor>(modules: &Modules<I, E>) {
         }
     }
 }
+
+/// Deliver the handles that the modules have offered since the last brokering
+pub fn broker<I: Ipc, E: Executor>(modules: &Modules<I, E>) {
+    for module in modules.values() {
+        module.send(&"handle_offers");
+        let offers: Vec<HandleOffer> = module.recv();
+        module.done_ack();
+
+        for offer in &offers {
+            let importer = modules.get(&offer.importer).unwrap();
+            importer.send(&"handle_deliver");
+            importer.send(&(&offer,));
+            importer.done_ack();
+        }
+    }
+}
@@ -22,6 +22,7 @@ pub use baselink::prelude::*;
 #[fml_macro::service]
 pub trait HelloFactory: fml::Service {
     fn create(&self, name: &str) -> SArc<dyn HelloRobot>;
+    fn echo(&self, robot: SArc<dyn HelloRobot>) -> SArc<dyn HelloRobot>;
 }
 
 #[fml_macro::service]
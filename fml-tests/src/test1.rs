@@ -15,27 +15,30 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::module::*;
+use crate::mod_hello::Command;
 use cbsb::execution::executor::{self, Executor};
 use cbsb::ipc::Ipc;
 use std::collections::HashMap;
 use std::sync::{Arc, Barrier};
 use std::thread;
 
+fn trait_map() -> HashMap<String, fml::TraitId> {
+    let mut map = HashMap::new();
+    map.insert("RelayerFactory".to_owned(), 1);
+    map.insert("RelayerMachine".to_owned(), 2);
+    map.insert("HelloFactory".to_owned(), 3);
+    map.insert("HelloRobot".to_owned(), 4);
+    map.insert("Schedule".to_owned(), 5);
+    map
+}
+
 pub fn run<I: Ipc + 'static + LinkMessage, E: Executor + 'static>(mod_path: &str, trial: usize, number: usize) {
     for _ in 0..trial {
         // If not there might be an inevitable deadlock
         assert!(number <= SERVER_THREADS);
 
         let args = serde_cbor::to_vec(&number).unwrap();
-        let trait_map = {
-            let mut map = HashMap::new();
-            map.insert("RelayerFactory".to_owned(), 1);
-            map.insert("RelayerMachine".to_owned(), 2);
-            map.insert("HelloFactory".to_owned(), 3);
-            map.insert("HelloRobot".to_owned(), 4);
-            map.insert("Schedule".to_owned(), 5);
-            map
-        };
+        let trait_map = trait_map();
 
         let mut modules = Modules::new();
 
@@ -54,7 +57,7 @@ pub fn run<I: Ipc + 'static + LinkMessage, E: Executor + 'static>(mod_path: &str
         for (_, module) in modules.drain() {
             let b = barrier.clone();
             joins.push(thread::spawn(move || {
-                module.debug(Vec::new());
+                module.debug(serde_cbor::to_vec(&Command::Hello).unwrap());
                 b.wait();
             }));
         }
@@ -120,6 +123,101 @@ fn fml_test_hello4() {
     }
 }
 
+#[test]
+fn fml_test_hello_offer() {
+    let k = start_test();
+    let hello = register();
+    let args = serde_cbor::to_vec(&3).unwrap();
+    let mut modules = Modules::new();
+    for i in 0..3 {
+        let name = format!("Module{}", i);
+        let ctx = executor::execute::<Intra, PlainThread>(&hello).unwrap();
+        modules.insert(name.clone(), FmlModule::new(ctx, trait_map(), name, args.clone()));
+    }
+    link_all(&modules);
+    exchange(&modules);
+
+    // Modules may offer handles at any time after the bootstrap, which the host delivers when it brokers.
+    for module in modules.values() {
+        module.debug(serde_cbor::to_vec(&Command::Offer).unwrap());
+    }
+    broker(&modules);
+    for module in modules.values() {
+        module.debug(serde_cbor::to_vec(&Command::CheckOffered).unwrap());
+    }
+
+    drop(modules);
+    end_test(k);
+}
+
+#[test]
+fn fml_test_gateway() {
+    use serde_json::{json, Value};
+    use std::io::{BufRead, BufReader, Write};
+
+    let k = start_test();
+    let hello = register();
+    let gateway = cbsb::ipc::generate_random_name();
+    executor::add_function_pool(gateway.clone(), Arc::new(baselink::gateway_main));
+
+    // This test is built with the traits, so its schema has them.
+    let dir = std::env::temp_dir();
+    let schema = dir.join(format!("{}.json", cbsb::ipc::generate_random_name())).to_str().unwrap().to_owned();
+    fml::write_schema(&schema).unwrap();
+    let socket = dir.join(format!("{}.sock", cbsb::ipc::generate_random_name())).to_str().unwrap().to_owned();
+    // A socket left over by a gateway that has gone is replaced.
+    drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+    let config = baselink::GatewayConfig {
+        socket: socket.clone(),
+        schemas: vec![schema.clone()],
+    };
+
+    let mut modules = Modules::new();
+    for i in 0..2 {
+        let name = format!("Module{}", i);
+        let ctx = executor::execute::<Intra, PlainThread>(&hello).unwrap();
+        modules.insert(name.clone(), FmlModule::new(ctx, trait_map(), name, serde_cbor::to_vec(&2).unwrap()));
+    }
+    let ctx = executor::execute::<Intra, PlainThread>(&gateway).unwrap();
+    let args = serde_cbor::to_vec(&config).unwrap();
+    modules.insert("Gateway".to_owned(), FmlModule::new(ctx, trait_map(), "Gateway".to_owned(), args));
+    link_all(&modules);
+    exchange(&modules);
+
+    let mut stream = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request = |request: &str| -> Value {
+        writeln!(stream, "{}", request).unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        serde_json::from_str(&response).unwrap()
+    };
+
+    let found = request(r#"{"Lookup": {"name": "Module1/factory", "trait": "HelloFactory"}}"#);
+    assert_eq!(found, json!({"Ok": {"handle": 1, "trait": "HelloFactory"}}));
+    let robot = request(r#"{"Call": {"handle": 1, "method": "create", "arguments": ["Robot"]}}"#);
+    assert_eq!(robot, json!({"Ok": {"handle": 2, "trait": "HelloRobot"}}));
+    assert_eq!(request(r#"{"Call": {"handle": 2, "method": "hello", "arguments": [7]}}"#), json!({"Ok": "Robot7"}));
+
+    // A handle passed back to the module it is from is the robot itself there.
+    let echoed = request(r#"{"Call": {"handle": 1, "method": "echo", "arguments": [{"handle": 2}]}}"#);
+    assert_eq!(echoed, json!({"Ok": {"handle": 3, "trait": "HelloRobot"}}));
+    assert_eq!(request(r#"{"Call": {"handle": 3, "method": "hello", "arguments": [1]}}"#), json!({"Ok": "Robot1"}));
+    assert!(request(r#"{"Call": {"handle": 2, "method": "hello", "arguments": [1]}}"#)["Err"].is_string());
+
+    assert!(request(r#"{"Call": {"handle": 1, "method": "create", "arguments": [1]}}"#)["Err"].is_string());
+    assert!(request(r#"{"Call": {"handle": 1, "method": "fly", "arguments": []}}"#)["Err"].is_string());
+    assert!(request(r#"{"Fly": null}"#)["Err"].is_string());
+    assert_eq!(request(r#"{"Release": {"handle": 3}}"#), json!({"Ok": null}));
+    assert_eq!(request(r#"{"Lookup": {"name": "Nothing", "trait": "HelloFactory"}}"#), json!({"Ok": null}));
+    let services = request(r#""Services""#);
+    assert!(services["Ok"].as_array().unwrap().iter().any(|service| service["name"] == "HelloRobot"));
+
+    drop(modules);
+    std::fs::remove_file(&schema).unwrap();
+    end_test(k);
+}
+
 #[test]
 fn fml_test_hello_binary1() {
     for _ in 0..3 {
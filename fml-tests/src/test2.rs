@@ -140,3 +140,24 @@ fn fml_test_relay_binary2() {
         end_test(k);
     }
 }
+
+#[test]
+fn relayer_schema() {
+    use fml::{Shape, Variant};
+
+    let schema = fml::schema();
+    let factory = schema.services.iter().find(|service| service.name == "RelayerFactory").unwrap();
+    let signatures: Vec<String> = factory.methods.iter().map(|method| method.signature()).collect();
+    assert_eq!(signatures, vec![
+        "create(key: String, current: usize, destination: String) -> SArc<dyn RelayerMachine>",
+        "ask_path(key: String, current: usize) -> Answer",
+    ]);
+    assert_eq!(factory.methods[0].output_shape, Some(Shape::Handle("RelayerMachine".to_owned())));
+    assert_eq!(
+        factory.methods[1].output_shape,
+        Some(Shape::Enum("Answer".to_owned(), vec![
+            ("Next".to_owned(), Variant::Newtype(Shape::Str)),
+            ("End".to_owned(), Variant::Newtype(Shape::Str)),
+        ]))
+    );
+}
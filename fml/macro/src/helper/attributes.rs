@@ -0,0 +1,73 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Method attributes in the form of `#[fml(...)]`, which are consumed by the macro.
+
+use proc_macro2::TokenStream as TokenStream2;
+
+#[derive(Default, PartialEq, Debug)]
+pub struct MethodAttributes {
+    /// `#[fml(oneway)]`: The caller sends the call and doesn't wait for any response.
+    pub oneway: bool,
+}
+
+fn is_ours(attr: &syn::Attribute) -> bool {
+    attr.path.is_ident("fml")
+}
+
+pub fn parse(method: &syn::TraitItemMethod) -> Result<MethodAttributes, TokenStream2> {
+    let mut result = MethodAttributes::default();
+    for attr in method.attrs.iter().filter(|attr| is_ours(attr)) {
+        let list = match attr.parse_meta() {
+            Ok(syn::Meta::List(list)) => list,
+            _ => return Err(syn::Error::new_spanned(attr, "Expected #[fml(...)]").to_compile_error()),
+        };
+        for nested in list.nested.iter() {
+            match nested {
+                syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.is_ident("oneway") => result.oneway = true,
+                _ => return Err(syn::Error::new_spanned(nested, "Unknown fml attribute").to_compile_error()),
+            }
+        }
+    }
+
+    if result.oneway {
+        if method.sig.asyncness.is_some() {
+            return Err(syn::Error::new_spanned(method, "One-way methods can't be async").to_compile_error())
+        }
+        if method.sig.output != syn::ReturnType::Default {
+            return Err(syn::Error::new_spanned(method, "One-way methods must not return anything").to_compile_error())
+        }
+    }
+    Ok(result)
+}
+
+/// Removes the attributes for the macro, which the compiler doesn't know.
+pub fn strip(method: &mut syn::TraitItemMethod) {
+    method.attrs.retain(|attr| !is_ours(attr));
+}
+
+#[test]
+fn parse_attributes() {
+    let method = |s: &str| syn::parse_str::<syn::TraitItemMethod>(s).unwrap();
+    assert_eq!(parse(&method("fn f(&self, a: u32);")).unwrap(), MethodAttributes::default());
+    let mut oneway = method("/// Doc\n#[fml(oneway)]\nfn f(&self, a: u32);");
+    assert!(parse(&oneway).unwrap().oneway);
+    strip(&mut oneway);
+    assert_eq!(oneway.attrs.len(), 1);
+    assert!(parse(&method("#[fml(oneway)] fn f(&self) -> u32;")).is_err());
+    assert!(parse(&method("#[fml(oneway)] async fn f(&self);")).is_err());
+    assert!(parse(&method("#[fml(twoway)] fn f(&self);")).is_err());
+}
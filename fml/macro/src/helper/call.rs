@@ -15,6 +15,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use super::path_of_single_ident;
+use super::types::Borrowable;
 use crate::service::MacroArgs;
 use proc_macro2::{Span, TokenStream as TokenStream2};
 use quote::ToTokens;
@@ -27,32 +28,47 @@ pub fn generate_imported_struct(
 ) -> Result<TokenStream2, TokenStream2> {
     let trait_ident = the_trait.ident.clone();
     let struct_ident = quote::format_ident!("{}Imported", trait_ident);
+    let batch_ident = quote::format_ident!("{}Batch", trait_ident);
+    let mut batch_methods = TokenStream2::new();
+    let trait_type = super::generics::trait_type(the_trait);
+    let generics = super::generics::named_generics(fml_path, the_trait);
+    let (impl_generics, type_generics, where_clause) = generics.split_for_impl();
+    let marker_field = super::generics::marker_field(the_trait);
+    let marker_init = super::generics::marker_init(the_trait);
+    let lit_struct_name = syn::LitStr::new(&struct_ident.to_string(), Span::call_site());
     let mut imported_struct = quote! {
-        #[derive(Debug)]
-        pub struct #struct_ident {
+        pub struct #struct_ident #generics #where_clause {
             handle: #fml_path::HandleInstance
+            #marker_field
+        }
+        impl #impl_generics std::fmt::Debug for #struct_ident #type_generics #where_clause {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct(#lit_struct_name).field("handle", &self.handle).finish()
+            }
         }
     };
     let mut imported_struct_impl = syn::parse2::<syn::ItemImpl>(quote! {
-        impl #trait_ident for #struct_ident {
+        impl #impl_generics #trait_type for #struct_ident #type_generics #where_clause {
         }
     })
     .unwrap();
 
-    for item in the_trait.items.iter() {
-        let method = match item {
-            syn::TraitItem::Method(x) => x,
-            non_method => {
-                return Err(
-                    syn::Error::new_spanned(non_method, "Service trait must have only methods").to_compile_error()
-                )
-            }
-        };
-
+    for method in super::remote_methods(the_trait)? {
         let id_ident = super::id::id_method_ident(the_trait, method);
 
+        let attributes = super::attributes::parse(method)?;
+        let is_async = super::future::is_async(method);
+        let is_stream = super::types::is_stream(&method.sig.output);
+        if is_async {
+            super::future::check_signature(method)?;
+        }
+
         let mut the_method = syn::parse_str::<syn::ImplItemMethod>("fn dummy() -> () {}").unwrap();
-        the_method.sig = method.sig.clone();
+        the_method.sig = if is_async {
+            super::future::desugar_signature(fml_path, &method.sig)
+        } else {
+            method.sig.clone()
+        };
         let mut arguments_in_tuple = syn::ExprTuple {
             attrs: Vec::new(),
             paren_token: syn::token::Paren(Span::call_site()),
@@ -63,11 +79,19 @@ pub fn generate_imported_struct(
                 syn::FnArg::Receiver(_) => continue, // &self
                 syn::FnArg::Typed(pattern) => {
                     if let syn::Pat::Ident(the_arg) = &*pattern.pat {
-                        arguments_in_tuple.elems.push(syn::Expr::Path(syn::ExprPath {
+                        let the_arg = syn::Expr::Path(syn::ExprPath {
                             attrs: Vec::new(),
                             qself: None,
                             path: path_of_single_ident(the_arg.ident.clone()),
-                        }));
+                        });
+                        // Bytes must be serialized as such so that the exporter can borrow them.
+                        let the_arg = match super::types::is_borrowable(&pattern.ty) {
+                            Some(Borrowable::Bytes) | Some(Borrowable::CowBytes) => syn::Expr::Verbatim(quote! {
+                                #fml_path::codec::AsBytes(&*#the_arg)
+                            }),
+                            _ => the_arg,
+                        };
+                        arguments_in_tuple.elems.push(the_arg);
                     } else {
                         return Err(syn::Error::new_spanned(arg, "You must not use a pattern for the argument")
                             .to_compile_error())
@@ -76,16 +100,87 @@ pub fn generate_imported_struct(
             }
         }
 
-        let the_call = quote! {
-            #fml_path::service_context::call(&self.handle, #id_ident.load(#fml_path::ID_ORDERING), &#arguments_in_tuple)
+        let the_call = if attributes.oneway {
+            quote! {
+                #fml_path::service_context::call_oneway(&self.handle, #id_ident.load(#fml_path::ID_ORDERING), &#arguments_in_tuple)
+            }
+        } else if is_stream {
+            quote! {
+                #fml_path::service_context::call_stream(&self.handle, #id_ident.load(#fml_path::ID_ORDERING), &#arguments_in_tuple)
+            }
+        } else if is_async && super::types::is_result(&method.sig.output) {
+            // The arguments are encoded right away, so the future doesn't borrow them.
+            quote! {
+                let call = #fml_path::service_context::try_call_async(&self.handle, #id_ident.load(#fml_path::ID_ORDERING), &#arguments_in_tuple);
+                Box::pin(async move {
+                    match call.await {
+                        Ok(x) => x,
+                        Err(e) => Err(From::from(e)),
+                    }
+                })
+            }
+        } else if is_async {
+            quote! {
+                #fml_path::service_context::call_async(&self.handle, #id_ident.load(#fml_path::ID_ORDERING), &#arguments_in_tuple)
+            }
+        } else if super::types::is_result(&method.sig.output) {
+            // Failure of the call itself is converted into the error type that the method declares.
+            quote! {
+                match #fml_path::service_context::try_call(&self.handle, #id_ident.load(#fml_path::ID_ORDERING), &#arguments_in_tuple) {
+                    Ok(x) => x,
+                    Err(e) => Err(From::from(e)),
+                }
+            }
+        } else {
+            quote! {
+                #fml_path::service_context::call(&self.handle, #id_ident.load(#fml_path::ID_ORDERING), &#arguments_in_tuple)
+            }
         };
-        the_method.block.stmts.push(syn::Stmt::Expr(syn::Expr::Verbatim(the_call)));
+        the_method.block = syn::parse2(quote! {{#the_call}}).unwrap();
         imported_struct_impl.items.push(syn::ImplItem::Method(the_method));
+
+        // Streams can't be batched, since the response of a batch is a single message.
+        if is_stream {
+            continue
+        }
+        let method_name = &method.sig.ident;
+        let batch_inputs = method.sig.inputs.iter().skip(1);
+        let return_type = match &method.sig.output {
+            syn::ReturnType::Type(_, t) => quote! {#t},
+            syn::ReturnType::Default => quote! {()},
+        };
+        batch_methods.extend(quote! {
+            pub fn #method_name(&mut self, #(#batch_inputs),*) -> Result<#fml_path::Queued<#return_type>, #fml_path::CallError> {
+                self.batch.queue(self.handle, #id_ident.load(#fml_path::ID_ORDERING), &#arguments_in_tuple)
+            }
+        });
     }
-    let trait_id_ident = super::id::id_trait_ident(&the_trait);
+    let trait_id = super::id::trait_id_expr(fml_path, the_trait);
+    let batch_generics = {
+        let mut batch_generics = generics.clone();
+        batch_generics.params.insert(0, syn::parse_quote!('a));
+        batch_generics
+    };
+    let (batch_impl_generics, batch_type_generics, _) = batch_generics.split_for_impl();
     imported_struct.extend(imported_struct_impl.to_token_stream());
     imported_struct.extend(quote! {
-        impl #fml_path::Service for #struct_ident {
+        /// Queues calls to an imported service in a batch, instead of making them one by one.
+        pub struct #batch_ident #batch_generics #where_clause {
+            batch: &'a mut #fml_path::Batch,
+            handle: &'a #fml_path::HandleInstance
+            #marker_field
+        }
+        impl #batch_impl_generics #batch_ident #batch_type_generics #where_clause {
+            pub fn new(batch: &'a mut #fml_path::Batch, service: &'a dyn #trait_type) -> Self {
+                #batch_ident {
+                    batch,
+                    handle: #fml_path::Service::get_handle(service)
+                    #marker_init
+                }
+            }
+            #batch_methods
+        }
+        impl #impl_generics #fml_path::Service for #struct_ident #type_generics #where_clause {
             fn get_handle(&self) -> &#fml_path::HandleInstance {
                 &self.handle
             }
@@ -93,23 +188,29 @@ pub fn generate_imported_struct(
                 &mut self.handle
             }
             fn get_trait_id(&self) -> #fml_path::TraitId {
-                #trait_id_ident.load(#fml_path::ID_ORDERING)
+                #trait_id
             }
         }
-        impl Drop for #struct_ident {
+        impl #impl_generics Drop for #struct_ident #type_generics #where_clause {
             fn drop(&mut self) {
                 #fml_path::service_context::delete(&self.handle)
             }
         }
-        impl #fml_path::ImportService<dyn #trait_ident> for dyn #trait_ident {
-            fn import(handle: #fml_path::HandleInstance) -> std::sync::Arc<dyn #trait_ident>  {
+        impl #impl_generics #fml_path::ImportService<dyn #trait_type> for dyn #trait_type #where_clause {
+            fn import(handle: #fml_path::HandleInstance) -> std::sync::Arc<dyn #trait_type>  {
+                // A handle sent back to this module is resolved to the object itself.
+                let handle = match #fml_path::service_context::import_back::<dyn #trait_type>(handle) {
+                    Ok(object) => return object,
+                    Err(handle) => handle,
+                };
                 std::sync::Arc::new(#struct_ident  {
-                    handle,
+                    handle
+                    #marker_init
                 })
             }
         }
-        impl #fml_path::ServiceDispatcher for #struct_ident  {
-            fn dispatch(&self, _method: #fml_path::MethodId, _arguments: &[u8], _return_buffer: std::io::Cursor<&mut Vec<u8>>) {panic!()}
+        impl #impl_generics #fml_path::ServiceDispatcher for #struct_ident #type_generics #where_clause {
+            fn dispatch(&self, _trait_id: #fml_path::TraitId, _method: #fml_path::MethodId, _arguments: &[u8], _return_buffer: std::io::Cursor<&mut Vec<u8>>) -> Result<(), #fml_path::CallError> {panic!()}
         }
     });
     Ok(imported_struct.to_token_stream())
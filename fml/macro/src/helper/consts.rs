@@ -0,0 +1,143 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A trait with consts can't be made into an object, so the consts of a service trait go to a companion trait,
+// `<Trait>Consts`, which every implementor of the trait gets by a blanket impl.
+// They can't be overridden, so that both sides of a call have the same values.
+// `Self::CONST` in the default methods is made to refer to the companion,
+// and `<dyn Trait>::CONST` still works as well.
+
+use proc_macro2::TokenStream as TokenStream2;
+use syn::fold::Fold;
+use syn::Ident;
+
+pub fn companion_ident(the_trait: &syn::ItemTrait) -> Ident {
+    quote::format_ident!("{}Consts", the_trait.ident)
+}
+
+/// Makes `Self::CONST` into `<Self as TraitConsts>::CONST`.
+struct SelfConsts {
+    names: Vec<Ident>,
+    companion: syn::Path,
+}
+
+impl Fold for SelfConsts {
+    fn fold_expr_path(&mut self, expr: syn::ExprPath) -> syn::ExprPath {
+        let segments = &expr.path.segments;
+        if expr.qself.is_none()
+            && segments.len() == 2
+            && segments[0].ident == "Self"
+            && self.names.iter().any(|name| segments[1].ident == *name)
+        {
+            let companion = &self.companion;
+            let name = &segments[1].ident;
+            return syn::parse_quote! {<Self as #companion>::#name}
+        }
+        syn::fold::fold_expr_path(self, expr)
+    }
+}
+
+/// Takes the consts out of the trait, and returns the companion trait that has them.
+pub fn generate_consts(the_trait: &mut syn::ItemTrait) -> TokenStream2 {
+    let consts: Vec<syn::TraitItemConst> = the_trait
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            syn::TraitItem::Const(constant) => Some(constant.clone()),
+            _ => None,
+        })
+        .collect();
+    if consts.is_empty() {
+        return TokenStream2::new()
+    }
+    the_trait.items.retain(|item| !matches!(item, syn::TraitItem::Const(_)));
+
+    let companion = companion_ident(the_trait);
+    let (decl_generics, type_generics, decl_where_clause) = the_trait.generics.split_for_impl();
+    let mut fold = SelfConsts {
+        names: consts.iter().map(|constant| constant.ident.clone()).collect(),
+        companion: syn::parse_quote! {#companion #type_generics},
+    };
+    for item in the_trait.items.iter_mut() {
+        if let syn::TraitItem::Method(method) = item {
+            if let Some(block) = method.default.take() {
+                method.default = Some(fold.fold_block(block));
+            }
+        }
+    }
+
+    let vis = &the_trait.vis;
+    let trait_type = super::generics::trait_type(the_trait);
+    let lit_doc = syn::LitStr::new(
+        &format!("Consts of `{}`, which every implementor has as they are.", the_trait.ident),
+        proc_macro2::Span::call_site(),
+    );
+    let mut blanket_generics = the_trait.generics.clone();
+    blanket_generics.params.push(syn::parse_quote! {FmlImplementor: ?Sized + #trait_type});
+    let (blanket_generics, _, blanket_where_clause) = blanket_generics.split_for_impl();
+    let generics = super::generics::generics(the_trait);
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let inherent: Vec<TokenStream2> = consts
+        .iter()
+        .map(
+            |syn::TraitItemConst {
+                 attrs,
+                 ident,
+                 ty,
+                 ..
+             }| {
+                quote! {
+                    #(#attrs)*
+                    #vis const #ident: #ty = <Self as #companion #type_generics>::#ident;
+                }
+            },
+        )
+        .collect();
+
+    quote! {
+        #[doc = #lit_doc]
+        #vis trait #companion #decl_generics #decl_where_clause {
+            #(#consts)*
+        }
+        impl #blanket_generics #companion #type_generics for FmlImplementor #blanket_where_clause {}
+        impl #impl_generics dyn #trait_type #where_clause {
+            #(#inherent)*
+        }
+    }
+}
+
+#[test]
+fn companion_trait() {
+    let mut the_trait: syn::ItemTrait = syn::parse_str(
+        "trait Tally: Service {
+            const UNIT: u32 = 10;
+            fn read_in_units(&self) -> u32 { self.read() * Self::UNIT + Self::read_twice() }
+            fn read(&self) -> u32;
+        }",
+    )
+    .unwrap();
+    let companion = generate_consts(&mut the_trait).to_string();
+    assert!(companion.contains("trait TallyConsts { const UNIT : u32 = 10 ; }"));
+    assert!(companion.contains("for FmlImplementor"));
+    assert_eq!(the_trait.items.len(), 2);
+    let method = quote! {#the_trait}.to_string();
+    assert!(method.contains("< Self as TallyConsts > :: UNIT"));
+    // Only the consts are changed.
+    assert!(method.contains("Self :: read_twice ()"));
+
+    let mut the_trait: syn::ItemTrait = syn::parse_str("trait Tally: Service { fn read(&self) -> u32; }").unwrap();
+    assert!(generate_consts(&mut the_trait).is_empty());
+}
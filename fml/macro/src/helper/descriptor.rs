@@ -0,0 +1,202 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::types::{type_string, Borrowable};
+use crate::service::MacroArgs;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::ToTokens;
+
+fn lit_str(s: &str) -> syn::LitStr {
+    syn::LitStr::new(s, Span::call_site())
+}
+
+/// Doc comments of the item, with a line for each attribute
+pub fn doc_of(attrs: &[syn::Attribute]) -> String {
+    attrs
+        .iter()
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(syn::Meta::NameValue(syn::MetaNameValue {
+                path,
+                lit: syn::Lit::Str(lit),
+                ..
+            })) if path.is_ident("doc") => Some(lit.value().trim().to_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The shape is traced when described, except those in a generic trait which are left as written.
+fn shape_of(fml_path: &syn::Path, generic: bool, the_type: &syn::Type) -> TokenStream2 {
+    if generic {
+        let lit_type = lit_str(&type_string(the_type));
+        quote! {#fml_path::Shape::Opaque(#lit_type.to_owned())}
+    } else {
+        quote! {#fml_path::shape_of::<#the_type>()}
+    }
+}
+
+/// Shape of the argument as it is sent. Borrowed arguments are sent as the owned ones.
+fn argument_shape(
+    fml_path: &syn::Path,
+    generic: bool,
+    arg: &syn::FnArg,
+    the_type: &syn::Type,
+) -> Result<TokenStream2, TokenStream2> {
+    let sent_type = match super::types::is_borrowable(the_type) {
+        Some(Borrowable::Bytes) | Some(Borrowable::CowBytes) => syn::parse_quote! {Vec<u8>},
+        Some(Borrowable::Str) | Some(Borrowable::CowStr) => syn::parse_quote! {String},
+        None => super::types::is_ref(the_type)
+            .map_err(|e| syn::Error::new_spanned(arg, &e).to_compile_error())?
+            .unwrap_or_else(|| the_type.clone()),
+    };
+    Ok(shape_of(fml_path, generic, &sent_type))
+}
+
+/// Shapes of the arguments, and that of the return value or of each item for a stream
+pub fn signature_shapes(
+    fml_path: &syn::Path,
+    the_trait: &syn::ItemTrait,
+    method: &syn::TraitItemMethod,
+) -> Result<(Vec<TokenStream2>, TokenStream2), TokenStream2> {
+    let generic = super::generics::is_generic(the_trait);
+    let mut arguments = Vec::new();
+    for arg in method.sig.inputs.iter() {
+        if let syn::FnArg::Typed(pattern) = arg {
+            arguments.push(argument_shape(fml_path, generic, arg, &pattern.ty)?);
+        }
+    }
+    let output = match &method.sig.output {
+        syn::ReturnType::Type(_, t) => {
+            let shape = shape_of(
+                fml_path,
+                generic,
+                &super::types::stream_item(&method.sig.output).unwrap_or_else(|| (**t).clone()),
+            );
+            quote! {Some(#shape)}
+        }
+        syn::ReturnType::Default => quote! {None},
+    };
+    Ok((arguments, output))
+}
+
+fn describe_method(
+    fml_path: &syn::Path,
+    the_trait: &syn::ItemTrait,
+    method: &syn::TraitItemMethod,
+) -> Result<TokenStream2, TokenStream2> {
+    let lit_name = lit_str(&method.sig.ident.to_string());
+    let lit_doc = lit_str(&doc_of(&method.attrs));
+    let id_ident = super::id::id_method_ident(the_trait, method);
+    let fingerprint_ident = super::id::id_method_fingerprint_ident(the_trait, method);
+    let asynchronous = method.sig.asyncness.is_some();
+    let oneway = super::attributes::parse(method)?.oneway;
+    let stream = super::types::stream_item(&method.sig.output).is_some();
+
+    let (shapes, output_shape) = signature_shapes(fml_path, the_trait, method)?;
+    let mut arguments = Vec::new();
+    let typed = method.sig.inputs.iter().filter_map(|arg| match arg {
+        syn::FnArg::Typed(pattern) => Some(pattern),
+        syn::FnArg::Receiver(_) => None,
+    });
+    for (pattern, shape) in typed.zip(shapes) {
+        let lit_arg_name = lit_str(&pattern.pat.to_token_stream().to_string());
+        let lit_arg_type = lit_str(&type_string(&pattern.ty));
+        arguments.push(quote! {
+            #fml_path::ArgumentDescriptor {
+                name: #lit_arg_name.to_owned(),
+                ty: #lit_arg_type.to_owned(),
+                shape: #shape,
+            }
+        });
+    }
+    let output = match &method.sig.output {
+        syn::ReturnType::Type(_, t) => {
+            let lit_output = lit_str(&type_string(t));
+            quote! {Some(#lit_output.to_owned())}
+        }
+        syn::ReturnType::Default => quote! {None},
+    };
+
+    Ok(quote! {
+        #fml_path::MethodDescriptor {
+            name: #lit_name.to_owned(),
+            id: #id_ident.load(#fml_path::ID_ORDERING),
+            doc: #lit_doc.to_owned(),
+            asynchronous: #asynchronous,
+            oneway: #oneway,
+            stream: #stream,
+            arguments: vec![#(#arguments),*],
+            output: #output,
+            output_shape: #output_shape,
+            fingerprint: #fingerprint_ident(),
+        }
+    })
+}
+
+/// Registers the descriptor of the trait, which is made when asked, so that it has the ids set up.
+pub fn generate_descriptor(
+    MacroArgs {
+        fml_path,
+    }: &MacroArgs,
+    the_trait: &syn::ItemTrait,
+) -> Result<TokenStream2, TokenStream2> {
+    let trait_ident = &the_trait.ident;
+    let lit_trait_name = lit_str(&trait_ident.to_string());
+    let lit_doc = lit_str(&doc_of(&the_trait.attrs));
+    let type_params: Vec<syn::LitStr> =
+        super::generics::type_params(the_trait).iter().map(|param| lit_str(&param.to_string())).collect();
+    // Each instance of a generic trait has its own id.
+    let id = if super::generics::is_generic(the_trait) {
+        quote! {None}
+    } else {
+        let id_ident = super::id::id_trait_ident(the_trait);
+        quote! {Some(#id_ident.load(#fml_path::ID_ORDERING))}
+    };
+    let mut methods = Vec::new();
+    for method in super::remote_methods(the_trait)? {
+        methods.push(describe_method(fml_path, the_trait, method)?);
+    }
+
+    let entry_ident = quote::format_ident!("DSC_ENTRY_{}", trait_ident);
+    let describer_ident = quote::format_ident!("describe_{}", trait_ident);
+    Ok(quote! {
+        #[allow(non_upper_case_globals)]
+        #[distributed_slice(#fml_path::DSC_REG)]
+        static #entry_ident: fn() -> #fml_path::ServiceDescriptor = #describer_ident;
+        #[allow(non_snake_case)]
+        fn #describer_ident() -> #fml_path::ServiceDescriptor {
+            #fml_path::ServiceDescriptor {
+                name: #lit_trait_name.to_owned(),
+                type_params: vec![#(#type_params.to_owned()),*],
+                id: #id,
+                doc: #lit_doc.to_owned(),
+                methods: vec![#(#methods),*],
+            }
+        }
+    })
+}
+
+#[test]
+fn doc_comments() {
+    let method = syn::parse_str::<syn::TraitItemMethod>(
+        "/// Says hello\n///   with the flag\n#[fml(oneway)]\nfn hello(&self, flag: i32);",
+    )
+    .unwrap();
+    assert_eq!(doc_of(&method.attrs), "Says hello\nwith the flag");
+    let method = syn::parse_str::<syn::TraitItemMethod>("fn hello(&self, flag: i32);").unwrap();
+    assert_eq!(doc_of(&method.attrs), "");
+}
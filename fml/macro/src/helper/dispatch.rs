@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use super::types::Borrowable;
 use crate::service::MacroArgs;
 use proc_macro2::{Span, TokenStream as TokenStream2};
 
@@ -27,18 +28,17 @@ pub fn generate_dispatch(
 ) -> Result<TokenStream2, TokenStream2> {
     let trait_ident = the_trait.ident.clone();
     let mut if_else_clauses = TokenStream2::new();
+    let mut async_if_else_clauses = TokenStream2::new();
+    let mut stream_if_else_clauses = TokenStream2::new();
 
     // Make an if statement for service's each method
-    for item in the_trait.items.iter() {
-        let method = match item {
-            syn::TraitItem::Method(x) => x,
-            non_method => {
-                return Err(
-                    syn::Error::new_spanned(non_method, "Service trait must have only methods").to_compile_error()
-                )
-            }
-        };
+    for method in super::remote_methods(the_trait)? {
         let id_ident = super::id::id_method_ident(the_trait, method);
+        let is_async = super::future::is_async(method);
+        let is_stream = super::types::is_stream(&method.sig.output);
+        if is_async {
+            super::future::check_signature(method)?;
+        }
 
         // Argument will be represented as a tuple. We deserialize the data as a tuple here
         let mut the_let_pattern = syn::PatTuple {
@@ -83,7 +83,15 @@ pub fn generate_dispatch(
                 _ => panic!(),
             };
 
-            if let Some(unrefed_type) = super::types::is_ref(arg_type)
+            // Bytes and strings are deserialized borrowing the arguments, without copying.
+            let borrowable = super::types::is_borrowable(arg_type);
+            if let Some(borrowable) = &borrowable {
+                let borrowed_type = match borrowable {
+                    Borrowable::Bytes | Borrowable::CowBytes => quote! {#fml_path::codec::BorrowedBytes<'_>},
+                    Borrowable::Str | Borrowable::CowStr => quote! {#fml_path::codec::BorrowedStr<'_>},
+                };
+                type_annotation.elems.push(syn::parse2(borrowed_type).unwrap());
+            } else if let Some(unrefed_type) = super::types::is_ref(arg_type)
                 .map_err(|e| syn::Error::new_spanned(arg_source, &e).to_compile_error())?
             {
                 type_annotation.elems.push(unrefed_type);
@@ -94,7 +102,16 @@ pub fn generate_dispatch(
             type_annotation.elems.push_punct(syn::token::Comma(Span::call_site()));
 
             let arg_ident = quote::format_ident!("a{}", j + 1);
-            let the_arg = if super::types::is_ref(arg_type)
+            let the_arg = if let Some(borrowable) = borrowable {
+                match borrowable {
+                    Borrowable::Bytes | Borrowable::Str => quote! {
+                        &*#arg_ident.0
+                    },
+                    Borrowable::CowBytes | Borrowable::CowStr => quote! {
+                        #arg_ident.0
+                    },
+                }
+            } else if super::types::is_ref(arg_type)
                 .map_err(|e| syn::Error::new_spanned(arg_source, &e).to_compile_error())?
                 .is_some()
             {
@@ -110,16 +127,34 @@ pub fn generate_dispatch(
         }
 
         let stmt_deserialize = quote! {
-            let #the_let_pattern: #type_annotation = serde_cbor::from_reader(&arguments[std::mem::size_of::<#fml_path::PacketHeader>()..]).unwrap();
+            let #the_let_pattern: #type_annotation = #fml_path::codec::decode(&arguments[#fml_path::PacketHeader::SIZE..])?;
         };
 
         let method_name = method.sig.ident.clone();
-        let stmt_call = quote! {
-            let result = object.#method_name(#the_args);
+        if is_stream {
+            // Only the port server can send a stream, so there is no direct dispatch for it.
+            stream_if_else_clauses.extend(quote! {
+                if method == #id_ident.load(#fml_path::ID_ORDERING) {
+                    #stmt_deserialize
+                    let result = object.#method_name(#the_args);
+                    return Ok(Some(#fml_path::Stream::into_pending(result)));
+                }
+            });
+            continue
+        }
+        let stmt_call = if is_async {
+            // This is only for direct dispatch; the port server drives the future with dispatch_async() instead.
+            quote! {
+                let result = #fml_path::block_on(object.#method_name(#the_args));
+            }
+        } else {
+            quote! {
+                let result = object.#method_name(#the_args);
+            }
         };
 
         let the_return = quote! {
-            serde_cbor::to_writer(return_buffer, &result).unwrap();
+            #fml_path::codec::encode(return_buffer, &result)?;
         };
 
         if_else_clauses.extend(quote! {
@@ -127,31 +162,112 @@ pub fn generate_dispatch(
                 #stmt_deserialize
                 #stmt_call
                 #the_return
-                return;
+                return Ok(());
             }
         });
+
+        if is_async {
+            async_if_else_clauses.extend(quote! {
+                if method == #id_ident.load(#fml_path::ID_ORDERING) {
+                    #stmt_deserialize
+                    return Ok(Some(Box::pin(async move {
+                        let result = object.#method_name(#the_args).await;
+                        let mut return_buffer: Vec<u8> = vec![0; #fml_path::PacketHeader::SIZE];
+                        #fml_path::codec::encode({
+                            let mut c = std::io::Cursor::new(&mut return_buffer);
+                            c.set_position(#fml_path::PacketHeader::SIZE as u64);
+                            c
+                        }, &result)?;
+                        Ok(return_buffer)
+                    })));
+                }
+            });
+        }
     }
+    async_if_else_clauses.extend(quote! {
+        Ok(None)
+    });
+    stream_if_else_clauses.extend(quote! {
+        Ok(None)
+    });
     if_else_clauses.extend(quote! {
-        panic!("Invalid handle call. Fatal Error.")
+        Err(#fml_path::CallError::UnknownMethod(method))
     });
 
-    let trait_id_ident = super::id::id_trait_ident(&the_trait);
+    let trait_id = super::id::trait_id_expr(fml_path, the_trait);
+    // The id of an instance of a generic trait is not checked at the setup, so it may be missing.
+    let try_trait_id = if super::generics::is_generic(the_trait) {
+        let generic_trait_id = super::id::generic_trait_id_expr(fml_path, the_trait);
+        quote! {
+            fn try_id() -> Result<#fml_path::TraitId, #fml_path::CallError> {
+                #generic_trait_id
+            }
+        }
+    } else {
+        TokenStream2::new()
+    };
+    let trait_type = super::generics::trait_type(the_trait);
+    let generics = super::generics::named_generics(fml_path, the_trait);
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+    // An instance of a generic trait can't register itself statically, so it can't be queried.
+    let query_entry = if super::generics::is_generic(the_trait) {
+        TokenStream2::new()
+    } else {
+        let query_entry_ident = quote::format_ident!("QRY_ENTRY_{}", trait_ident);
+        let query_exporter_ident = quote::format_ident!("query_exporter_{}", trait_ident);
+        quote! {
+            #[allow(non_upper_case_globals)]
+            #[distributed_slice(#fml_path::QRY_REG)]
+            static #query_entry_ident: (fn() -> #fml_path::TraitId, fn(port_id: #fml_path::PortId, object: std::sync::Arc<dyn #fml_path::Service>) -> Option<#fml_path::HandleInstance>) =
+            (<dyn #trait_ident as #fml_path::IdOfService<dyn #trait_ident>>::id, #query_exporter_ident);
+            #[allow(non_snake_case)]
+            fn #query_exporter_ident(port_id: #fml_path::PortId, object: std::sync::Arc<dyn #fml_path::Service>) -> Option<#fml_path::HandleInstance> {
+                let object = intertrait::cast::CastArc::cast::<dyn #trait_ident>(object).ok()?;
+                Some(<dyn #trait_ident as #fml_path::ExportService<dyn #trait_ident>>::export(port_id, object))
+            }
+        }
+    };
     let result = quote! {
-        impl #fml_path::ExportService<dyn #trait_ident> for dyn #trait_ident {
-            fn export(port_id: #fml_path::PortId, handle: std::sync::Arc<dyn #trait_ident>) -> #fml_path::HandleInstance {
-                #fml_path::service_context::register(port_id, intertrait::cast::CastArc::cast::<dyn #fml_path::Service>(handle).expect("Trait casting failed"))
+        #query_entry
+        impl #impl_generics #fml_path::ExportService<dyn #trait_type> for dyn #trait_type #where_clause {
+            fn export(port_id: #fml_path::PortId, handle: std::sync::Arc<dyn #trait_type>) -> #fml_path::HandleInstance {
+                // An object going back to the port it was imported from is sent as the handle it was imported with.
+                let handle = match #fml_path::service_context::export_back(port_id, handle) {
+                    Ok(handle) => return handle,
+                    Err(handle) => handle,
+                };
+                // An object bound to a port already, such as an imported one, is exported through a forwarder.
+                let service: std::sync::Arc<dyn #fml_path::Service> = if #fml_path::Forwarder::<dyn #trait_type>::is_needed(&*handle) {
+                    std::sync::Arc::new(#fml_path::Forwarder::new(handle))
+                } else {
+                    intertrait::cast::CastArc::cast::<dyn #fml_path::Service>(handle).expect("Trait casting failed")
+                };
+                let trait_id = <dyn #trait_type as #fml_path::IdOfService<dyn #trait_type>>::id();
+                #fml_path::service_context::register_as(port_id, trait_id, service)
             }
         }
-        impl #fml_path::DispatchService<dyn #trait_ident> for dyn #trait_ident {
-            fn dispatch(object: &dyn #trait_ident, method: #fml_path::MethodId, arguments: &[u8],
-            return_buffer: std::io::Cursor<&mut Vec<u8>>) {
+        impl #impl_generics #fml_path::DispatchService<dyn #trait_type> for dyn #trait_type #where_clause {
+            fn dispatch(object: &dyn #trait_type, method: #fml_path::MethodId, arguments: &[u8],
+            return_buffer: std::io::Cursor<&mut Vec<u8>>) -> Result<(), #fml_path::CallError> {
                 #if_else_clauses
             }
+            #[allow(unused_variables)]
+            fn dispatch_async(object: std::sync::Arc<dyn #trait_type>, method: #fml_path::MethodId, arguments: &[u8])
+            -> Result<Option<#fml_path::PendingReturn>, #fml_path::CallError> {
+                #async_if_else_clauses
+            }
+            #[allow(unused_variables)]
+            fn dispatch_stream(object: &dyn #trait_type, method: #fml_path::MethodId, arguments: &[u8])
+            -> Result<Option<#fml_path::PendingStream>, #fml_path::CallError> {
+                #stream_if_else_clauses
+            }
         }
-        impl #fml_path::IdOfService<dyn #trait_ident> for dyn #trait_ident {
+        impl #impl_generics #fml_path::IdOfService<dyn #trait_type> for dyn #trait_type #where_clause {
             fn id() -> #fml_path::TraitId{
-                #trait_id_ident.load(#fml_path::ID_ORDERING)
+                #trait_id
             }
+            #try_trait_id
         }
     };
     Ok(result)
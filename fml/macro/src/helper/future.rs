@@ -0,0 +1,87 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use proc_macro2::TokenStream as TokenStream2;
+
+pub fn is_async(method: &syn::TraitItemMethod) -> bool {
+    method.sig.asyncness.is_some()
+}
+
+/// Trait objects can't have `async fn`, so it is declared to return a boxed future instead.
+/// `async fn f(&self, a: A) -> R` becomes `fn f(&self, a: A) -> BoxFuture<'_, R>`.
+pub fn desugar_signature(fml_path: &syn::Path, sig: &syn::Signature) -> syn::Signature {
+    let mut sig = sig.clone();
+    sig.asyncness = None;
+    let output = match &sig.output {
+        syn::ReturnType::Type(_, t) => quote! {#t},
+        syn::ReturnType::Default => quote! {()},
+    };
+    sig.output = syn::parse2(quote! {
+        -> #fml_path::BoxFuture<'_, #output>
+    })
+    .unwrap();
+    sig
+}
+
+/// The trait as it will be declared, with all the async methods desugared.
+pub fn desugar_trait(fml_path: &syn::Path, the_trait: &syn::ItemTrait) -> syn::ItemTrait {
+    let mut the_trait = the_trait.clone();
+    for item in the_trait.items.iter_mut() {
+        if let syn::TraitItem::Method(method) = item {
+            if is_async(method) {
+                method.sig = desugar_signature(fml_path, &method.sig);
+            }
+        }
+    }
+    the_trait
+}
+
+/// Arguments of an async method must be owned, since the future outlives the received packet.
+/// It can't return a stream either, which is asynchronous by itself.
+pub fn check_signature(method: &syn::TraitItemMethod) -> Result<(), TokenStream2> {
+    if super::types::is_stream(&method.sig.output) {
+        return Err(syn::Error::new_spanned(method, "Async methods can't return a stream").to_compile_error())
+    }
+    for arg in method.sig.inputs.iter() {
+        if let syn::FnArg::Typed(pattern) = arg {
+            if super::types::is_borrowable(&pattern.ty).is_some() || matches!(*pattern.ty, syn::Type::Reference(_)) {
+                return Err(
+                    syn::Error::new_spanned(arg, "Async methods must take their arguments by value").to_compile_error()
+                )
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn desugar_async() {
+    let fml_path = syn::parse_str::<syn::Path>("fml").unwrap();
+    let method = syn::parse_str::<syn::TraitItemMethod>("async fn f(&self, a: u32) -> String;").unwrap();
+    assert!(is_async(&method));
+    let expected =
+        syn::parse_str::<syn::TraitItemMethod>("fn f(&self, a: u32) -> fml::BoxFuture<'_, String>;").unwrap();
+    assert_eq!(desugar_signature(&fml_path, &method.sig), expected.sig);
+
+    let method = syn::parse_str::<syn::TraitItemMethod>("async fn f(&self);").unwrap();
+    let expected = syn::parse_str::<syn::TraitItemMethod>("fn f(&self) -> fml::BoxFuture<'_, ()>;").unwrap();
+    assert_eq!(desugar_signature(&fml_path, &method.sig), expected.sig);
+
+    assert!(check_signature(&syn::parse_str("async fn f(&self, a: Vec<u8>);").unwrap()).is_ok());
+    assert!(check_signature(&syn::parse_str("async fn f(&self, a: &str);").unwrap()).is_err());
+    assert!(check_signature(&syn::parse_str("async fn f(&self, a: Cow<[u8]>);").unwrap()).is_err());
+    assert!(check_signature(&syn::parse_str("async fn f(&self) -> Stream<u8>;").unwrap()).is_err());
+}
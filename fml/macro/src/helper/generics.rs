@@ -0,0 +1,112 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A service trait may have type parameters. Each instance of it, such as `Store<String, u64>`,
+// is a distinct service with its own trait id, while the ids of the methods are shared.
+
+use proc_macro2::TokenStream as TokenStream2;
+
+pub fn is_generic(the_trait: &syn::ItemTrait) -> bool {
+    the_trait.generics.type_params().next().is_some()
+}
+
+/// Only type parameters are allowed, since the trait id is given by the names of the types. See `TypeName`.
+pub fn check(the_trait: &syn::ItemTrait) -> Result<(), TokenStream2> {
+    if the_trait.generics.lifetimes().next().is_some() || the_trait.generics.const_params().next().is_some() {
+        return Err(syn::Error::new_spanned(&the_trait.generics, "Service trait can have only type parameters")
+            .to_compile_error())
+    }
+    Ok(())
+}
+
+/// Generics of the trait for the generated items.
+/// The type parameters must be 'static, so that an instance of the trait can be a service object.
+pub fn generics(the_trait: &syn::ItemTrait) -> syn::Generics {
+    let mut generics = the_trait.generics.clone();
+    let params: Vec<syn::Ident> = generics.type_params().map(|param| param.ident.clone()).collect();
+    let where_clause = generics.make_where_clause();
+    for param in params {
+        where_clause.predicates.push(syn::parse_quote!(#param: 'static));
+    }
+    if where_clause.predicates.is_empty() {
+        generics.where_clause = None;
+    }
+    generics
+}
+
+/// Same as `generics()`, but the type parameters must also name themselves, so that the instance can be given its id.
+pub fn named_generics(fml_path: &syn::Path, the_trait: &syn::ItemTrait) -> syn::Generics {
+    let mut generics = generics(the_trait);
+    for param in type_params(the_trait) {
+        generics.make_where_clause().predicates.push(syn::parse_quote!(#param: #fml_path::TypeName));
+    }
+    generics
+}
+
+/// The trait with its type parameters, as in `dyn Store<K, V>`
+pub fn trait_type(the_trait: &syn::ItemTrait) -> TokenStream2 {
+    let trait_ident = &the_trait.ident;
+    let (_, type_generics, _) = the_trait.generics.split_for_impl();
+    quote! {#trait_ident #type_generics}
+}
+
+/// Type parameters of the trait, as they are
+pub fn type_params(the_trait: &syn::ItemTrait) -> Vec<syn::Ident> {
+    the_trait.generics.type_params().map(|param| param.ident.clone()).collect()
+}
+
+/// A field that a generated struct has to hold the type parameters, which is empty for a non-generic trait.
+/// The types are not owned, so it doesn't affect whether the struct is Send and Sync.
+pub fn marker_field(the_trait: &syn::ItemTrait) -> TokenStream2 {
+    if !is_generic(the_trait) {
+        return TokenStream2::new()
+    }
+    let params = type_params(the_trait);
+    quote! {, _marker: std::marker::PhantomData<fn() -> (#(#params,)*)>}
+}
+
+/// Initializer of the field made by `marker_field()`
+pub fn marker_init(the_trait: &syn::ItemTrait) -> TokenStream2 {
+    if !is_generic(the_trait) {
+        return TokenStream2::new()
+    }
+    quote! {, _marker: std::marker::PhantomData}
+}
+
+#[test]
+fn generic_trait() {
+    use quote::ToTokens;
+
+    let the_trait: syn::ItemTrait = syn::parse_str("trait Store<K: Clone, V>: Service {}").unwrap();
+    assert!(is_generic(&the_trait));
+    assert!(check(&the_trait).is_ok());
+    assert_eq!(trait_type(&the_trait).to_string(), "Store < K , V >");
+    let impl_generics = generics(&the_trait);
+    assert_eq!(impl_generics.where_clause.to_token_stream().to_string(), "where K : 'static , V : 'static");
+    let named_generics = named_generics(&syn::parse_str("fml").unwrap(), &the_trait);
+    assert_eq!(
+        named_generics.where_clause.to_token_stream().to_string(),
+        "where K : 'static , V : 'static , K : fml :: TypeName , V : fml :: TypeName"
+    );
+
+    let the_trait: syn::ItemTrait = syn::parse_str("trait Store: Service {}").unwrap();
+    assert!(!is_generic(&the_trait));
+    assert!(generics(&the_trait).where_clause.is_none());
+    assert!(marker_field(&the_trait).is_empty());
+
+    let the_trait: syn::ItemTrait = syn::parse_str("trait Store<'a>: Service {}").unwrap();
+    assert!(check(&the_trait).is_err());
+}
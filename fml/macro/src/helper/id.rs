@@ -26,11 +26,39 @@ pub fn id_trait_ident(the_trait: &syn::ItemTrait) -> Ident {
     quote::format_ident!("ID_TRAIT_{}", the_trait.ident)
 }
 
+/// Expression of the trait id of a generic trait, which is looked up by the name of the instance and may fail.
+pub fn generic_trait_id_expr(fml_path: &syn::Path, the_trait: &syn::ItemTrait) -> TokenStream2 {
+    let lit_trait_name = syn::LitStr::new(&format!("{}", the_trait.ident), Span::call_site());
+    let params = super::generics::type_params(the_trait);
+    quote! {
+        #fml_path::generic_trait_id(#lit_trait_name, &[#(<#params as #fml_path::TypeName>::type_name()),*])
+    }
+}
+
+/// Expression of the trait id. An instance of a generic trait unknown to the coordinator gets `UNKNOWN_TRAIT`.
+pub fn trait_id_expr(fml_path: &syn::Path, the_trait: &syn::ItemTrait) -> TokenStream2 {
+    if super::generics::is_generic(the_trait) {
+        let generic_trait_id = generic_trait_id_expr(fml_path, the_trait);
+        quote! {
+            #generic_trait_id.unwrap_or(#fml_path::UNKNOWN_TRAIT)
+        }
+    } else {
+        let id_ident = id_trait_ident(the_trait);
+        quote! {
+            #id_ident.load(#fml_path::ID_ORDERING)
+        }
+    }
+}
+
 fn lit_index(index: usize) -> syn::Lit {
     // We put a distinctive offset for the easy debug.
     syn::Lit::Int(syn::LitInt::new(&format!("{}", index + 7), Span::call_site()))
 }
 
+pub fn id_method_fingerprint_ident(the_trait: &syn::ItemTrait, method: &syn::TraitItemMethod) -> Ident {
+    quote::format_ident!("id_method_fingerprint_{}_{}", the_trait.ident, method.sig.ident)
+}
+
 fn id_method_entry_ident(the_trait: &syn::ItemTrait, method: &syn::TraitItemMethod) -> Ident {
     quote::format_ident!("ID_METHOD_ENTRY_{}_{}", the_trait.ident, method.sig.ident)
 }
@@ -56,54 +84,57 @@ pub fn generate_id_registeration(
     let mut result = TokenStream2::new();
 
     let lit_trait_name = syn::LitStr::new(&format!("{}", the_trait.ident), Span::call_site());
-    // registeration for trait itself
-    result.extend({
-        let id_ident = id_trait_ident(&the_trait);
-        let id_entry_ident = id_trait_entry_ident(&the_trait);
-        let id_setter_ident = id_trait_setter_ident(&the_trait);
-        let id_entry = quote! {
-            #[allow(non_upper_case_globals)]
-            static #id_ident: #fml_path::TraitIdAtomic = #fml_path::TraitIdAtomic::new(0);
-            #[allow(non_upper_case_globals)]
-            #[distributed_slice(#fml_path::TID_REG)]
-            static #id_entry_ident: (&'static str, fn(id: #fml_path::TraitId)) =
-            (#lit_trait_name, #id_setter_ident);
-            #[allow(non_snake_case)]
-            fn #id_setter_ident(id: #fml_path::TraitId) {
-                #id_ident.store(id, #fml_path::ID_ORDERING);
-            }
-        };
-        id_entry
-    });
+    // registeration for trait itself, of which a generic one has no static id
+    if !super::generics::is_generic(the_trait) {
+        result.extend({
+            let id_ident = id_trait_ident(&the_trait);
+            let id_entry_ident = id_trait_entry_ident(&the_trait);
+            let id_setter_ident = id_trait_setter_ident(&the_trait);
+            let id_entry = quote! {
+                #[allow(non_upper_case_globals)]
+                static #id_ident: #fml_path::TraitIdAtomic = #fml_path::TraitIdAtomic::new(0);
+                #[allow(non_upper_case_globals)]
+                #[distributed_slice(#fml_path::TID_REG)]
+                static #id_entry_ident: (&'static str, fn(id: #fml_path::TraitId)) =
+                (#lit_trait_name, #id_setter_ident);
+                #[allow(non_snake_case)]
+                fn #id_setter_ident(id: #fml_path::TraitId) {
+                    #id_ident.store(id, #fml_path::ID_ORDERING);
+                }
+            };
+            id_entry
+        });
+    }
 
     // registeration for methods in the trait
     let mut method_id_table = TokenStream2::new();
-    for (i, item) in the_trait.items.iter().enumerate() {
-        let method = match item {
-            syn::TraitItem::Method(x) => x,
-            non_method => {
-                return Err(
-                    syn::Error::new_spanned(non_method, "Service trait must have only methods").to_compile_error()
-                )
-            }
-        };
+    for (i, method) in super::remote_methods(the_trait)?.into_iter().enumerate() {
         let lit_index = lit_index(i);
         let lit_method_name = syn::LitStr::new(&format!("{}", method.sig.ident), Span::call_site());
+        let asynchronous = method.sig.asyncness.is_some();
+        let oneway = super::attributes::parse(method)?.oneway;
+        let stream = super::types::stream_item(&method.sig.output).is_some();
+        let (argument_shapes, output_shape) = super::descriptor::signature_shapes(fml_path, the_trait, method)?;
 
         let id_ident = id_method_ident(&the_trait, method);
         let id_entry_ident = id_method_entry_ident(&the_trait, method);
         let id_setter_ident = id_method_setter_ident(&the_trait, method);
+        let fingerprint_ident = id_method_fingerprint_ident(&the_trait, method);
         let id_entry = quote! {
             #[allow(non_upper_case_globals)]
             static #id_ident: #fml_path::MethodIdAtomic = #fml_path::MethodIdAtomic::new(#lit_index);
             #[distributed_slice(#fml_path::MID_REG)]
             #[allow(non_upper_case_globals)]
-            static #id_entry_ident: (&'static str, &'static str, fn(id: #fml_path::MethodId)) =
-            (#lit_trait_name, #lit_method_name, #id_setter_ident);
+            static #id_entry_ident: (&'static str, &'static str, fn() -> u64, fn(id: #fml_path::MethodId)) =
+            (#lit_trait_name, #lit_method_name, #fingerprint_ident, #id_setter_ident);
             #[allow(non_snake_case)]
             fn #id_setter_ident(id: #fml_path::MethodId) {
                 #id_ident.store(id, #fml_path::ID_ORDERING);
             }
+            #[allow(non_snake_case)]
+            fn #fingerprint_ident() -> u64 {
+                #fml_path::fingerprint(#asynchronous, #oneway, #stream, &[#(#argument_shapes),*], #output_shape)
+            }
         };
         method_id_table.extend(id_entry);
     }
@@ -14,11 +14,47 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod attributes;
 pub mod call;
+pub mod consts;
+pub mod descriptor;
 pub mod dispatch;
+pub mod future;
+pub mod generics;
 pub mod id;
 pub mod types;
 
+use proc_macro2::TokenStream as TokenStream2;
+
+/// Methods of the trait that are called remotely, in the order of declaration.
+///
+/// A method with a default body runs where it is called, on top of the remote ones, so it is not among them.
+/// An associated const must have a value, so that both sides have the same one.
+pub fn remote_methods(the_trait: &syn::ItemTrait) -> Result<Vec<&syn::TraitItemMethod>, TokenStream2> {
+    let mut methods = Vec::new();
+    for item in the_trait.items.iter() {
+        match item {
+            syn::TraitItem::Method(method) if method.default.is_none() => methods.push(method),
+            syn::TraitItem::Method(method) => {
+                if method.sig.asyncness.is_some() {
+                    return Err(syn::Error::new_spanned(method, "Default methods can't be async").to_compile_error())
+                }
+            }
+            syn::TraitItem::Const(constant) => {
+                if constant.default.is_none() {
+                    return Err(syn::Error::new_spanned(constant, "Associated const of service trait must have a value")
+                        .to_compile_error())
+                }
+            }
+            non_method => {
+                return Err(syn::Error::new_spanned(non_method, "Service trait must have only methods and consts")
+                    .to_compile_error())
+            }
+        }
+    }
+    Ok(methods)
+}
+
 pub fn path_of_single_ident(ident: syn::Ident) -> syn::Path {
     syn::Path {
         leading_colon: None,
@@ -32,3 +68,27 @@ pub fn path_of_single_ident(ident: syn::Ident) -> syn::Path {
         },
     }
 }
+
+#[test]
+fn remote_methods_of_trait() {
+    let the_trait: syn::ItemTrait = syn::parse_str(
+        "trait Store: Service {
+            const VERSION: u32 = 3;
+            fn get(&self) -> u32;
+            fn get_twice(&self) -> u32 { self.get() * 2 }
+            fn put(&self, value: u32);
+        }",
+    )
+    .unwrap();
+    let methods: Vec<String> =
+        remote_methods(&the_trait).unwrap().iter().map(|method| method.sig.ident.to_string()).collect();
+    assert_eq!(methods, vec!["get", "put"]);
+
+    let the_trait: syn::ItemTrait = syn::parse_str("trait Store: Service { const VERSION: u32; }").unwrap();
+    assert!(remote_methods(&the_trait).is_err());
+    let the_trait: syn::ItemTrait = syn::parse_str("trait Store: Service { type Key; }").unwrap();
+    assert!(remote_methods(&the_trait).is_err());
+    let the_trait: syn::ItemTrait =
+        syn::parse_str("trait Store: Service { async fn get(&self) -> u32 { 0 } }").unwrap();
+    assert!(remote_methods(&the_trait).is_err());
+}
@@ -14,6 +14,8 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use quote::ToTokens;
+
 /// In addition, it coverts str->String and [] -> Vec
 pub fn is_ref(the_type: &syn::Type) -> Result<Option<syn::Type>, String> {
     if *the_type
@@ -52,6 +54,122 @@ pub fn is_ref(the_type: &syn::Type) -> Result<Option<syn::Type>, String> {
     }
 }
 
+/// Arguments that can be deserialized without copying, borrowing the received packet.
+#[derive(PartialEq, Debug)]
+pub enum Borrowable {
+    /// `&[u8]`
+    Bytes,
+    /// `&str`
+    Str,
+    /// `Cow<[u8]>`
+    CowBytes,
+    /// `Cow<str>`
+    CowStr,
+}
+
+pub fn is_borrowable(the_type: &syn::Type) -> Option<Borrowable> {
+    let bytes = syn::parse2::<syn::Type>(quote! {[u8]}).unwrap();
+    let str = syn::parse2::<syn::Type>(quote! {str}).unwrap();
+    match the_type {
+        syn::Type::Reference(x) if x.mutability.is_none() => {
+            if *x.elem == bytes {
+                Some(Borrowable::Bytes)
+            } else if *x.elem == str {
+                Some(Borrowable::Str)
+            } else {
+                None
+            }
+        }
+        syn::Type::Path(x) if x.qself.is_none() => {
+            let segment = x.path.segments.last()?;
+            if segment.ident != "Cow" {
+                return None
+            }
+            let args = match &segment.arguments {
+                syn::PathArguments::AngleBracketed(args) => args,
+                _ => return None,
+            };
+            // Skip the lifetime, if any
+            let the_type = args.args.iter().find_map(|arg| match arg {
+                syn::GenericArgument::Type(t) => Some(t),
+                _ => None,
+            })?;
+            if *the_type == bytes {
+                Some(Borrowable::CowBytes)
+            } else if *the_type == str {
+                Some(Borrowable::CowStr)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether the method returns a `Result`, whose error type must then be constructible from `CallError`.
+pub fn is_result(the_type: &syn::ReturnType) -> bool {
+    match the_type {
+        syn::ReturnType::Type(_, x) => match &**x {
+            syn::Type::Path(x) if x.qself.is_none() => x.path.segments.last().map_or(false, |segment| {
+                segment.ident == "Result" && matches!(segment.arguments, syn::PathArguments::AngleBracketed(_))
+            }),
+            _ => false,
+        },
+        syn::ReturnType::Default => false,
+    }
+}
+
+/// Whether the method returns a `Stream`, of which the items are sent one by one.
+pub fn is_stream(the_type: &syn::ReturnType) -> bool {
+    match the_type {
+        syn::ReturnType::Type(_, x) => match &**x {
+            syn::Type::Path(x) if x.qself.is_none() => x.path.segments.last().map_or(false, |segment| {
+                segment.ident == "Stream" && matches!(segment.arguments, syn::PathArguments::AngleBracketed(_))
+            }),
+            _ => false,
+        },
+        syn::ReturnType::Default => false,
+    }
+}
+
+/// Type of the items, if the method returns a `Stream`.
+pub fn stream_item(the_type: &syn::ReturnType) -> Option<syn::Type> {
+    if !is_stream(the_type) {
+        return None
+    }
+    match the_type {
+        syn::ReturnType::Type(_, x) => match &**x {
+            syn::Type::Path(x) => match &x.path.segments.last()?.arguments {
+                syn::PathArguments::AngleBracketed(arguments) => match arguments.args.first()? {
+                    syn::GenericArgument::Type(item) => Some(item.clone()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        },
+        syn::ReturnType::Default => None,
+    }
+}
+
+/// The type as it is written, such as `Vec<u8>`, without the spaces that the tokens put between everything.
+pub fn type_string(the_type: &syn::Type) -> String {
+    let tokens = the_type.to_token_stream().to_string();
+    let mut result = String::with_capacity(tokens.len());
+    let mut chars = tokens.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ' ' {
+            let before = result.chars().last().unwrap_or(' ');
+            let after = chars.peek().cloned().unwrap_or(' ');
+            if "<&([:".contains(before) || "<>,)];:".contains(after) {
+                continue
+            }
+        }
+        result.push(c);
+    }
+    result
+}
+
 #[test]
 fn recognize_ref() {
     let t = syn::parse_str::<syn::Type>("Vec<u32>").unwrap();
@@ -71,3 +189,56 @@ fn recognize_ref() {
     let t = syn::parse_str::<syn::Type>("&mut i32").unwrap();
     assert!(is_ref(&t).is_err())
 }
+
+#[test]
+fn recognize_result() {
+    let t = syn::parse_str::<syn::ReturnType>("-> Result<u32, CallError>").unwrap();
+    assert!(is_result(&t));
+    let t = syn::parse_str::<syn::ReturnType>("-> std::result::Result<(), MyError>").unwrap();
+    assert!(is_result(&t));
+    let t = syn::parse_str::<syn::ReturnType>("-> Vec<u32>").unwrap();
+    assert!(!is_result(&t));
+    let t = syn::parse_str::<syn::ReturnType>("").unwrap();
+    assert!(!is_result(&t));
+}
+
+#[test]
+fn recognize_stream() {
+    let t = syn::parse_str::<syn::ReturnType>("-> Stream<u32>").unwrap();
+    assert!(is_stream(&t));
+    let t = syn::parse_str::<syn::ReturnType>("-> fml::Stream<Vec<u8>>").unwrap();
+    assert!(is_stream(&t));
+    let t = syn::parse_str::<syn::ReturnType>("-> Vec<u32>").unwrap();
+    assert!(!is_stream(&t));
+    let t = syn::parse_str::<syn::ReturnType>("-> Stream<Vec<u8>>").unwrap();
+    assert_eq!(stream_item(&t).unwrap(), syn::parse_str::<syn::Type>("Vec<u8>").unwrap());
+    let t = syn::parse_str::<syn::ReturnType>("-> Stream").unwrap();
+    assert!(!is_stream(&t));
+}
+
+#[test]
+fn recognize_borrowable() {
+    let cases = [
+        ("&[u8]", Some(Borrowable::Bytes)),
+        ("&str", Some(Borrowable::Str)),
+        ("Cow<[u8]>", Some(Borrowable::CowBytes)),
+        ("std::borrow::Cow<'_, str>", Some(Borrowable::CowStr)),
+        ("&[u32]", None),
+        ("&mut [u8]", None),
+        ("Cow<Vec<u8>>", None),
+        ("Vec<u8>", None),
+    ];
+    for (t, expected) in cases.iter() {
+        assert_eq!(is_borrowable(&syn::parse_str::<syn::Type>(t).unwrap()), *expected, "{}", t);
+    }
+}
+
+#[test]
+fn render_type() {
+    let render = |s: &str| type_string(&syn::parse_str::<syn::Type>(s).unwrap());
+    assert_eq!(render("Vec<Vec<u8> >"), "Vec<Vec<u8>>");
+    assert_eq!(render("& 'a mut [u8]"), "&'a mut [u8]");
+    assert_eq!(render("std::borrow::Cow<[u8]>"), "std::borrow::Cow<[u8]>");
+    assert_eq!(render("(u32, SArc<dyn HelloRobot>)"), "(u32, SArc<dyn HelloRobot>)");
+    assert_eq!(render("Result<[u8; 32], CallError>"), "Result<[u8; 32], CallError>");
+}
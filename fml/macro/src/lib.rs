@@ -28,6 +28,11 @@ mod service_impl;
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
 
+/// Makes the trait a service trait, of which the objects can be exported and imported through the ports.
+///
+/// Associated consts must have values, and they go to a companion trait `<Trait>Consts`,
+/// since a trait with consts can't be made into an object. Every implementor gets it by a blanket impl,
+/// so they can name the consts as `Self::CONST` with it in scope, but can't override them.
 #[proc_macro_attribute]
 pub fn service(args: TokenStream, input: TokenStream) -> TokenStream {
     TokenStream::from(service::service(TokenStream2::from(args), TokenStream2::from(input)))
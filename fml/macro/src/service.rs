@@ -49,6 +49,9 @@ pub fn service_adv(args: TokenStream2, input: TokenStream2) -> TokenStream2 {
         Err(_) => return syn::Error::new_spanned(input, "You can use #[service] only on a trait").to_compile_error(),
     };
     let args: MacroArgs = syn::parse2(args).unwrap();
+    if let Err(x) = generics::check(&source_trait) {
+        return x
+    }
 
     let id = {
         let result = id::generate_id_registeration(&args, &source_trait);
@@ -57,6 +60,13 @@ pub fn service_adv(args: TokenStream2, input: TokenStream2) -> TokenStream2 {
             Err(x) => return x,
         }
     };
+    let descriptor = {
+        let result = descriptor::generate_descriptor(&args, &source_trait);
+        match result {
+            Ok(x) => x,
+            Err(x) => return x,
+        }
+    };
     let dispatch = {
         let result = dispatch::generate_dispatch(&args, &source_trait);
         match result {
@@ -72,9 +82,22 @@ pub fn service_adv(args: TokenStream2, input: TokenStream2) -> TokenStream2 {
         }
     };
 
+    // The trait as it will be declared
+    let mut the_trait = future::desugar_trait(&args.fml_path, &source_trait);
+    for item in the_trait.items.iter_mut() {
+        if let syn::TraitItem::Method(method) = item {
+            attributes::strip(method);
+        }
+    }
+
+    // A trait with consts can't be made into an object, so they go to a companion trait instead.
+    let consts = consts::generate_consts(&mut the_trait);
+
     quote! {
-        #source_trait
+        #the_trait
+        #consts
         #id
+        #descriptor
         #dispatch
         #import
     }
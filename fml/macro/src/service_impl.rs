@@ -19,9 +19,11 @@ use syn::parse::{Parse, ParseStream};
 use syn::punctuated::Punctuated;
 use syn::Token;
 
+/// The struct dispatches all the traits by itself, each by the trait it was exported as.
+/// The first one is the trait it is exported as by default, such as at the bootstrap.
 fn helper(
     fml_path: &syn::Path,
-    service_trait: &syn::Path,
+    service_traits: &[syn::Path],
     source_struct: &syn::ItemStruct,
 ) -> Result<TokenStream2, TokenStream2> {
     let got_no_handle = (|| -> Result<bool, TokenStream2> {
@@ -41,14 +43,36 @@ fn helper(
     }
 
     let struct_name = source_struct.ident.clone();
+    let service_trait = &service_traits[0];
 
     Ok(quote! {
-        #[cast_to([sync] #fml_path::Service)]
+        #[cast_to([sync] #fml_path::Service, #(#service_traits),*)]
         #[derive(Debug)]
         #source_struct
         impl #fml_path::ServiceDispatcher for #struct_name {
-            fn dispatch(&self, method: #fml_path::MethodId, arguments: &[u8], return_buffer: std::io::Cursor<&mut Vec<u8>>) {
-                <dyn #service_trait as #fml_path::DispatchService<dyn #service_trait>>::dispatch(self, method, arguments, return_buffer);
+            fn dispatch(&self, trait_id: #fml_path::TraitId, method: #fml_path::MethodId, arguments: &[u8], return_buffer: std::io::Cursor<&mut Vec<u8>>) -> Result<(), #fml_path::CallError> {
+                #(
+                    if trait_id == <dyn #service_traits as #fml_path::IdOfService<dyn #service_traits>>::id() {
+                        return <dyn #service_traits as #fml_path::DispatchService<dyn #service_traits>>::dispatch(self, method, arguments, return_buffer)
+                    }
+                )*
+                Err(#fml_path::CallError::UnknownMethod(method))
+            }
+            fn dispatch_async(self: std::sync::Arc<Self>, trait_id: #fml_path::TraitId, method: #fml_path::MethodId, arguments: &[u8]) -> Result<Option<#fml_path::PendingReturn>, #fml_path::CallError> {
+                #(
+                    if trait_id == <dyn #service_traits as #fml_path::IdOfService<dyn #service_traits>>::id() {
+                        return <dyn #service_traits as #fml_path::DispatchService<dyn #service_traits>>::dispatch_async(self, method, arguments)
+                    }
+                )*
+                Ok(None)
+            }
+            fn dispatch_stream(&self, trait_id: #fml_path::TraitId, method: #fml_path::MethodId, arguments: &[u8]) -> Result<Option<#fml_path::PendingStream>, #fml_path::CallError> {
+                #(
+                    if trait_id == <dyn #service_traits as #fml_path::IdOfService<dyn #service_traits>>::id() {
+                        return <dyn #service_traits as #fml_path::DispatchService<dyn #service_traits>>::dispatch_stream(self, method, arguments)
+                    }
+                )*
+                Ok(None)
             }
         }
         impl #fml_path::Service for #struct_name {
@@ -61,28 +85,31 @@ fn helper(
             fn get_trait_id(&self) -> #fml_path::TraitId {
                 <dyn #service_trait as #fml_path::IdOfService<dyn #service_trait>>::id()
             }
+            fn serves(&self, trait_id: #fml_path::TraitId) -> bool {
+                #(trait_id == <dyn #service_traits as #fml_path::IdOfService<dyn #service_traits>>::id())||*
+            }
         }
     })
 }
 
 struct MacroArgs {
     fml_path: syn::Path,
-    service_trait: syn::Path,
+    service_traits: Vec<syn::Path>,
 }
 impl Parse for MacroArgs {
     fn parse(input: ParseStream) -> syn::parse::Result<Self> {
         if input.is_empty() {
-            return Err(input.error("You must supply two arguments (FML path, Trait)"))
+            return Err(input.error("You must supply two or more arguments (FML path, Trait, ...)"))
         }
-        let mut args = Punctuated::<syn::Path, Token![,]>::parse_terminated(input)?;
-        if args.len() != 2 {
-            return Err(input.error("You must supply two arguments (FML path, Trait)"))
+        let args = Punctuated::<syn::Path, Token![,]>::parse_terminated(input)?;
+        if args.len() < 2 {
+            return Err(input.error("You must supply two or more arguments (FML path, Trait, ...)"))
         }
-        let service_trait = args.pop().unwrap().into_value();
-        let fml_path = args.pop().unwrap().into_value();
+        let mut args = args.into_iter();
+        let fml_path = args.next().unwrap();
         Ok(MacroArgs {
             fml_path,
-            service_trait,
+            service_traits: args.collect(),
         })
     }
 }
@@ -97,7 +124,7 @@ pub fn service_impl_adv(args: TokenStream2, input: TokenStream2) -> TokenStream2
         }
     };
 
-    match helper(&args.fml_path, &args.service_trait, &source_struct) {
+    match helper(&args.fml_path, &args.service_traits, &source_struct) {
         Ok(x) => x,
         Err(x) => x,
     }
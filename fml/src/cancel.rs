@@ -0,0 +1,131 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+struct Inner {
+    cancelled: AtomicBool,
+    /// Nothing is sent through this; dropping it wakes up everyone watching the receiver.
+    sender: Mutex<Option<Sender<()>>>,
+    receiver: Receiver<()>,
+    /// Futures waiting for the cancellation
+    wakers: Mutex<Vec<Waker>>,
+}
+
+/// A flag to abandon calls in progress.
+///
+/// The caller cancels the calls made under `with_token()`, and the service implementation
+/// serving such a call finds the cancellation through `is_cancelled()`, so that it can stop early.
+#[derive(Clone)]
+pub struct CancelToken {
+    inner: Arc<Inner>,
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        let (sender, receiver) = bounded(1);
+        CancelToken {
+            inner: Arc::new(Inner {
+                cancelled: AtomicBool::new(false),
+                sender: Mutex::new(Some(sender)),
+                receiver,
+                wakers: Default::default(),
+            }),
+        }
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.sender.lock().take();
+        for waker in self.inner.wakers.lock().drain(..) {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Returns a receiver which becomes disconnected when the token is cancelled.
+    pub(crate) fn watch(&self) -> Receiver<()> {
+        self.inner.receiver.clone()
+    }
+
+    /// Same as `watch()`, but for a future. The future must `forget()` its waker once it is done.
+    pub(crate) fn poll_cancelled(&self, cx: &mut Context) -> Poll<()> {
+        // Registered before the check, so that a cancellation in between isn't missed.
+        {
+            let mut wakers = self.inner.wakers.lock();
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+        }
+        if self.is_cancelled() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Stops waking the given waker, since a token may live much longer than the calls made with it.
+    pub(crate) fn forget(&self, waker: &Waker) {
+        self.inner.wakers.lock().retain(|x| !x.will_wake(waker));
+    }
+}
+
+// Token of the calls made from the current thread.
+//
+// While a service handler serves a call, the token of that call is set here,
+// so that the service can check it and all the nested calls are cancelled together.
+// Note that you must manually apply the token again if you created threads during service handling.
+thread_local!(static CURRENT: RefCell<Option<CancelToken>> = RefCell::new(None));
+
+/// Restores the previous token even if the given closure panics.
+struct Restore(Option<CancelToken>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        CURRENT.with(|c| c.replace(self.0.take()));
+    }
+}
+
+/// Returns the token that will be applied to the calls made from this thread.
+pub fn current() -> Option<CancelToken> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+/// Returns whether the call being served by this thread has been cancelled.
+pub fn is_cancelled() -> bool {
+    CURRENT.with(|c| c.borrow().as_ref().map_or(false, CancelToken::is_cancelled))
+}
+
+/// Runs `f`, making all the calls within it cancellable with the given token.
+/// This replaces the token of the call being served, if any.
+pub fn with_token<T, F: FnOnce() -> T>(token: &CancelToken, f: F) -> T {
+    let _restore = Restore(CURRENT.with(|c| c.replace(Some(token.clone()))));
+    f()
+}
@@ -0,0 +1,262 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::service::CallError;
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::io::Write;
+
+/// Serialization format of the payloads (arguments and return values) on the wire.
+pub trait Codec {
+    fn encode<W: Write, T: Serialize>(&self, writer: W, value: &T) -> Result<(), CallError>;
+
+    /// The result may borrow from `data`, if both the type and the format allow it.
+    fn decode<'a, T: Deserialize<'a>>(&self, data: &'a [u8]) -> Result<T, CallError>;
+
+    fn to_vec<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CallError> {
+        let mut buffer = Vec::new();
+        self.encode(&mut buffer, value)?;
+        Ok(buffer)
+    }
+}
+
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode<W: Write, T: Serialize>(&self, writer: W, value: &T) -> Result<(), CallError> {
+        serde_cbor::to_writer(writer, value).map_err(|e| CallError::Encode(e.to_string()))
+    }
+
+    fn decode<'a, T: Deserialize<'a>>(&self, data: &'a [u8]) -> Result<T, CallError> {
+        serde_cbor::from_slice(data).map_err(|e| CallError::Decode(e.to_string()))
+    }
+}
+
+/// Fastest, but the peer must have exactly the same definition of the types.
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<W: Write, T: Serialize>(&self, writer: W, value: &T) -> Result<(), CallError> {
+        bincode::serialize_into(writer, value).map_err(|e| CallError::Encode(e.to_string()))
+    }
+
+    fn decode<'a, T: Deserialize<'a>>(&self, data: &'a [u8]) -> Result<T, CallError> {
+        bincode::deserialize(data).map_err(|e| CallError::Decode(e.to_string()))
+    }
+}
+
+/// Human readable, for debugging.
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<W: Write, T: Serialize>(&self, writer: W, value: &T) -> Result<(), CallError> {
+        serde_json::to_writer(writer, value).map_err(|e| CallError::Encode(e.to_string()))
+    }
+
+    fn decode<'a, T: Deserialize<'a>>(&self, data: &'a [u8]) -> Result<T, CallError> {
+        serde_json::from_slice(data).map_err(|e| CallError::Decode(e.to_string()))
+    }
+}
+
+/// One of the codecs above, which is chosen for each port when it is linked.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum CodecKind {
+    Cbor,
+    Bincode,
+    Json,
+}
+
+impl Default for CodecKind {
+    fn default() -> Self {
+        CodecKind::Cbor
+    }
+}
+
+impl CodecKind {
+    pub(crate) const ALL: [CodecKind; 3] = [CodecKind::Cbor, CodecKind::Bincode, CodecKind::Json];
+
+    /// Identifier on the wire, used in the link negotiation
+    pub(crate) fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|codec| codec.to_byte() == byte)
+    }
+}
+
+impl Codec for CodecKind {
+    fn encode<W: Write, T: Serialize>(&self, writer: W, value: &T) -> Result<(), CallError> {
+        match self {
+            CodecKind::Cbor => Cbor.encode(writer, value),
+            CodecKind::Bincode => Bincode.encode(writer, value),
+            CodecKind::Json => Json.encode(writer, value),
+        }
+    }
+
+    fn decode<'a, T: Deserialize<'a>>(&self, data: &'a [u8]) -> Result<T, CallError> {
+        match self {
+            CodecKind::Cbor => Cbor.decode(data),
+            CodecKind::Bincode => Bincode.decode(data),
+            CodecKind::Json => Json.decode(data),
+        }
+    }
+}
+
+// Codec of the port that the current thread is serving.
+//
+// The service handlers set this, so that the generated dispatch code doesn't have to know
+// which port the call came from.
+thread_local!(static CURRENT: Cell<CodecKind> = Cell::new(CodecKind::Cbor));
+
+pub(crate) fn set_current(codec: CodecKind) {
+    CURRENT.with(|c| c.set(codec))
+}
+
+pub fn current() -> CodecKind {
+    CURRENT.with(|c| c.get())
+}
+
+/// Encodes with the codec of the port being served. This is for the auto-generated code.
+pub fn encode<W: Write, T: Serialize>(writer: W, value: &T) -> Result<(), CallError> {
+    current().encode(writer, value)
+}
+
+/// Decodes with the codec of the port being served. This is for the auto-generated code.
+pub fn decode<'a, T: Deserialize<'a>>(data: &'a [u8]) -> Result<T, CallError> {
+    current().decode(data)
+}
+
+/// Serializes a byte slice as bytes, rather than as a sequence of integers, so that it can be
+/// deserialized into `BorrowedBytes` without copying.
+pub struct AsBytes<'a>(pub &'a [u8]);
+
+impl<'a> std::fmt::Debug for AsBytes<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl<'a> Serialize for AsBytes<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// Bytes that borrow the received packet if the codec allows it, and copied otherwise.
+/// This is for the auto-generated dispatch code of `&[u8]` and `Cow<[u8]>` arguments.
+pub struct BorrowedBytes<'a>(pub Cow<'a, [u8]>);
+
+struct BorrowedBytesVisitor;
+
+impl<'de> Visitor<'de> for BorrowedBytesVisitor {
+    type Value = BorrowedBytes<'de>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "bytes")
+    }
+
+    fn visit_borrowed_bytes<E: de::Error>(self, v: &'de [u8]) -> Result<Self::Value, E> {
+        Ok(BorrowedBytes(Cow::Borrowed(v)))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(BorrowedBytes(Cow::Owned(v.to_vec())))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(BorrowedBytes(Cow::Owned(v)))
+    }
+
+    // Formats without bytes (e.g. JSON) give them as a sequence.
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(BorrowedBytes(Cow::Owned(bytes)))
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for BorrowedBytes<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(BorrowedBytesVisitor)
+    }
+}
+
+/// A string that borrows the received packet if the codec allows it, and copied otherwise.
+/// This is for the auto-generated dispatch code of `&str` and `Cow<str>` arguments.
+pub struct BorrowedStr<'a>(pub Cow<'a, str>);
+
+struct BorrowedStrVisitor;
+
+impl<'de> Visitor<'de> for BorrowedStrVisitor {
+    type Value = BorrowedStr<'de>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a string")
+    }
+
+    fn visit_borrowed_str<E: de::Error>(self, v: &'de str) -> Result<Self::Value, E> {
+        Ok(BorrowedStr(Cow::Borrowed(v)))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(BorrowedStr(Cow::Owned(v.to_owned())))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+        Ok(BorrowedStr(Cow::Owned(v)))
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for BorrowedStr<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(BorrowedStrVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let value = (1_u32, "Hello".to_owned(), vec![Some(2_i64), None]);
+        for codec in CodecKind::ALL.iter() {
+            let encoded = codec.to_vec(&value).unwrap();
+            assert_eq!(codec.decode::<(u32, String, Vec<Option<i64>>)>(&encoded).unwrap(), value);
+            assert_eq!(CodecKind::from_byte(codec.to_byte()), Some(*codec));
+        }
+        assert_eq!(Json.to_vec(&value).unwrap(), br#"[1,"Hello",[2,null]]"#.to_vec());
+    }
+
+    #[test]
+    fn borrowed() {
+        let data = vec![1_u8, 2, 3];
+        for codec in CodecKind::ALL.iter() {
+            let encoded = codec.to_vec(&(AsBytes(&data), "Hello")).unwrap();
+            let (bytes, string): (BorrowedBytes, BorrowedStr) = codec.decode(&encoded).unwrap();
+            assert_eq!(&*bytes.0, &data[..]);
+            assert_eq!(string.0, "Hello");
+            // JSON has no bytes
+            assert_eq!(matches!(bytes.0, Cow::Borrowed(_)), *codec != CodecKind::Json);
+            assert!(matches!(string.0, Cow::Borrowed(_)));
+        }
+    }
+}
@@ -28,6 +28,23 @@ pub struct FmlConfig {
     pub server_threads: usize,
     /// Maximum outbound call slots
     pub call_slots: usize,
+    /// Preferred codec of the payloads. The one actually used is negotiated with the peer on link.
+    #[serde(default)]
+    pub codec: crate::codec::CodecKind,
+    /// Maximum payload of a single packet. Larger messages are sent in fragments.
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: usize,
+    /// Maximum payload of a message, which is either the arguments or the result of a call
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+}
+
+fn default_chunk_size() -> usize {
+    64 * 1024
+}
+
+fn default_max_message_size() -> usize {
+    1024 * 1024 * 1024
 }
 
 /// The entire global context that is enough to make services function.
@@ -37,6 +54,8 @@ pub struct PortTable {
     /// TODO: Though it uses HashMap now, we can issue PortIds in a series from 0 to ...
     /// Thus it may be optimized to use plain array later.
     pub map: HashMap<PortId, (String, PortId, Port)>,
+    /// Services published by this module. See the naming module.
+    pub names: crate::service::naming::Names,
 }
 
 /// This manages thread-local keys for module instance discrimination
@@ -0,0 +1,61 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+// Deadline of the calls made from the current thread.
+//
+// While a service handler serves a call that has a deadline, the same deadline is set here,
+// so that all the nested calls made by the service inherit it automatically.
+// Note that you must manually apply the deadline again if you created threads during service handling.
+thread_local!(static DEADLINE: Cell<Option<Instant>> = Cell::new(None));
+
+/// Restores the previous deadline even if the given closure panics.
+struct Restore(Option<Instant>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        DEADLINE.with(|d| d.set(self.0))
+    }
+}
+
+/// Returns the deadline that will be applied to the calls made from this thread.
+pub fn get() -> Option<Instant> {
+    DEADLINE.with(|d| d.get())
+}
+
+/// Runs `f`, applying the given deadline to all the calls made within it.
+/// If there is already an earlier deadline, that one is kept.
+pub fn with_deadline<T, F: FnOnce() -> T>(deadline: Instant, f: F) -> T {
+    with(Some(deadline), f)
+}
+
+/// Same as `with_deadline()`, but the deadline is given as a duration from now.
+pub fn with_timeout<T, F: FnOnce() -> T>(timeout: Duration, f: F) -> T {
+    with_deadline(Instant::now() + timeout, f)
+}
+
+pub(crate) fn with<T, F: FnOnce() -> T>(deadline: Option<Instant>, f: F) -> T {
+    let previous = get();
+    let _restore = Restore(previous);
+    let effective = match (previous, deadline) {
+        (Some(previous), Some(deadline)) => Some(std::cmp::min(previous, deadline)),
+        (previous, deadline) => previous.or(deadline),
+    };
+    DEADLINE.with(|d| d.set(effective));
+    f()
+}
@@ -16,7 +16,10 @@
 
 extern crate codechain_basesandbox as cbsb;
 
+pub mod cancel;
+pub mod codec;
 mod context;
+pub mod deadline;
 mod port;
 pub mod queue;
 mod service;
@@ -27,34 +30,65 @@ mod tests;
 #[macro_use]
 extern crate intertrait;
 
+pub use codec::CodecKind;
 pub use context::{
     global, single_process_support::get_key, single_process_support::set_key, termination, FmlConfig, InstanceKey,
     PortTable,
 };
-pub use port::{PacketHeader, Port, PortId};
-pub use service::id::{setup_identifiers, IdMap};
-pub use service::SArc;
+pub use futures::future::BoxFuture;
+pub use port::{AsyncCall, PacketHeader, Port, PortId, ProtocolError, StreamCall, PROTOCOL_VERSION};
+pub use service::batch::{Batch, BatchResults, Queued};
+pub use service::descriptor::{describe_method, descriptors, ArgumentDescriptor, MethodDescriptor, ServiceDescriptor};
+pub use service::dynamic::{InvokeError, Invoker};
+pub use service::forward::Forwarder;
+pub use service::id::{setup_identifiers, IdMap, TypeName};
+pub use service::naming::{lookup, publish, unpublish};
+pub use service::query::query_service;
+pub use service::schema::{schema, shape_of, write_schema, Schema, Shape, Variant};
+pub use service::stream::Stream;
 pub use service::{
-    dispatch::PortDispatcher, dispatch::ServiceDispatcher, HandleInstance, MethodId, Service, ServiceObjectId, TraitId,
+    dispatch::PendingReturn, dispatch::PendingStream, dispatch::PortDispatcher, dispatch::ServiceDispatcher,
+    HandleInstance, MethodId, Service, ServiceObjectId, TraitId,
 };
+pub use service::{CallError, SArc};
 
 /// You should not import this! This is for the auto-generated code
 pub mod env {
+    pub use crate::codec;
     pub use crate::context::global;
     pub use crate::port::{PacketHeader, Port, PortId};
+    pub use crate::service::batch::{Batch, Queued};
+    pub use crate::service::descriptor::{ArgumentDescriptor, MethodDescriptor, ServiceDescriptor, DSC_REG};
     pub use crate::service::dispatch::ServiceDispatcher;
-    pub use crate::service::id::{MID_REG, TID_REG};
+    pub use crate::service::dispatch::{PendingReturn, PendingStream};
+    pub use crate::service::forward::Forwarder;
+    pub use crate::service::id::{fingerprint, generic_trait_id, TypeName, MID_REG, TID_REG, UNKNOWN_TRAIT};
+    pub use crate::service::query::QRY_REG;
+    pub use crate::service::schema::{shape_of, Shape};
     pub use crate::service::service_context;
+    pub use crate::service::stream::Stream;
+    pub use crate::service::CallError;
     pub use crate::service::{DispatchService, ExportService, IdOfService, ImportService, SArc};
     pub use crate::service::{HandleInstance, MethodId, MethodIdAtomic, Service, TraitId, TraitIdAtomic, ID_ORDERING};
+    pub use futures::{executor::block_on, future::BoxFuture};
 }
 
 /// You should not import this! This is for the auto-generated code
 pub mod env_mock {
+    pub use crate::codec;
     pub use crate::context::global;
     pub use crate::port::{PacketHeader, Port, PortId};
+    pub use crate::service::batch::{Batch, Queued};
+    pub use crate::service::descriptor::{ArgumentDescriptor, MethodDescriptor, ServiceDescriptor, DSC_REG};
     pub use crate::service::dispatch::ServiceDispatcher;
-    pub use crate::service::id::{MID_REG, TID_REG};
+    pub use crate::service::dispatch::{PendingReturn, PendingStream};
+    pub use crate::service::forward::Forwarder;
+    pub use crate::service::id::{fingerprint, generic_trait_id, TypeName, MID_REG, TID_REG, UNKNOWN_TRAIT};
+    pub use crate::service::query::QRY_REG;
+    pub use crate::service::schema::{shape_of, Shape};
+    pub use crate::service::stream::Stream;
+    pub use crate::service::CallError;
     pub use crate::service::{DispatchService, ExportService, IdOfService, ImportService, SArc};
     pub use crate::service::{HandleInstance, MethodId, MethodIdAtomic, Service, TraitId, TraitIdAtomic, ID_ORDERING};
+    pub use futures::{executor::block_on, future::BoxFuture};
 }
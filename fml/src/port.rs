@@ -14,13 +14,27 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod batch;
+mod chunk;
 pub mod client;
+mod header;
 pub mod server;
 
+use crate::cancel::CancelToken;
+use crate::codec::Codec;
+use crate::codec::CodecKind;
 use crate::context::{single_process_support::InstanceKey, FmlConfig};
-use crate::service::{MethodId, PortDispatcher, ServiceObjectId};
+use crate::service::id::{fingerprints, mismatched_methods, MethodFingerprint};
+use crate::service::{CallError, HandleInstance, MethodId, PortDispatcher, ServiceObjectId, TraitId};
 use cbsb::ipc::{multiplex, IpcRecv, IpcSend};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chunk::Chunking;
+pub use client::{AsyncCall, StreamCall};
+pub use header::{PacketHeader, ProtocolError, PROTOCOL_VERSION};
+use header::{FLAG_CALL, FLAG_ERROR, FLAG_HELLO, FLAG_ITEM, FLAG_ONEWAY};
 
 // This module implements two important communication models: Client and Server
 //
@@ -32,72 +46,217 @@ use std::sync::Arc;
 // Here servcie handler simply calls the dispatcher given by the port,
 // whenever it receives a new inbound call.
 //
-// TODO: Introduce Rust async/await to support N concurrent calls where
-// N > # of handler threads
+// Async methods of a service are driven by an executor instead, so that they can have
+// more concurrent calls than the handler threads.
+//
+// A method returning a stream occupies the handler until the stream ends, sending the items
+// as far as the caller has given credits for.
 
 pub type SlotId = u32;
 pub type PortId = u16;
 
-const SLOT_CALL_OR_RETURN_INDICATOR: SlotId = 1000;
 const DELETE_INDICATOR: MethodId = 1234;
+// Sent by the caller to cancel the call running on the slot. There is no response for this.
+const CANCEL_INDICATOR: MethodId = 1236;
+// The call carries several calls to be served in order. See the batch module.
+const BATCH_INDICATOR: MethodId = 1237;
+// Sent by the caller of a stream to let the exporter send more items. The payload is the number of them.
+const CREDIT_INDICATOR: MethodId = 1238;
+// Asks whether the object implements the trait of which the id is the payload. See `Port::query()`.
+const QUERY_INDICATOR: MethodId = 1239;
+// Asks for the service published under the name and as the trait, which are the payload. See `Port::lookup()`.
+const LOOKUP_INDICATOR: MethodId = 1240;
+
+// How many items of a stream the exporter may send ahead of the caller's consumption.
+const STREAM_WINDOW: u32 = 16;
+
+// Value of PacketHeader::slot for one-way calls. The reassembler still needs one to tell the messages apart.
+const ONEWAY_SLOT: SlotId = SlotId::MAX;
+
+// Value of PacketHeader::timeout for a call without deadline.
+const NO_DEADLINE: u64 = 0;
 
 const MULTIPLEX_INDEX_SERVER: usize = 0;
 const MULTIPLEX_INDEX_CLIENT: usize = 1;
 
-#[repr(C)]
-#[derive(PartialEq, Debug)]
-pub struct PacketHeader {
-    pub slot: SlotId,
-    pub handle: ServiceObjectId,
-    pub method: MethodId,
-}
+// How long to wait for the peer's hello when linking.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(30);
 
-impl PacketHeader {
-    pub fn new(buffer: &[u8]) -> Self {
-        unsafe { std::ptr::read(buffer.as_ptr().cast()) }
-    }
+pub struct ServerOrClientForwarder;
 
-    pub fn write(&self, buffer: &mut [u8]) {
-        unsafe {
-            std::ptr::copy_nonoverlapping(self, buffer.as_mut_ptr().cast(), 1);
+impl multiplex::Forward for ServerOrClientForwarder {
+    fn forward(data: &[u8]) -> usize {
+        match PacketHeader::read(&data) {
+            Ok(header) if header.flags & FLAG_CALL != 0 => MULTIPLEX_INDEX_SERVER,
+            // An invalid packet is dropped by the receiver of the responses, which can't be confused by it.
+            _ => MULTIPLEX_INDEX_CLIENT,
         }
     }
 }
 
-#[test]
-fn encoding_packet_header() {
-    let ph1 = PacketHeader {
-        slot: 0x1234,
-        handle: ServiceObjectId {
-            index: 0x8888,
-        },
-        method: 0x5678,
-    };
-    let mut buffer = vec![0 as u8; std::mem::size_of::<PacketHeader>()];
-    ph1.write(&mut buffer);
-    let ph2 = PacketHeader::new(&buffer);
-    assert_eq!(ph2, ph1);
+/// Payload of the hello packet, which is always in CBOR since the codec is not decided yet.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Hello {
+    /// Codecs this end supports, the preferred one first
+    pub codecs: Vec<u8>,
+    /// Signatures of the methods this end knows
+    pub methods: Vec<MethodFingerprint>,
 }
 
-pub struct ServerOrClientForwarder;
+pub(crate) fn supported_codecs(preferred: CodecKind) -> Vec<u8> {
+    std::iter::once(preferred)
+        .chain(CodecKind::ALL.iter().copied().filter(|&codec| codec != preferred))
+        .map(CodecKind::to_byte)
+        .collect()
+}
 
-impl multiplex::Forward for ServerOrClientForwarder {
-    fn forward(data: &[u8]) -> usize {
-        let header = PacketHeader::new(&data);
-        if header.slot >= SLOT_CALL_OR_RETURN_INDICATOR {
-            MULTIPLEX_INDEX_SERVER
-        } else {
-            MULTIPLEX_INDEX_CLIENT
-        }
+pub(crate) fn hello_packet(hello: &Hello) -> Vec<u8> {
+    let mut packet = vec![0; PacketHeader::SIZE];
+    serde_cbor::to_writer(&mut packet, hello).unwrap();
+    PacketHeader {
+        flags: FLAG_HELLO,
+        slot: 0,
+        handle: ServiceObjectId::default(),
+        method: 0,
+        timeout: NO_DEADLINE,
+    }
+    .write(&mut packet);
+    packet
+}
+
+/// Chooses the codec given the peer's hello payload. Both ends reach the same result.
+///
+/// If the preferences differ, the one with the lower identifier wins, as long as both support it.
+pub(crate) fn choose_codec(ours: CodecKind, theirs: &[u8]) -> Result<CodecKind, ProtocolError> {
+    let supported_by_peer = |codec: CodecKind| theirs.contains(&codec.to_byte());
+    let their_preference = theirs.first().and_then(|&byte| CodecKind::from_byte(byte));
+    std::iter::once(ours)
+        .chain(their_preference)
+        .filter(|&codec| supported_by_peer(codec))
+        .min()
+        .ok_or(ProtocolError::NoCommonCodec)
+}
+
+/// Exchanges hello with the peer, so that we fail early if the peer speaks another protocol.
+/// Both ends send first, so this doesn't block even if they link at the same time.
+fn handshake<S: IpcSend, R: IpcRecv>(send: &S, recv: &R, preferred: CodecKind) -> Result<CodecKind, ProtocolError> {
+    let methods = fingerprints();
+    send.send(&hello_packet(&Hello {
+        codecs: supported_codecs(preferred),
+        methods: methods.clone(),
+    }));
+    let packet = recv.recv(Some(HELLO_TIMEOUT)).map_err(|_| ProtocolError::NoHello)?;
+    if PacketHeader::read(&packet)?.flags != FLAG_HELLO {
+        return Err(ProtocolError::NoHello)
+    }
+    let hello: Hello = serde_cbor::from_slice(&packet[PacketHeader::SIZE..]).map_err(|_| ProtocolError::NoHello)?;
+    let mismatched = mismatched_methods(&methods, &hello.methods);
+    if !mismatched.is_empty() {
+        return Err(ProtocolError::SignatureMismatch(mismatched))
     }
+    choose_codec(preferred, &hello.codecs)
 }
 
 pub struct Port {
     dispatcher: Arc<PortDispatcher>,
+    codec: CodecKind,
     /// _multiplexer must be dropped first
     _multiplexer: multiplex::Multiplexer,
     _server: server::Server,
-    client: client::Client,
+    client: Arc<client::Client>,
+}
+
+/// The calling side of a port, which is used without holding the port table.
+///
+/// A call may wait long for the response, while the port table is to be written by linking or unlinking.
+#[derive(Clone)]
+pub(crate) struct Caller {
+    client: Arc<client::Client>,
+    codec: CodecKind,
+}
+
+impl Caller {
+    /// See `Port::call()`.
+    pub fn call(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        data: Vec<u8>,
+        deadline: Option<Instant>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Vec<u8>, CallError> {
+        self.client.call(handle, method, data, deadline, cancel)
+    }
+
+    /// See `Port::call_oneway()`.
+    pub fn call_oneway(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        data: Vec<u8>,
+        deadline: Option<Instant>,
+    ) -> Result<(), CallError> {
+        self.client.call_oneway(handle, method, data, deadline)
+    }
+
+    /// See `Port::call_batch()`.
+    pub fn call_batch(
+        &self,
+        calls: Vec<(ServiceObjectId, MethodId, Vec<u8>)>,
+        deadline: Option<Instant>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Vec<Result<Vec<u8>, CallError>>, CallError> {
+        let calls: Vec<Vec<u8>> = calls
+            .into_iter()
+            .map(|(handle, method, mut data)| {
+                PacketHeader {
+                    flags: FLAG_CALL,
+                    slot: 0,
+                    handle,
+                    method,
+                    timeout: NO_DEADLINE,
+                }
+                .write(&mut data);
+                data
+            })
+            .collect();
+        let message = batch::pack(calls.iter().map(Vec::as_slice));
+        let handle = ServiceObjectId::default();
+        let response = self.client.call(handle, BATCH_INDICATOR, message, deadline, cancel)?;
+        let results = batch::unpack(&response)?;
+        if results.len() != calls.len() {
+            return Err(CallError::Decode("Malformed batch".to_owned()))
+        }
+        Ok(results.into_iter().map(|result| client::check_response(result.to_vec(), self.codec)).collect())
+    }
+
+    /// See `Port::query()`.
+    pub fn query(
+        &self,
+        handle: ServiceObjectId,
+        trait_id: TraitId,
+        deadline: Option<Instant>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Option<HandleInstance>, CallError> {
+        let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+        self.codec.encode(&mut buffer, &(trait_id,))?;
+        let response = self.client.call(handle, QUERY_INDICATOR, buffer, deadline, cancel)?;
+        self.codec.decode(&response[PacketHeader::SIZE..])
+    }
+
+    /// See `Port::lookup()`.
+    pub fn lookup(
+        &self,
+        name: &str,
+        trait_id: TraitId,
+        deadline: Option<Instant>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Option<HandleInstance>, CallError> {
+        let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+        self.codec.encode(&mut buffer, &(name, trait_id))?;
+        let response = self.client.call(ServiceObjectId::default(), LOOKUP_INDICATOR, buffer, deadline, cancel)?;
+        self.codec.decode(&response[PacketHeader::SIZE..])
+    }
 }
 
 impl Port {
@@ -108,34 +267,142 @@ impl Port {
         dispatcher: Arc<PortDispatcher>,
         instance_key: InstanceKey,
         config: &FmlConfig,
-    ) -> Self {
+    ) -> Result<Self, ProtocolError> {
+        let codec = handshake(&send, &recv, config.codec)?;
+        let chunking = Chunking {
+            chunk_size: config.chunk_size,
+            max_message_size: config.max_message_size,
+        };
         let (mut multiplex_ends, _multiplexer) =
             multiplex::Multiplexer::create::<ServerOrClientForwarder, S, R>(send, recv, 2, 256);
 
         let client = {
             let (send, recv) = multiplex_ends.pop().unwrap();
-            client::Client::new(send, recv, config.call_slots as u32)
+            Arc::new(client::Client::new(send, recv, config.call_slots as u32, codec, chunking))
         };
 
         let _server = {
             let (send, recv) = multiplex_ends.pop().unwrap();
-            server::Server::new(dispatcher.clone(), send, recv, instance_key, id, config.server_threads, 128)
+            server::Server::new(
+                dispatcher.clone(),
+                send,
+                recv,
+                instance_key,
+                id,
+                codec,
+                chunking,
+                config.server_threads,
+                128,
+            )
         };
 
-        Port {
+        Ok(Port {
             dispatcher,
+            codec,
             _multiplexer,
             _server,
             client,
-        }
+        })
+    }
+
+    pub fn call(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        data: Vec<u8>,
+        deadline: Option<Instant>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Vec<u8>, CallError> {
+        self.client.call(handle, method, data, deadline, cancel)
+    }
+
+    /// Same as `call()`, but doesn't block the thread. See `Client::call_async()`.
+    pub fn call_async(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        data: Vec<u8>,
+        deadline: Option<Instant>,
+        cancel: Option<CancelToken>,
+    ) -> client::AsyncCall {
+        self.client.call_async(handle, method, data, deadline, cancel)
     }
 
-    pub fn call(&self, handle: ServiceObjectId, method: MethodId, data: Vec<u8>) -> Vec<u8> {
-        self.client.call(handle, method, data)
+    /// Calls a method that returns a stream. See `Client::call_stream()`.
+    pub fn call_stream(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        data: Vec<u8>,
+        deadline: Option<Instant>,
+        cancel: Option<CancelToken>,
+    ) -> client::StreamCall {
+        self.client.call_stream(handle, method, data, deadline, cancel)
+    }
+
+    /// Makes the given calls in a single message, and returns their results in the same order.
+    /// Each call is `(handle, method, data)`, where data has PacketHeader::SIZE bytes reserved on the first.
+    ///
+    /// The whole batch fails only if the message itself couldn't be delivered.
+    pub fn call_batch(
+        &self,
+        calls: Vec<(ServiceObjectId, MethodId, Vec<u8>)>,
+        deadline: Option<Instant>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Vec<Result<Vec<u8>, CallError>>, CallError> {
+        self.caller().call_batch(calls, deadline, cancel)
+    }
+
+    /// Sends a call that expects no response. See `Client::call_oneway()`.
+    pub fn call_oneway(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        data: Vec<u8>,
+        deadline: Option<Instant>,
+    ) -> Result<(), CallError> {
+        self.client.call_oneway(handle, method, data, deadline)
+    }
+
+    pub fn delete(&self, handle: ServiceObjectId) -> Result<(), CallError> {
+        self.client.delete(handle)
+    }
+
+    /// Asks the exporter whether the object implements the given trait,
+    /// which it exports again as that trait if so.
+    pub fn query(
+        &self,
+        handle: ServiceObjectId,
+        trait_id: TraitId,
+        deadline: Option<Instant>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Option<HandleInstance>, CallError> {
+        self.caller().query(handle, trait_id, deadline, cancel)
+    }
+
+    /// Asks the peer for the service it publishes under the name as the given trait,
+    /// which it exports if there is one.
+    pub fn lookup(
+        &self,
+        name: &str,
+        trait_id: TraitId,
+        deadline: Option<Instant>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Option<HandleInstance>, CallError> {
+        self.caller().lookup(name, trait_id, deadline, cancel)
+    }
+
+    /// For the calls made after the port table is released. See `Caller`.
+    pub(crate) fn caller(&self) -> Caller {
+        Caller {
+            client: self.client.clone(),
+            codec: self.codec,
+        }
     }
 
-    pub fn delete(&self, handle: ServiceObjectId) {
-        self.client.delete(handle);
+    /// The codec negotiated with the peer
+    pub fn codec(&self) -> CodecKind {
+        self.codec
     }
 
     pub fn dispatcher_get(&self) -> Arc<PortDispatcher> {
@@ -0,0 +1,91 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A batch carries several calls in a single message, and its response carries their results in the same order.
+//
+// The payload is a sequence of entries, each of which is a 4-byte little endian length followed by a whole packet:
+// a header and the arguments for a call, or a header and the return value for a result.
+// A result has FLAG_ERROR if the call failed, just like an ordinary response.
+
+use super::PacketHeader;
+use crate::service::CallError;
+use std::convert::TryInto;
+
+/// Makes the payload of a batch, reserving PacketHeader::SIZE bytes on the first.
+pub fn pack<'a>(entries: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    let mut message = vec![0; PacketHeader::SIZE];
+    for entry in entries {
+        message.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+        message.extend_from_slice(entry);
+    }
+    message
+}
+
+/// Splits the message into the packets of its entries.
+pub fn unpack(message: &[u8]) -> Result<Vec<&[u8]>, CallError> {
+    let malformed = || CallError::Decode("Malformed batch".to_owned());
+    let mut entries = Vec::new();
+    let mut rest = &message[PacketHeader::SIZE..];
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(malformed())
+        }
+        let length = u32::from_le_bytes(rest[0..4].try_into().unwrap()) as usize;
+        let entry = rest.get(4..4 + length).ok_or_else(malformed)?;
+        PacketHeader::read(entry).map_err(|_| malformed())?;
+        entries.push(entry);
+        rest = &rest[4 + length..];
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ServiceObjectId;
+
+    fn packet(method: u32, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; PacketHeader::SIZE];
+        packet.extend_from_slice(payload);
+        PacketHeader {
+            flags: 0,
+            slot: 0,
+            handle: ServiceObjectId {
+                index: 1,
+                generation: 0,
+                nonce: 0,
+            },
+            method,
+            timeout: 0,
+        }
+        .write(&mut packet);
+        packet
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        let entries = vec![packet(1, b""), packet(2, b"Hello"), packet(3, &[0; 100])];
+        let message = pack(entries.iter().map(Vec::as_slice));
+        let unpacked = unpack(&message).unwrap();
+        assert_eq!(unpacked, entries.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        assert_eq!(unpack(&pack(std::iter::empty())).unwrap().len(), 0);
+
+        // Truncated
+        assert!(unpack(&message[..message.len() - 1]).is_err());
+        // Not a packet
+        assert!(unpack(&pack(std::iter::once(&b"Hello"[..]))).is_err());
+    }
+}
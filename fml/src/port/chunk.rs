@@ -0,0 +1,248 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Large messages are sent as a series of fragments, so that they don't block the other traffic of the port
+// and the channels below never have to hold the whole message at once.
+//
+// Every fragment is a packet with the same header as the message, except its length.
+// All of them but the last have FLAG_MORE, and the first one starts the message.
+// Fragments of a message never interleave with another message of the same slot and direction,
+// since a slot carries only one message at a time.
+
+use super::header::FLAG_MORE;
+use super::{PacketHeader, ProtocolError, SlotId};
+use crossbeam::channel::{SendError, Sender};
+use std::collections::HashMap;
+
+/// Limits on the messages of a port
+#[derive(Clone, Copy, Debug)]
+pub struct Chunking {
+    /// Maximum payload of a single fragment
+    pub chunk_size: usize,
+    /// Maximum payload of a whole message, either to send or to receive
+    pub max_message_size: usize,
+}
+
+impl Chunking {
+    pub fn payload_size(message: &[u8]) -> usize {
+        message.len() - PacketHeader::SIZE
+    }
+
+    /// Sends the message, fragmenting it if needed.
+    /// The header must have been written already.
+    pub fn send(&self, send: &Sender<Vec<u8>>, message: Vec<u8>) -> Result<(), SendError<Vec<u8>>> {
+        if Self::payload_size(&message) <= self.chunk_size {
+            return send.send(message)
+        }
+        let mut header = PacketHeader::read(&message).unwrap();
+        let mut chunks = message[PacketHeader::SIZE..].chunks(self.chunk_size).peekable();
+        while let Some(chunk) = chunks.next() {
+            if chunks.peek().is_some() {
+                header.flags |= FLAG_MORE;
+            } else {
+                header.flags &= !FLAG_MORE;
+            }
+            let mut fragment = Vec::with_capacity(PacketHeader::SIZE + chunk.len());
+            fragment.resize(PacketHeader::SIZE, 0);
+            fragment.extend_from_slice(chunk);
+            header.write(&mut fragment);
+            send.send(fragment)?;
+        }
+        Ok(())
+    }
+}
+
+enum Pending {
+    Receiving(Vec<u8>),
+    /// The message turned out to be too large, so the rest will be discarded.
+    Overflowed(usize),
+}
+
+/// Message that exceeded the limit, of which only the header is left.
+#[derive(PartialEq, Debug)]
+pub struct Overflow {
+    pub header: PacketHeader,
+    pub size: usize,
+}
+
+/// Reassembles the fragments received from one direction of a port.
+pub struct Reassembler {
+    max_message_size: usize,
+    pending: HashMap<SlotId, Pending>,
+}
+
+impl Reassembler {
+    pub fn new(chunking: Chunking) -> Self {
+        Reassembler {
+            max_message_size: chunking.max_message_size,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Takes a packet, and returns the message if it completes one.
+    ///
+    /// An invalid packet is rejected, and so is the message of its slot received so far.
+    pub fn push(&mut self, packet: Vec<u8>) -> Result<Option<Result<Vec<u8>, Overflow>>, ProtocolError> {
+        let mut header = match PacketHeader::read(&packet) {
+            Ok(header) => header,
+            Err(e) => {
+                if let Some(slot) = PacketHeader::read_slot(&packet) {
+                    self.pending.remove(&slot);
+                }
+                return Err(e)
+            }
+        };
+        let more = header.flags & FLAG_MORE != 0;
+        let pending = match self.pending.remove(&header.slot) {
+            None if !more => {
+                let size = Chunking::payload_size(&packet);
+                if size > self.max_message_size {
+                    return Ok(Some(Err(Overflow {
+                        header,
+                        size,
+                    })))
+                }
+                return Ok(Some(Ok(packet)))
+            }
+            None => Pending::Receiving(packet),
+            Some(Pending::Receiving(mut message)) => {
+                message.extend_from_slice(&packet[PacketHeader::SIZE..]);
+                Pending::Receiving(message)
+            }
+            Some(Pending::Overflowed(size)) => Pending::Overflowed(size + Chunking::payload_size(&packet)),
+        };
+        let pending = match pending {
+            Pending::Receiving(message) if Chunking::payload_size(&message) > self.max_message_size => {
+                Pending::Overflowed(Chunking::payload_size(&message))
+            }
+            pending => pending,
+        };
+
+        if more {
+            self.pending.insert(header.slot, pending);
+            return Ok(None)
+        }
+        header.flags &= !FLAG_MORE;
+        Ok(Some(match pending {
+            Pending::Receiving(mut message) => {
+                header.write(&mut message);
+                Ok(message)
+            }
+            Pending::Overflowed(size) => Err(Overflow {
+                header,
+                size,
+            }),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ServiceObjectId;
+    use crossbeam::channel::unbounded;
+
+    fn message(size: usize) -> Vec<u8> {
+        let mut message = vec![0; PacketHeader::SIZE];
+        message.extend((0..size).map(|i| i as u8));
+        PacketHeader {
+            flags: 0,
+            slot: 3,
+            handle: ServiceObjectId {
+                index: 1,
+                generation: 0,
+                nonce: 0,
+            },
+            method: 2,
+            timeout: 0,
+        }
+        .write(&mut message);
+        message
+    }
+
+    #[test]
+    fn fragment_and_reassemble() {
+        let chunking = Chunking {
+            chunk_size: 10,
+            max_message_size: 100,
+        };
+        let (send, recv) = unbounded();
+        let mut reassembler = Reassembler::new(chunking);
+        for &size in &[0, 10, 11, 35, 100] {
+            chunking.send(&send, message(size)).unwrap();
+            let fragments: Vec<_> = recv.try_iter().collect();
+            assert_eq!(fragments.len(), std::cmp::max(1, (size + 9) / 10));
+            assert!(fragments.iter().all(|f| Chunking::payload_size(f) <= 10));
+
+            let mut results: Vec<_> = fragments.into_iter().filter_map(|f| reassembler.push(f).unwrap()).collect();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results.pop().unwrap().ok().unwrap(), message(size));
+        }
+    }
+
+    #[test]
+    fn too_large() {
+        let (send, recv) = unbounded();
+        Chunking {
+            chunk_size: 10,
+            max_message_size: 1000,
+        }
+        .send(&send, message(101))
+        .unwrap();
+        let mut reassembler = Reassembler::new(Chunking {
+            chunk_size: 10,
+            max_message_size: 100,
+        });
+        let mut results: Vec<_> = recv.try_iter().filter_map(|f| reassembler.push(f).unwrap()).collect();
+        assert_eq!(results.len(), 1);
+        let overflow = results.pop().unwrap().err().unwrap();
+        assert_eq!(overflow.size, 101);
+        assert_eq!(overflow.header.method, 2);
+        // It must be able to receive the next one.
+        assert_eq!(reassembler.push(message(5)).unwrap().unwrap().ok().unwrap(), message(5));
+    }
+
+    #[test]
+    fn invalid_packet() {
+        let (send, recv) = unbounded();
+        Chunking {
+            chunk_size: 10,
+            max_message_size: 100,
+        }
+        .send(&send, message(25))
+        .unwrap();
+        let mut fragments: Vec<_> = recv.try_iter().collect();
+        let mut reassembler = Reassembler::new(Chunking {
+            chunk_size: 10,
+            max_message_size: 100,
+        });
+        assert_eq!(reassembler.push(fragments.remove(0)), Ok(None));
+        // The length doesn't match the payload.
+        let mut broken = fragments.remove(0);
+        broken.pop();
+        assert_eq!(
+            reassembler.push(broken),
+            Err(ProtocolError::LengthMismatch {
+                header: 10,
+                actual: 9,
+            })
+        );
+        assert_eq!(reassembler.push(vec![1, 2, 3]), Err(ProtocolError::TooShort(3)));
+        // The message was discarded, so the last fragment starts a new one.
+        assert_eq!(reassembler.push(fragments.remove(0)).unwrap().unwrap().ok().unwrap().len(), PacketHeader::SIZE + 5);
+        assert_eq!(reassembler.push(message(5)).unwrap().unwrap().ok().unwrap(), message(5));
+    }
+}
@@ -14,13 +14,24 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use super::chunk::{Chunking, Overflow, Reassembler};
 use super::PacketHeader;
-use super::{DELETE_INDICATOR, SLOT_CALL_OR_RETURN_INDICATOR};
+use super::{CANCEL_INDICATOR, CREDIT_INDICATOR, DELETE_INDICATOR, NO_DEADLINE, ONEWAY_SLOT, STREAM_WINDOW};
+use super::{FLAG_CALL, FLAG_ERROR, FLAG_ITEM, FLAG_ONEWAY};
+use crate::cancel::CancelToken;
+use crate::codec::{Codec, CodecKind};
 use crate::queue::Queue;
-use crate::service::{MethodId, ServiceObjectId};
-use crossbeam::channel::{bounded, Receiver, Sender};
+use crate::service::{CallError, MethodId, ServiceObjectId};
+use crossbeam::channel::{after, bounded, never, select, Receiver, Sender};
+use futures::channel::{mpsc, oneshot};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use super::SlotId;
 
@@ -36,22 +47,231 @@ struct CallSlot {
     response: Receiver<Vec<u8>>,
 }
 
-fn receiver(recv: Receiver<Vec<u8>>, response_send: Vec<Sender<Vec<u8>>>) -> Result<(), ()> {
+/// Free call slots, which both threads and futures can wait for.
+struct CallSlots {
+    queue: Queue<CallSlot>,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl CallSlots {
+    fn push(&self, slot: CallSlot) {
+        self.queue.push(slot);
+        for waker in self.wakers.lock().drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn pop(&self, timeout: Option<Duration>) -> Result<CallSlot, ()> {
+        self.queue.pop(timeout)
+    }
+
+    fn poll_pop(&self, cx: &mut Context) -> Poll<CallSlot> {
+        // Registered before the check, so that a slot returned in between isn't missed.
+        {
+            let mut wakers = self.wakers.lock();
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+        }
+        match self.queue.try_pop() {
+            Some(slot) => Poll::Ready(slot),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Slots of which the caller has given up waiting for the response.
+/// Such a slot is returned back only after the late response arrives, so that it can't be mistaken
+/// as a response for the next call.
+type AbandonedSlots = Mutex<HashMap<SlotId, CallSlot>>;
+
+/// Slots used by `call_async()`, of which the response goes to the future instead.
+type AsyncResponses = Mutex<HashMap<SlotId, oneshot::Sender<Vec<u8>>>>;
+
+/// Slots used by `call_stream()`, of which the items and the final response go to the stream.
+/// The exporter never sends more items than the window, so this needs no bound.
+type StreamResponses = Mutex<HashMap<SlotId, mpsc::UnboundedSender<Vec<u8>>>>;
+
+/// Everything but the receiver thread, shared by the client, the receiver and the async calls.
+struct Shared {
+    call_slots: CallSlots,
+    abandoned_slots: AbandonedSlots,
+    async_responses: AsyncResponses,
+    stream_responses: StreamResponses,
+    /// For the one-way calls, which don't have a slot to send through
+    invoke: Sender<Vec<u8>>,
+    /// One-way calls share a slot number, so their fragments must not interleave.
+    oneway_lock: Mutex<()>,
+    codec: CodecKind,
+    chunking: Chunking,
+}
+
+impl Shared {
+    fn check_size(&self, data: &[u8]) -> Result<(), CallError> {
+        let size = Chunking::payload_size(data);
+        let limit = self.chunking.max_message_size;
+        if size > limit {
+            return Err(CallError::TooLarge {
+                size,
+                limit,
+            })
+        }
+        Ok(())
+    }
+
+    /// Sends the call through the slot. The caller must return the slot if this fails.
+    fn send_call(
+        &self,
+        slot: &CallSlot,
+        handle: ServiceObjectId,
+        method: MethodId,
+        data: Vec<u8>,
+        deadline: Option<Instant>,
+    ) -> Result<(), CallError> {
+        self.send(&slot.invoke, FLAG_CALL, slot.id, handle, method, data, deadline)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn send(
+        &self,
+        invoke: &Sender<Vec<u8>>,
+        flags: u8,
+        slot: SlotId,
+        handle: ServiceObjectId,
+        method: MethodId,
+        mut data: Vec<u8>,
+        deadline: Option<Instant>,
+    ) -> Result<(), CallError> {
+        let timeout = match deadline.map(time_left) {
+            // Rounding down to 0 must not be taken as no deadline.
+            Some(Some(x)) => std::cmp::max(x.as_micros() as u64, 1),
+            Some(None) => return Err(CallError::Timeout),
+            None => NO_DEADLINE,
+        };
+        let header = PacketHeader {
+            handle,
+            method,
+            flags,
+            slot,
+            timeout,
+        };
+        header.write(&mut data);
+        self.chunking.send(invoke, data).map_err(|_| CallError::PeerGone)
+    }
+
+    /// Gives up waiting for the response, asking the exporter to cancel the call.
+    /// `responded` tells whether the response has arrived after all.
+    fn abandon(&self, slot: CallSlot, handle: ServiceObjectId, responded: impl FnOnce(&CallSlot) -> bool) {
+        let mut abandoned_slots = self.abandoned_slots.lock();
+        // The response might have arrived just before we take the lock.
+        if responded(&slot) {
+            self.call_slots.push(slot);
+            return
+        }
+        let buffer = PacketHeader {
+            handle,
+            method: CANCEL_INDICATOR,
+            flags: FLAG_CALL,
+            slot: slot.id,
+            timeout: NO_DEADLINE,
+        }
+        .to_packet();
+        // The exporter might be already gone, and then the slot is never used again anyway.
+        slot.invoke.send(buffer).ok();
+        abandoned_slots.insert(slot.id, slot);
+    }
+}
+
+fn receiver(recv: Receiver<Vec<u8>>, response_send: Vec<Sender<Vec<u8>>>, shared: &Shared) -> Result<(), ()> {
+    let chunking = shared.chunking;
+    let mut reassembler = Reassembler::new(chunking);
     loop {
-        let data = recv.recv().map_err(|_| ())?;
-        let header = PacketHeader::new(&data);
-        response_send[header.slot as usize].send(data).unwrap();
+        let data = match reassembler.push(recv.recv().map_err(|_| ())?) {
+            Err(e) => {
+                // No call can be told of it, since the slot might not be what it reads.
+                log::warn!("Dropped an invalid response: {}", e);
+                continue
+            }
+            Ok(None) => continue,
+            Ok(Some(Ok(data))) => data,
+            Ok(Some(Err(Overflow {
+                mut header,
+                size,
+            }))) => {
+                // The caller gets an error instead.
+                header.flags |= FLAG_ERROR;
+                let mut data = vec![0; PacketHeader::SIZE];
+                let error = CallError::TooLarge {
+                    size,
+                    limit: chunking.max_message_size,
+                };
+                shared.codec.encode(&mut data, &error).unwrap();
+                header.write(&mut data);
+                data
+            }
+        };
+        let header = PacketHeader::read(&data).unwrap();
+        // Items of a stream are followed by the final response, which alone ends the call.
+        let last = header.flags & FLAG_ITEM == 0;
+        let mut abandoned_slots = shared.abandoned_slots.lock();
+        let mut stream_responses = shared.stream_responses.lock();
+        if abandoned_slots.contains_key(&header.slot) {
+            if last {
+                shared.call_slots.push(abandoned_slots.remove(&header.slot).unwrap());
+            }
+        } else if let Some(response) = shared.async_responses.lock().remove(&header.slot) {
+            // The future can't be dropped without abandoning the slot first.
+            response.send(data).unwrap();
+        } else if let Some(items) = stream_responses.get(&header.slot) {
+            // Neither can the stream.
+            items.unbounded_send(data).unwrap();
+            if last {
+                stream_responses.remove(&header.slot);
+            }
+        } else if let Some(response) = response_send.get(header.slot as usize) {
+            response.send(data).unwrap();
+        } else {
+            log::warn!("Dropped a response to the unknown slot {}", header.slot);
+        }
+    }
+}
+
+/// Converts the response to an error if the exporter reported one.
+pub(super) fn check_response(response: Vec<u8>, codec: CodecKind) -> Result<Vec<u8>, CallError> {
+    if PacketHeader::read(&response).unwrap().flags & FLAG_ERROR != 0 {
+        let error: CallError = codec.decode(&response[PacketHeader::SIZE..])?;
+        return Err(error)
+    }
+    Ok(response)
+}
+
+/// Time left until the deadline, or None if it has already passed.
+fn time_left(deadline: Instant) -> Option<Duration> {
+    let now = Instant::now();
+    if deadline > now {
+        Some(deadline - now)
+    } else {
+        None
     }
 }
 
 pub struct Client {
-    call_slots: Arc<Queue<CallSlot>>,
+    shared: Arc<Shared>,
     receiver_thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Client {
-    pub fn new(ipc_send: Sender<Vec<u8>>, ipc_recv: Receiver<Vec<u8>>, callslot_size: SlotId) -> Self {
-        let call_slots = Arc::new(Queue::new(callslot_size as usize));
+    pub fn new(
+        ipc_send: Sender<Vec<u8>>,
+        ipc_recv: Receiver<Vec<u8>>,
+        callslot_size: SlotId,
+        codec: CodecKind,
+        chunking: Chunking,
+    ) -> Self {
+        let call_slots = CallSlots {
+            queue: Queue::new(callslot_size as usize),
+            wakers: Default::default(),
+        };
         let mut response_send = Vec::new();
         for i in 0..callslot_size {
             let (send_slot, recv_slot) = bounded(1);
@@ -63,43 +283,157 @@ impl Client {
             response_send.push(send_slot);
         }
 
-        Client {
+        let shared = Arc::new(Shared {
             call_slots,
+            abandoned_slots: Default::default(),
+            async_responses: Default::default(),
+            stream_responses: Default::default(),
+            invoke: ipc_send,
+            oneway_lock: Default::default(),
+            codec,
+            chunking,
+        });
+        let shared_ = shared.clone();
+        Client {
+            shared,
             receiver_thread: Some(thread::spawn(move || {
-                receiver(ipc_recv, response_send).ok();
+                receiver(ipc_recv, response_send, &shared_).ok();
+                // Wakes up the pending futures and streams with an error.
+                shared_.async_responses.lock().clear();
+                shared_.stream_responses.lock().clear();
             })),
         }
     }
 
-    /// Caller must have reserved sizeof(PacketHeader) bytes on the first of data
-    pub fn call(&self, handle: ServiceObjectId, method: MethodId, mut data: Vec<u8>) -> Vec<u8> {
-        let slot = self.call_slots.pop(Some(TIMEOUT)).expect("Module doesn't respond");
-        let header = PacketHeader {
+    /// Caller must have reserved PacketHeader::SIZE bytes on the first of data
+    pub fn call(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        data: Vec<u8>,
+        deadline: Option<Instant>,
+        cancel: Option<&CancelToken>,
+    ) -> Result<Vec<u8>, CallError> {
+        if cancel.map_or(false, CancelToken::is_cancelled) {
+            return Err(CallError::Cancelled)
+        }
+        self.shared.check_size(&data)?;
+        let slot_timeout = match deadline {
+            Some(deadline) => std::cmp::min(TIMEOUT, time_left(deadline).ok_or(CallError::Timeout)?),
+            None => TIMEOUT,
+        };
+        let slot = self.shared.call_slots.pop(Some(slot_timeout)).map_err(|_| CallError::Timeout)?;
+        if let Err(error) = self.shared.send_call(&slot, handle, method, data, deadline) {
+            self.shared.call_slots.push(slot);
+            return Err(error)
+        }
+
+        let cancelled = cancel.map_or_else(never, CancelToken::watch);
+        let expired = deadline.map_or_else(never, |deadline| after(time_left(deadline).unwrap_or_default()));
+        let return_value = select! {
+            recv(slot.response) -> return_value => return_value.map_err(|_| CallError::PeerGone),
+            recv(cancelled) -> _ => Err(CallError::Cancelled),
+            recv(expired) -> _ => Err(CallError::Timeout),
+        };
+        match return_value {
+            Ok(return_value) => {
+                self.shared.call_slots.push(slot); //return back
+                check_response(return_value, self.shared.codec)
+            }
+            Err(CallError::PeerGone) => {
+                self.shared.call_slots.push(slot);
+                Err(CallError::PeerGone)
+            }
+            Err(error) => {
+                self.shared.abandon(slot, handle, |slot| slot.response.try_recv().is_ok());
+                Err(error)
+            }
+        }
+    }
+
+    /// Same as `call()`, but returns a future instead of blocking the thread.
+    ///
+    /// Dropping the future before it completes abandons the call, as cancellation does.
+    /// Note that the deadline is only sent to the exporter; the future itself doesn't expire.
+    pub fn call_async(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        data: Vec<u8>,
+        deadline: Option<Instant>,
+        cancel: Option<CancelToken>,
+    ) -> AsyncCall {
+        AsyncCall {
+            shared: self.shared.clone(),
             handle,
             method,
-            slot: slot.id as u32 + SLOT_CALL_OR_RETURN_INDICATOR,
-        };
-        header.write(&mut data);
-        slot.invoke.send(data).unwrap();
-        let return_value = slot.response.recv().unwrap();
-        self.call_slots.push(slot); //return back
-        return_value
+            deadline,
+            cancel,
+            waker: None,
+            state: AsyncCallState::Queued(data),
+        }
+    }
+
+    /// Calls a method that returns a stream, of which the items are received one by one.
+    ///
+    /// The exporter sends the items as far as we have consumed them within the window, and
+    /// dropping the stream before the end abandons the call. As `call_async()`, it doesn't expire by itself.
+    pub fn call_stream(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        data: Vec<u8>,
+        deadline: Option<Instant>,
+        cancel: Option<CancelToken>,
+    ) -> StreamCall {
+        StreamCall {
+            shared: self.shared.clone(),
+            handle,
+            method,
+            deadline,
+            cancel,
+            waker: None,
+            state: StreamCallState::Queued(data),
+        }
+    }
+
+    /// Sends a call that expects no response, returning as soon as it is sent.
+    ///
+    /// This doesn't wait for a free slot, and the caller never learns whether the call has succeeded.
+    /// The deadline still applies to when the exporter may start serving it.
+    pub fn call_oneway(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        data: Vec<u8>,
+        deadline: Option<Instant>,
+    ) -> Result<(), CallError> {
+        self.shared.check_size(&data)?;
+        let _lock = self.shared.oneway_lock.lock();
+        self.shared.send(&self.shared.invoke, FLAG_CALL | FLAG_ONEWAY, ONEWAY_SLOT, handle, method, data, deadline)
     }
 
     /// request to delete given handle from the registry of exporter
-    pub fn delete(&self, handle: ServiceObjectId) {
-        let slot = self.call_slots.pop(Some(TIMEOUT)).expect("Module doesn't respond");
-        let mut buffer = vec![0 as u8; std::mem::size_of::<PacketHeader>()];
-        let header = PacketHeader {
+    pub fn delete(&self, handle: ServiceObjectId) -> Result<(), CallError> {
+        let slot = self.shared.call_slots.pop(Some(TIMEOUT)).map_err(|_| CallError::Timeout)?;
+        let buffer = PacketHeader {
             handle,
             method: DELETE_INDICATOR,
-            slot: slot.id as u32 + SLOT_CALL_OR_RETURN_INDICATOR,
+            flags: FLAG_CALL,
+            slot: slot.id,
+            timeout: NO_DEADLINE,
+        }
+        .to_packet();
+        let return_value = if slot.invoke.send(buffer).is_ok() {
+            slot.response.recv().map_err(|_| CallError::PeerGone)
+        } else {
+            Err(CallError::PeerGone)
         };
-        header.write(&mut buffer);
-        slot.invoke.send(buffer).unwrap();
-        let return_value = slot.response.recv().unwrap();
-        assert_eq!(PacketHeader::new(&return_value).method, DELETE_INDICATOR);
-        self.call_slots.push(slot) //return back
+        self.shared.call_slots.push(slot); //return back
+        if PacketHeader::read(&check_response(return_value?, self.shared.codec)?).unwrap().method != DELETE_INDICATOR {
+            return Err(CallError::Decode("Unexpected response to a delete".to_owned()))
+        }
+        Ok(())
     }
 }
 
@@ -108,3 +442,219 @@ impl Drop for Client {
         self.receiver_thread.take().unwrap().join().unwrap();
     }
 }
+
+enum AsyncCallState {
+    /// Waiting for a free slot
+    Queued(Vec<u8>),
+    /// Waiting for the response
+    Sent(CallSlot, oneshot::Receiver<Vec<u8>>),
+    Done,
+}
+
+/// A call in progress made by `Client::call_async()`, which resolves to the response.
+pub struct AsyncCall {
+    shared: Arc<Shared>,
+    handle: ServiceObjectId,
+    method: MethodId,
+    deadline: Option<Instant>,
+    cancel: Option<CancelToken>,
+    /// Registered to the cancel token
+    waker: Option<Waker>,
+    state: AsyncCallState,
+}
+
+impl AsyncCall {
+    fn finish(&mut self, result: Result<Vec<u8>, CallError>) -> Poll<Result<Vec<u8>, CallError>> {
+        if let AsyncCallState::Sent(slot, _) = std::mem::replace(&mut self.state, AsyncCallState::Done) {
+            // The receiver has taken the entry if the response has arrived, and then the slot is simply returned.
+            let shared = &self.shared;
+            shared.abandon(slot, self.handle, |slot| shared.async_responses.lock().remove(&slot.id).is_none());
+        }
+        if let (Some(cancel), Some(waker)) = (&self.cancel, self.waker.take()) {
+            cancel.forget(&waker);
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl Future for AsyncCall {
+    type Output = Result<Vec<u8>, CallError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if let Some(cancel) = &this.cancel {
+            let cancelled = cancel.poll_cancelled(cx);
+            this.waker = Some(cx.waker().clone());
+            if cancelled.is_ready() {
+                return this.finish(Err(CallError::Cancelled))
+            }
+        }
+        if let AsyncCallState::Queued(data) = &this.state {
+            if let Err(error) = this.shared.check_size(data) {
+                this.state = AsyncCallState::Done;
+                return this.finish(Err(error))
+            }
+            let slot = match this.shared.call_slots.poll_pop(cx) {
+                Poll::Ready(slot) => slot,
+                Poll::Pending => return Poll::Pending,
+            };
+            let data = match std::mem::replace(&mut this.state, AsyncCallState::Done) {
+                AsyncCallState::Queued(data) => data,
+                _ => unreachable!(),
+            };
+            // This must precede sending, since the response may arrive right after.
+            let (send, recv) = oneshot::channel();
+            this.shared.async_responses.lock().insert(slot.id, send);
+            if let Err(error) = this.shared.send_call(&slot, this.handle, this.method, data, this.deadline) {
+                this.shared.async_responses.lock().remove(&slot.id);
+                this.shared.call_slots.push(slot);
+                return this.finish(Err(error))
+            }
+            this.state = AsyncCallState::Sent(slot, recv);
+        }
+        let result = match &mut this.state {
+            AsyncCallState::Sent(_, response) => match Pin::new(response).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(response)) => check_response(response, this.shared.codec),
+                Poll::Ready(Err(oneshot::Canceled)) => Err(CallError::PeerGone),
+            },
+            _ => panic!("AsyncCall polled after completion"),
+        };
+        this.finish(result)
+    }
+}
+
+impl Drop for AsyncCall {
+    fn drop(&mut self) {
+        // Nothing to do if it has completed.
+        let _ = self.finish(Err(CallError::Cancelled));
+    }
+}
+
+enum StreamCallState {
+    /// Waiting for a free slot
+    Queued(Vec<u8>),
+    /// Receiving the items. `consumed` counts those not credited back to the exporter yet.
+    Sent {
+        slot: CallSlot,
+        items: mpsc::UnboundedReceiver<Vec<u8>>,
+        consumed: u32,
+    },
+    Done,
+}
+
+/// A call in progress made by `Client::call_stream()`, which yields the items and ends with the final response.
+pub struct StreamCall {
+    shared: Arc<Shared>,
+    handle: ServiceObjectId,
+    method: MethodId,
+    deadline: Option<Instant>,
+    cancel: Option<CancelToken>,
+    /// Registered to the cancel token
+    waker: Option<Waker>,
+    state: StreamCallState,
+}
+
+impl StreamCall {
+    fn finish(&mut self) {
+        if let StreamCallState::Sent {
+            slot,
+            ..
+        } = std::mem::replace(&mut self.state, StreamCallState::Done)
+        {
+            // The receiver has taken the entry if the final response has arrived, and then the slot is simply returned.
+            let shared = &self.shared;
+            shared.abandon(slot, self.handle, |slot| shared.stream_responses.lock().remove(&slot.id).is_none());
+        }
+        if let (Some(cancel), Some(waker)) = (&self.cancel, self.waker.take()) {
+            cancel.forget(&waker);
+        }
+    }
+
+    fn fail(&mut self, error: CallError) -> Poll<Option<Result<Vec<u8>, CallError>>> {
+        self.finish();
+        Poll::Ready(Some(Err(error)))
+    }
+}
+
+impl futures::Stream for StreamCall {
+    type Item = Result<Vec<u8>, CallError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let StreamCallState::Done = this.state {
+            return Poll::Ready(None)
+        }
+        if let Some(cancel) = &this.cancel {
+            let cancelled = cancel.poll_cancelled(cx);
+            this.waker = Some(cx.waker().clone());
+            if cancelled.is_ready() {
+                return this.fail(CallError::Cancelled)
+            }
+        }
+        if let StreamCallState::Queued(data) = &this.state {
+            if let Err(error) = this.shared.check_size(data) {
+                return this.fail(error)
+            }
+            let slot = match this.shared.call_slots.poll_pop(cx) {
+                Poll::Ready(slot) => slot,
+                Poll::Pending => return Poll::Pending,
+            };
+            let data = match std::mem::replace(&mut this.state, StreamCallState::Done) {
+                StreamCallState::Queued(data) => data,
+                _ => unreachable!(),
+            };
+            // This must precede sending, since the items may arrive right after.
+            let (send, recv) = mpsc::unbounded();
+            this.shared.stream_responses.lock().insert(slot.id, send);
+            if let Err(error) = this.shared.send_call(&slot, this.handle, this.method, data, this.deadline) {
+                this.shared.stream_responses.lock().remove(&slot.id);
+                this.shared.call_slots.push(slot);
+                return this.fail(error)
+            }
+            this.state = StreamCallState::Sent {
+                slot,
+                items: recv,
+                consumed: 0,
+            };
+        }
+        let (slot, items, consumed) = match &mut this.state {
+            StreamCallState::Sent {
+                slot,
+                items,
+                consumed,
+            } => (slot, items, consumed),
+            _ => unreachable!(),
+        };
+        let response = match Pin::new(items).poll_next(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Some(response)) => response,
+            Poll::Ready(None) => return this.fail(CallError::PeerGone),
+        };
+        if PacketHeader::read(&response).unwrap().flags & FLAG_ITEM != 0 {
+            *consumed += 1;
+            // Credits are given in bulk, so that the exporter doesn't stall as long as we keep up.
+            if *consumed >= STREAM_WINDOW / 2 {
+                let mut credit = vec![0; PacketHeader::SIZE];
+                credit.extend_from_slice(&consumed.to_le_bytes());
+                // If the peer is gone, the next poll will find it.
+                this.shared.send(&slot.invoke, FLAG_CALL, slot.id, this.handle, CREDIT_INDICATOR, credit, None).ok();
+                *consumed = 0;
+            }
+            // An item can fail by itself if it was too large for us, while the rest may still come.
+            return Poll::Ready(Some(check_response(response, this.shared.codec)))
+        }
+        this.finish();
+        match check_response(response, this.shared.codec) {
+            Ok(_) => Poll::Ready(None),
+            Err(error) => Poll::Ready(Some(Err(error))),
+        }
+    }
+}
+
+impl Drop for StreamCall {
+    fn drop(&mut self) {
+        // Nothing to do if it has ended.
+        self.finish();
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::SlotId;
+use crate::service::{MethodId, ServiceObjectId};
+use std::convert::TryInto;
+
+// Layout of the header, all in little endian.
+//
+// 0..3   magic
+// 3      protocol version
+// 4      flags
+// 5..8   (reserved)
+// 8..12  slot
+// 12..16 method
+// 16..20 handle index
+// 20..24 length of the payload following the header
+// 24..32 timeout
+// 32..36 handle generation
+// 36..40 (reserved)
+// 40..48 handle nonce
+
+const MAGIC: [u8; 3] = *b"FML";
+/// Bump this whenever the header or the meaning of a packet changes.
+pub const PROTOCOL_VERSION: u8 = 7;
+
+/// The packet is a call from the peer's client, rather than a response to ours.
+pub const FLAG_CALL: u8 = 0b001;
+/// The call failed in the exporter, and the payload is a serialized CallError.
+pub const FLAG_ERROR: u8 = 0b010;
+/// The first packet sent on a link. Its payload is a `Hello` in CBOR, with the codecs and the method signatures of the sender.
+pub const FLAG_HELLO: u8 = 0b100;
+/// The packet is a fragment of a message, which continues in the next one.
+pub const FLAG_MORE: u8 = 0b1000;
+/// The call expects no response, and it doesn't occupy a slot of the caller.
+pub const FLAG_ONEWAY: u8 = 0b1_0000;
+/// The response is an item of a stream, which is followed by more responses.
+pub const FLAG_ITEM: u8 = 0b10_0000;
+
+/// A packet that this end can't understand.
+#[derive(PartialEq, Debug, Clone)]
+pub enum ProtocolError {
+    /// The packet is shorter than the header.
+    TooShort(usize),
+    /// The packet doesn't start with the magic, so it is not from FML at all.
+    BadMagic,
+    /// The peer speaks another version of the protocol.
+    VersionMismatch {
+        ours: u8,
+        theirs: u8,
+    },
+    /// The length in the header doesn't match the actual payload.
+    LengthMismatch {
+        header: u32,
+        actual: usize,
+    },
+    /// The peer didn't start the link with a valid hello packet.
+    NoHello,
+    /// The peer supports none of the codecs we can use.
+    NoCommonCodec,
+    /// These methods are known to both ends, but with different signatures.
+    SignatureMismatch(Vec<String>),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProtocolError::TooShort(size) => write!(f, "Packet of {} bytes is shorter than the header", size),
+            ProtocolError::BadMagic => write!(f, "Packet is not from FML"),
+            ProtocolError::VersionMismatch {
+                ours,
+                theirs,
+            } => write!(f, "Protocol version mismatch: ours is {}, but the peer's is {}", ours, theirs),
+            ProtocolError::LengthMismatch {
+                header,
+                actual,
+            } => write!(f, "Header says the payload is {} bytes, but it is {} bytes", header, actual),
+            ProtocolError::NoHello => write!(f, "Peer didn't say hello"),
+            ProtocolError::NoCommonCodec => write!(f, "No codec is supported by both ends"),
+            ProtocolError::SignatureMismatch(methods) => {
+                write!(f, "Signatures of these methods differ from the peer's: {}", methods.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct PacketHeader {
+    pub flags: u8,
+    pub slot: SlotId,
+    pub handle: ServiceObjectId,
+    pub method: MethodId,
+    /// Time left until the deadline of the call in microseconds, measured when it is sent.
+    pub timeout: u64,
+}
+
+impl PacketHeader {
+    /// Size of the encoded header, which precedes the payload in every packet.
+    pub const SIZE: usize = 48;
+
+    /// Decodes the header of the given packet, checking that it is consistent with the packet.
+    pub fn read(buffer: &[u8]) -> Result<Self, ProtocolError> {
+        if buffer.len() < Self::SIZE {
+            return Err(ProtocolError::TooShort(buffer.len()))
+        }
+        if buffer[0..3] != MAGIC {
+            return Err(ProtocolError::BadMagic)
+        }
+        if buffer[3] != PROTOCOL_VERSION {
+            return Err(ProtocolError::VersionMismatch {
+                ours: PROTOCOL_VERSION,
+                theirs: buffer[3],
+            })
+        }
+        let length = u32::from_le_bytes(buffer[20..24].try_into().unwrap());
+        if length as usize != buffer.len() - Self::SIZE {
+            return Err(ProtocolError::LengthMismatch {
+                header: length,
+                actual: buffer.len() - Self::SIZE,
+            })
+        }
+        Ok(PacketHeader {
+            flags: buffer[4],
+            slot: u32::from_le_bytes(buffer[8..12].try_into().unwrap()),
+            method: u32::from_le_bytes(buffer[12..16].try_into().unwrap()),
+            handle: ServiceObjectId {
+                index: u32::from_le_bytes(buffer[16..20].try_into().unwrap()),
+                generation: u32::from_le_bytes(buffer[32..36].try_into().unwrap()),
+                nonce: u64::from_le_bytes(buffer[40..48].try_into().unwrap()),
+            },
+            timeout: u64::from_le_bytes(buffer[24..32].try_into().unwrap()),
+        })
+    }
+
+    /// Reads just the slot of a packet that `read()` rejected, as long as it is from FML at all.
+    pub fn read_slot(buffer: &[u8]) -> Option<SlotId> {
+        if buffer.len() < Self::SIZE || buffer[0..3] != MAGIC {
+            return None
+        }
+        Some(u32::from_le_bytes(buffer[8..12].try_into().unwrap()))
+    }
+
+    /// Encodes the header on the first SIZE bytes of the packet.
+    /// The payload must have been written already, since its length goes into the header.
+    pub fn write(&self, buffer: &mut [u8]) {
+        assert!(buffer.len() >= Self::SIZE, "No room for the header");
+        let length = (buffer.len() - Self::SIZE) as u32;
+        buffer[0..3].copy_from_slice(&MAGIC);
+        buffer[3] = PROTOCOL_VERSION;
+        buffer[4] = self.flags;
+        buffer[5..8].copy_from_slice(&[0; 3]);
+        buffer[8..12].copy_from_slice(&self.slot.to_le_bytes());
+        buffer[12..16].copy_from_slice(&self.method.to_le_bytes());
+        buffer[16..20].copy_from_slice(&self.handle.index.to_le_bytes());
+        buffer[20..24].copy_from_slice(&length.to_le_bytes());
+        buffer[24..32].copy_from_slice(&self.timeout.to_le_bytes());
+        buffer[32..36].copy_from_slice(&self.handle.generation.to_le_bytes());
+        buffer[36..40].copy_from_slice(&[0; 4]);
+        buffer[40..48].copy_from_slice(&self.handle.nonce.to_le_bytes());
+    }
+
+    /// Returns a new packet that has only the header.
+    pub fn to_packet(&self) -> Vec<u8> {
+        let mut buffer = vec![0; Self::SIZE];
+        self.write(&mut buffer);
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> PacketHeader {
+        PacketHeader {
+            flags: FLAG_CALL,
+            slot: 0x1234,
+            handle: ServiceObjectId {
+                index: 0x8888,
+                generation: 0x99,
+                nonce: 0xaabb_ccdd,
+            },
+            method: 0x5678,
+            timeout: 0x1122_3344_5566_7788,
+        }
+    }
+
+    #[test]
+    fn encoding_packet_header() {
+        let mut buffer = vec![0xff; PacketHeader::SIZE + 3];
+        header().write(&mut buffer);
+        assert_eq!(PacketHeader::read(&buffer), Ok(header()));
+        // Explicitly little endian, regardless of the platform
+        assert_eq!(&buffer[0..12], b"FML\x07\x01\x00\x00\x00\x34\x12\x00\x00");
+        assert_eq!(&buffer[20..24], &[3, 0, 0, 0]);
+    }
+
+    #[test]
+    fn invalid_packet_header() {
+        let mut buffer = header().to_packet();
+        assert_eq!(PacketHeader::read(&buffer[..10]), Err(ProtocolError::TooShort(10)));
+
+        buffer.push(0);
+        assert_eq!(
+            PacketHeader::read(&buffer),
+            Err(ProtocolError::LengthMismatch {
+                header: 0,
+                actual: 1,
+            })
+        );
+
+        buffer[3] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            PacketHeader::read(&buffer),
+            Err(ProtocolError::VersionMismatch {
+                ours: PROTOCOL_VERSION,
+                theirs: PROTOCOL_VERSION + 1,
+            })
+        );
+
+        buffer[0] = b'X';
+        assert_eq!(PacketHeader::read(&buffer), Err(ProtocolError::BadMagic));
+    }
+}
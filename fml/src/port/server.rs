@@ -14,16 +14,36 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use super::batch;
+use super::chunk::{Chunking, Overflow, Reassembler};
 use super::PacketHeader;
 use super::PortId;
-use super::{DELETE_INDICATOR, SLOT_CALL_OR_RETURN_INDICATOR};
+use super::SlotId;
+use super::{BATCH_INDICATOR, CANCEL_INDICATOR, CREDIT_INDICATOR, DELETE_INDICATOR, LOOKUP_INDICATOR, QUERY_INDICATOR};
+use super::{FLAG_CALL, FLAG_ERROR, FLAG_ITEM, FLAG_ONEWAY};
+use super::{NO_DEADLINE, STREAM_WINDOW};
+use crate::cancel::{self, CancelToken};
+use crate::codec::{self, Codec, CodecKind};
 use crate::context::single_process_support;
+use crate::deadline;
 use crate::queue::Queue;
-use crate::service::{dispatch::delete, PortDispatcher, UNDECIDED_PORT};
-use crossbeam::channel::{bounded, Receiver, Sender};
+use crate::service::dispatch::{delete, query, PendingReturn, PendingStream};
+use crate::service::naming::export_named;
+use crate::service::{CallError, PortDispatcher, UNDECIDED_PORT};
+use crossbeam::channel::{after, bounded, never, select, unbounded, Receiver, Sender};
+use futures::executor::ThreadPool;
+use futures::FutureExt;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::future::Future;
 use std::io::Cursor;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
 use std::thread;
+use std::time::{Duration, Instant};
 
 /// This manages thread-local keys for port, which will be used in serialization of
 /// SArc. Note that this is required even in the inter-process setup.
@@ -46,6 +66,23 @@ pub mod port_thread_local {
             k.get()
         })
     }
+
+    /// Restores the previous key even if the given closure panics.
+    struct Restore(crate::port::PortId);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            INSTANCE_KEY.with(|k| k.set(self.0))
+        }
+    }
+
+    /// Runs `f` with the given key, replacing the one set for the thread, if any.
+    /// The arguments of a call are serialized within this, since they go to the port of the call,
+    /// not the one where the current call has come from.
+    pub fn with_key<T, F: FnOnce() -> T>(key: crate::port::PortId, f: F) -> T {
+        let _restore = Restore(INSTANCE_KEY.with(|k| k.replace(key)));
+        f()
+    }
 }
 
 #[cfg(debug_assertions)]
@@ -53,10 +90,202 @@ const TIMEOUT: std::time::Duration = std::time::Duration::from_millis(1_000_000)
 #[cfg(not(debug_assertions))]
 const TIMEOUT: std::time::Duration = std::time::Duration::from_millis(50);
 
+/// Recovers the message given to panic!(), which is usually either &str or String.
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown panic".to_owned()
+    }
+}
+
+/// An inbound call, handed from the receiver to a service handler.
+struct Invocation {
+    /// The deadline of the call is measured from here, not from when a handler becomes available.
+    received: Instant,
+    cancel: CancelToken,
+    data: Vec<u8>,
+}
+
+/// A call being served, which the receiver has to reach on the caller's request.
+struct RunningCall {
+    cancel: CancelToken,
+    /// Set while the call is sending a stream
+    credits: Option<Sender<u32>>,
+}
+
+/// Calls being served, so that the receiver can cancel them or give credits to their streams.
+type RunningCalls = Mutex<HashMap<SlotId, RunningCall>>;
+
+/// Makes a response that reports the error instead of the result.
+fn error_packet(mut header: PacketHeader, error: &CallError) -> Vec<u8> {
+    header.flags = (header.flags & !FLAG_CALL) | FLAG_ERROR;
+    let mut buffer = vec![0; PacketHeader::SIZE];
+    codec::current().encode(&mut buffer, error).unwrap();
+    header.write(&mut buffer);
+    buffer
+}
+
+/// Sends the result of the call back to the caller, unless it is one-way.
+fn respond(
+    mut header: PacketHeader,
+    result: Result<Vec<u8>, CallError>,
+    response: &Sender<Vec<u8>>,
+    running_calls: &RunningCalls,
+    chunking: Chunking,
+) {
+    // Nobody waits for it, so even an error is dropped.
+    if header.flags & FLAG_ONEWAY != 0 {
+        return
+    }
+    header.flags &= !FLAG_CALL;
+    let slot = header.slot;
+    let result = match result {
+        Ok(buffer) if Chunking::payload_size(&buffer) > chunking.max_message_size => Err(CallError::TooLarge {
+            size: Chunking::payload_size(&buffer),
+            limit: chunking.max_message_size,
+        }),
+        result => result,
+    };
+    let buffer = match result {
+        Ok(mut buffer) => {
+            header.write(&mut buffer);
+            buffer
+        }
+        // Discard whatever has been partially written, and report the error instead.
+        Err(error) => error_packet(header, &error),
+    };
+    // This must precede the response, after which the caller may reuse the slot.
+    running_calls.lock().remove(&slot);
+    chunking.send(response, buffer).unwrap();
+}
+
+/// Polls an async method under the deadline and the cancellation of the call,
+/// as the service handler does for the others.
+struct InCall {
+    deadline: Option<Instant>,
+    cancel: CancelToken,
+    pending: PendingReturn,
+}
+
+impl Future for InCall {
+    type Output = Result<Vec<u8>, CallError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let InCall {
+            deadline,
+            cancel,
+            pending,
+        } = &mut *self;
+        deadline::with(*deadline, || cancel::with_token(cancel, || pending.as_mut().poll(cx)))
+    }
+}
+
+/// Serves the calls in a batch one by one, collecting their results.
+/// Async methods are simply blocked on here.
+fn serve_batch(dispatcher: &PortDispatcher, data: &[u8]) -> Result<Vec<u8>, CallError> {
+    let results: Vec<Vec<u8>> = batch::unpack(data)?
+        .into_iter()
+        .map(|call| {
+            let mut header = PacketHeader::read(call).unwrap();
+            header.flags &= !FLAG_CALL;
+            let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+            // The rest of the batch is skipped once the whole call is cancelled or expired.
+            let result = if cancel::is_cancelled() {
+                Err(CallError::Cancelled)
+            } else if deadline::get().map_or(false, |deadline| deadline <= Instant::now()) {
+                Err(CallError::Timeout)
+            } else {
+                // A panic fails only that call.
+                catch_unwind(AssertUnwindSafe(|| {
+                    dispatcher.dispatch(header.handle, header.method, call, {
+                        let mut c = Cursor::new(&mut buffer);
+                        c.set_position(PacketHeader::SIZE as u64);
+                        c
+                    })
+                }))
+                .unwrap_or_else(|payload| Err(CallError::RemotePanic(panic_message(payload))))
+            };
+            match result {
+                Ok(()) => {
+                    header.write(&mut buffer);
+                    buffer
+                }
+                Err(error) => error_packet(header, &error),
+            }
+        })
+        .collect();
+    Ok(batch::pack(results.iter().map(Vec::as_slice)))
+}
+
+/// Sends the items of a stream one by one, as far as the caller has given credits for.
+/// The final response, which ends the stream, is left to the caller of this.
+fn serve_stream(
+    header: &PacketHeader,
+    items: PendingStream,
+    response: &Sender<Vec<u8>>,
+    running_calls: &RunningCalls,
+    chunking: Chunking,
+) -> Result<(), CallError> {
+    let (credit_send, credits) = unbounded();
+    running_calls.lock().get_mut(&header.slot).expect("Stream is not a running call").credits = Some(credit_send);
+    let mut item_header = header.clone();
+    item_header.flags = (header.flags & !FLAG_CALL) | FLAG_ITEM;
+
+    // This runs in the scope of the call, so the deadline and the token here are the call's.
+    let cancelled = cancel::current().map_or_else(never, |cancel| cancel.watch());
+    let expired =
+        deadline::get().map_or_else(never, |deadline| after(deadline.saturating_duration_since(Instant::now())));
+    let mut window = STREAM_WINDOW;
+    for item in items {
+        let mut buffer = item?;
+        let size = Chunking::payload_size(&buffer);
+        if size > chunking.max_message_size {
+            return Err(CallError::TooLarge {
+                size,
+                limit: chunking.max_message_size,
+            })
+        }
+        while window == 0 {
+            select! {
+                recv(credits) -> credit => match credit {
+                    Ok(credit) => window = window.saturating_add(credit),
+                    // Another call has taken the slot, so the caller has given up this one.
+                    Err(_) => return Err(CallError::Cancelled),
+                },
+                recv(cancelled) -> _ => return Err(CallError::Cancelled),
+                recv(expired) -> _ => return Err(CallError::Timeout),
+            }
+        }
+        if cancel::is_cancelled() {
+            return Err(CallError::Cancelled)
+        } else if deadline::get().map_or(false, |deadline| deadline <= Instant::now()) {
+            return Err(CallError::Timeout)
+        }
+        window -= 1;
+        item_header.write(&mut buffer);
+        chunking.send(response, buffer).unwrap();
+    }
+    Ok(())
+}
+
+/// What the service handler has done with a call
+enum Served {
+    Done(Vec<u8>),
+    /// The method is async, and the executor will finish it.
+    Pending(PendingReturn),
+}
+
+#[allow(clippy::too_many_arguments)]
 fn service_handler(
-    invoke: Receiver<Vec<u8>>,
+    invoke: Receiver<Invocation>,
     response: Sender<Vec<u8>>,
     dispatcher: Arc<PortDispatcher>,
+    running_calls: Arc<RunningCalls>,
+    executor: ThreadPool,
+    chunking: Chunking,
     instance_key: single_process_support::InstanceKey,
     port_id: PortId,
     token: u32,
@@ -68,63 +297,238 @@ fn service_handler(
     // This is for service object serialization
     port_thread_local::set_key(port_id);
     loop {
-        let data = invoke.recv().map_err(|_| ())?;
-        if data.len() < std::mem::size_of::<PacketHeader>() {
-            panic!("Invalid packet received: {:?}", data);
-        }
-        let mut header = PacketHeader::new(&data);
-        header.slot -= SLOT_CALL_OR_RETURN_INDICATOR;
-        let mut buffer: Vec<u8> = vec![0; std::mem::size_of::<PacketHeader>()];
+        let Invocation {
+            received,
+            cancel,
+            data,
+        } = invoke.recv().map_err(|_| ())?;
+        let header = PacketHeader::read(&data).unwrap();
+        let deadline = if header.timeout == NO_DEADLINE {
+            None
+        } else {
+            Some(received + Duration::from_micros(header.timeout))
+        };
 
-        if header.method == DELETE_INDICATOR {
-            delete(dispatcher.get_id(), header.handle);
+        let result = if deadline.map_or(false, |deadline| deadline <= Instant::now()) {
+            // The caller has already given up, so we don't bother to serve it.
+            Err(CallError::Timeout)
+        } else if cancel.is_cancelled() {
+            Err(CallError::Cancelled)
         } else {
-            dispatcher.dispatch(header.handle, header.method, &data, {
-                let mut c = Cursor::new(&mut buffer);
-                c.set_position(std::mem::size_of::<PacketHeader>() as u64);
-                c
-            });
+            // A panic in the service must not kill this thread; the caller is still waiting for the response,
+            // and this handler's token must be returned.
+            catch_unwind(AssertUnwindSafe(|| {
+                let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+                if header.method == DELETE_INDICATOR {
+                    delete(dispatcher.get_id(), header.handle)?;
+                    return Ok(Served::Done(buffer))
+                }
+                if header.method == QUERY_INDICATOR {
+                    let (trait_id,) = codec::decode(&data[PacketHeader::SIZE..])?;
+                    let exported = query(dispatcher.get_id(), header.handle, trait_id)?;
+                    codec::encode(&mut buffer, &exported)?;
+                    return Ok(Served::Done(buffer))
+                }
+                if header.method == LOOKUP_INDICATOR {
+                    let (name, trait_id): (String, _) = codec::decode(&data[PacketHeader::SIZE..])?;
+                    let exported = export_named(dispatcher.get_id(), &name, trait_id);
+                    codec::encode(&mut buffer, &exported)?;
+                    return Ok(Served::Done(buffer))
+                }
+                // Nested calls made by the service inherit the deadline and the cancellation.
+                deadline::with(deadline, || {
+                    cancel::with_token(&cancel, || {
+                        if header.method == BATCH_INDICATOR {
+                            return serve_batch(&dispatcher, &data).map(Served::Done)
+                        }
+                        if let Some(items) = dispatcher.dispatch_stream(header.handle, header.method, &data)? {
+                            serve_stream(&header, items, &response, &running_calls, chunking)?;
+                            return Ok(Served::Done(buffer))
+                        }
+                        if let Some(pending) = dispatcher.dispatch_async(header.handle, header.method, &data)? {
+                            return Ok(Served::Pending(pending))
+                        }
+                        dispatcher.dispatch(header.handle, header.method, &data, {
+                            let mut c = Cursor::new(&mut buffer);
+                            c.set_position(PacketHeader::SIZE as u64);
+                            c
+                        })?;
+                        Ok(Served::Done(buffer))
+                    })
+                })
+            }))
+            .unwrap_or_else(|payload| Err(CallError::RemotePanic(panic_message(payload))))
+        };
+
+        match result {
+            Ok(Served::Pending(pending)) => {
+                let response = response.clone();
+                let running_calls = running_calls.clone();
+                let call = InCall {
+                    deadline,
+                    cancel,
+                    pending,
+                };
+                executor.spawn_ok(async move {
+                    let result = AssertUnwindSafe(call)
+                        .catch_unwind()
+                        .await
+                        .unwrap_or_else(|payload| Err(CallError::RemotePanic(panic_message(payload))));
+                    respond(header, result, &response, &running_calls, chunking);
+                });
+            }
+            Ok(Served::Done(buffer)) => respond(header, Ok(buffer), &response, &running_calls, chunking),
+            Err(error) => respond(header, Err(error), &response, &running_calls, chunking),
         }
-        header.write(&mut buffer);
-        response.send(buffer).unwrap();
         token_queue.push(token);
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn receiver(
     ipc_send: Sender<Vec<u8>>,
     ipc_recv: Receiver<Vec<u8>>,
     dispatcher: Arc<PortDispatcher>,
     instance_key: single_process_support::InstanceKey,
     port_id: PortId,
+    codec: CodecKind,
+    chunking: Chunking,
     max_threads: usize,
     channel_capcity: usize,
 ) {
     // Handling service with threads is just receiver()'s implementation detail.
     // So all these thread management stuffs belong here, not the Server.
-    let mut invocation_send: Vec<Sender<Vec<u8>>> = Vec::new();
+    let mut invocation_send: Vec<Sender<Invocation>> = Vec::new();
     let mut service_handlers: Vec<thread::JoinHandle<()>> = Vec::new();
     let token_queue = Arc::new(Queue::<u32>::new(max_threads));
+    let running_calls: Arc<RunningCalls> = Default::default();
+    // Async methods are polled here, with the same thread-local setup as the service handlers.
+    let executor = ThreadPool::builder()
+        .pool_size(max_threads)
+        .name_prefix(format!("fml-executor-{}-", port_id))
+        .after_start(move |_| {
+            single_process_support::set_key(instance_key);
+            port_thread_local::set_key(port_id);
+            codec::set_current(codec);
+        })
+        .create()
+        .expect("Failed to create the executor");
 
     for i in 0..max_threads {
         let (send, recv) = bounded(channel_capcity);
         invocation_send.push(send);
         let dispatcher_ = dispatcher.clone();
         let ipc_send_ = ipc_send.clone();
+        let running_calls_ = running_calls.clone();
+        let executor_ = executor.clone();
         let token_queue_ = token_queue.clone();
         service_handlers.push(thread::spawn(move || {
-            service_handler(recv, ipc_send_, dispatcher_, instance_key, port_id, i as u32, token_queue_).ok();
+            // The generated dispatch code will use this.
+            codec::set_current(codec);
+            service_handler(
+                recv,
+                ipc_send_,
+                dispatcher_,
+                running_calls_,
+                executor_,
+                chunking,
+                instance_key,
+                port_id,
+                i as u32,
+                token_queue_,
+            )
+            .ok();
         }));
         token_queue.push(i as u32);
     }
 
-    while let Ok(data) = ipc_recv.recv() {
-        invocation_send[token_queue.pop(Some(TIMEOUT)).expect("Servcie handler unavailiable") as usize]
-            .send(data)
+    // Calls wait for a free handler in another thread, so that the receiver goes on reading the cancels and
+    // the credits meanwhile. A stream waiting for credits holds its handler, which the credits free up.
+    let (call_send, call_recv) = unbounded::<Invocation>();
+    let distributor = {
+        let ipc_send = ipc_send.clone();
+        let running_calls = running_calls.clone();
+        thread::spawn(move || {
+            // For the errors sent from here
+            codec::set_current(codec);
+            for invocation in call_recv {
+                match token_queue.pop(Some(TIMEOUT)) {
+                    Ok(token) => invocation_send[token as usize].send(invocation).unwrap(),
+                    Err(()) => {
+                        let header = PacketHeader::read(&invocation.data).unwrap();
+                        respond(header, Err(CallError::Busy), &ipc_send, &running_calls, chunking);
+                    }
+                }
+            }
+        })
+    };
+
+    // For the errors sent from here
+    codec::set_current(codec);
+    let mut reassembler = Reassembler::new(chunking);
+    while let Ok(packet) = ipc_recv.recv() {
+        let data = match reassembler.push(packet) {
+            Err(e) => {
+                log::warn!("Dropped an invalid call: {}", e);
+                continue
+            }
+            Ok(None) => continue,
+            Ok(Some(Ok(data))) => data,
+            Ok(Some(Err(Overflow {
+                header,
+                size,
+            }))) => {
+                if header.flags & FLAG_ONEWAY == 0 {
+                    let error = CallError::TooLarge {
+                        size,
+                        limit: chunking.max_message_size,
+                    };
+                    ipc_send.send(error_packet(header, &error)).unwrap();
+                }
+                continue
+            }
+        };
+        // The deadline is measured from when the whole message has arrived.
+        let received = Instant::now();
+        let header = PacketHeader::read(&data).unwrap();
+        let slot = header.slot;
+        if header.method == CANCEL_INDICATOR {
+            // The call might have finished already.
+            if let Some(call) = running_calls.lock().get(&slot) {
+                call.cancel.cancel();
+            }
+            continue
+        }
+        if header.method == CREDIT_INDICATOR {
+            // The stream might have ended already.
+            if let Some(credits) = running_calls.lock().get(&slot).and_then(|call| call.credits.as_ref()) {
+                if let Some(credit) = data.get(PacketHeader::SIZE..).and_then(|credit| credit.try_into().ok()) {
+                    // The stream may have just ended, not yet removed from the running calls.
+                    credits.send(u32::from_le_bytes(credit)).ok();
+                }
+            }
+            continue
+        }
+        let cancel = CancelToken::new();
+        // One-way calls can't be cancelled, and they all share the same slot number.
+        if header.flags & FLAG_ONEWAY == 0 {
+            running_calls.lock().insert(slot, RunningCall {
+                cancel: cancel.clone(),
+                credits: None,
+            });
+        }
+        call_send
+            .send(Invocation {
+                received,
+                cancel,
+                data,
+            })
             .unwrap();
     }
 
-    drop(invocation_send);
+    // The handlers stop once the distributor has handed over all the calls and dropped their channels.
+    drop(call_send);
+    distributor.join().unwrap();
     while let Some(x) = service_handlers.pop() {
         x.join().unwrap();
     }
@@ -135,18 +539,31 @@ pub struct Server {
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         dispatcher: Arc<PortDispatcher>,
         ipc_send: Sender<Vec<u8>>,
         ipc_recv: Receiver<Vec<u8>>,
         instance_key: single_process_support::InstanceKey,
         port_id: PortId,
+        codec: CodecKind,
+        chunking: Chunking,
         max_threads: usize,
         channel_capcity: usize,
     ) -> Self {
         Server {
             receiver_thread: Some(thread::spawn(move || {
-                receiver(ipc_send, ipc_recv, dispatcher, instance_key, port_id, max_threads, channel_capcity)
+                receiver(
+                    ipc_send,
+                    ipc_recv,
+                    dispatcher,
+                    instance_key,
+                    port_id,
+                    codec,
+                    chunking,
+                    max_threads,
+                    channel_capcity,
+                )
             })),
         }
     }
@@ -15,33 +15,36 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crossbeam::channel::{bounded, Receiver, Sender};
-use parking_lot::Mutex;
-use std::sync::Arc;
 
 /// Blocking concurrent Queue. (Crossbeam's queue doens't block)
 pub struct Queue<T> {
-    sender: Arc<Mutex<Sender<T>>>,
-    recver: Arc<Mutex<Receiver<T>>>,
+    sender: Sender<T>,
+    recver: Receiver<T>,
 }
 
 impl<T> Queue<T> {
     pub fn new(size: usize) -> Self {
         let (sender, recver) = bounded(size);
         Queue {
-            sender: Arc::new(Mutex::new(sender)),
-            recver: Arc::new(Mutex::new(recver)),
+            sender,
+            recver,
         }
     }
 
     pub fn push(&self, x: T) {
-        self.sender.lock().send(x).unwrap();
+        self.sender.send(x).unwrap();
     }
 
     pub fn pop(&self, timeout: Option<std::time::Duration>) -> Result<T, ()> {
         if let Some(duration) = timeout {
-            self.recver.lock().recv_timeout(duration).map_err(|_| ())
+            self.recver.recv_timeout(duration).map_err(|_| ())
         } else {
-            self.recver.lock().recv().map_err(|_| ())
+            self.recver.recv().map_err(|_| ())
         }
     }
+
+    /// Pops without blocking, even while others are waiting in `pop()`.
+    pub fn try_pop(&self) -> Option<T> {
+        self.recver.try_recv().ok()
+    }
 }
@@ -14,40 +14,55 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod batch;
 pub mod call;
+pub mod descriptor;
 pub mod dispatch;
+pub mod dynamic;
+pub mod error;
+pub mod forward;
 pub mod id;
+pub mod local;
+pub mod naming;
+pub mod query;
+pub mod schema;
 pub mod serde_support;
+pub mod stream;
 pub mod table;
 
 use super::port::PortId;
 pub use dispatch::PortDispatcher;
+pub use error::CallError;
 use serde::{Deserialize, Serialize};
 pub use std::sync::Arc;
 
 pub type MethodId = u32;
 pub type TraitId = u16;
-pub type InstanceId = u16;
+pub type InstanceId = u32;
 
 pub const ID_ORDERING: std::sync::atomic::Ordering = std::sync::atomic::Ordering::Relaxed;
 pub type MethodIdAtomic = std::sync::atomic::AtomicU32;
 pub type TraitIdAtomic = std::sync::atomic::AtomicU16;
 
 // We avoid using additional space with Option<>, by these.
-pub const UNDECIDED_INDEX: InstanceId = std::u16::MAX;
+pub const UNDECIDED_INDEX: InstanceId = std::u32::MAX;
 pub const UNDECIDED_PORT: PortId = std::u16::MAX;
 
 /// This struct represents an index to a service object in port server's registry
-#[derive(PartialEq, Serialize, Deserialize, Debug, Clone, Copy)]
+///
+/// The index is reused once the object is deleted, but the generation and the nonce are not.
+/// So an id that outlived its object, or one that is made up, matches no object.
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone, Copy, Default)]
 pub struct ServiceObjectId {
     pub(crate) index: InstanceId,
+    pub(crate) generation: u32,
+    pub(crate) nonce: u64,
 }
 
 /// This struct is stored in both service object and call stub.
-/// Actually, both use only part of the fields respectively,
-/// though the other fields will be still set same as
-/// the other side's HandleInstance.
-/// For debugging and simplicity, we won't split this for now.
+/// Only the call stub has it set, as the importer of the object.
+/// The service object keeps it undecided, since it may be exported many times,
+/// and each export is identified by the service object table of the port.
 #[derive(PartialEq, Serialize, Deserialize, Debug)]
 pub struct HandleInstance {
     pub(crate) id: ServiceObjectId,
@@ -55,6 +70,8 @@ pub struct HandleInstance {
     pub(crate) port_id_exporter: PortId,
     // That of importer's.
     pub(crate) port_id_importer: PortId,
+    // Whether it is sent back to the exporter, which resolves it to the object itself.
+    pub(crate) returned: bool,
 }
 
 impl Default for HandleInstance {
@@ -62,23 +79,17 @@ impl Default for HandleInstance {
         HandleInstance {
             id: ServiceObjectId {
                 index: UNDECIDED_INDEX,
+                generation: 0,
+                nonce: 0,
             },
             port_id_exporter: UNDECIDED_PORT,
             port_id_importer: UNDECIDED_PORT,
+            returned: false,
         }
     }
 }
 
 impl HandleInstance {
-    /// This clone is allowed only within this crate
-    pub(crate) fn careful_clone(&self) -> Self {
-        HandleInstance {
-            id: self.id,
-            port_id_exporter: self.port_id_exporter,
-            port_id_importer: self.port_id_importer,
-        }
-    }
-
     /// You (module implementor) should not call this!
     pub fn for_dispatcher_get_port_id(&self) -> PortId {
         self.port_id_exporter
@@ -90,6 +101,11 @@ pub trait Service: dispatch::ServiceDispatcher + std::fmt::Debug + intertrait::C
     fn get_handle(&self) -> &HandleInstance;
     fn get_handle_mut(&mut self) -> &mut HandleInstance;
     fn get_trait_id(&self) -> TraitId;
+
+    /// Whether this dispatches the calls to the trait by itself, so that it can be exported as it without a forwarder.
+    fn serves(&self, trait_id: TraitId) -> bool {
+        self.get_trait_id() == trait_id
+    }
 }
 
 pub struct SArc<T: ?Sized + Service> {
@@ -123,11 +139,34 @@ pub trait ExportService<T: ?Sized + Service> {
 }
 
 pub trait DispatchService<T: ?Sized + Service> {
-    fn dispatch(object: &T, method: MethodId, arguments: &[u8], return_buffer: std::io::Cursor<&mut Vec<u8>>);
+    fn dispatch(
+        object: &T,
+        method: MethodId,
+        arguments: &[u8],
+        return_buffer: std::io::Cursor<&mut Vec<u8>>,
+    ) -> Result<(), CallError>;
+
+    fn dispatch_async(
+        object: Arc<T>,
+        method: MethodId,
+        arguments: &[u8],
+    ) -> Result<Option<dispatch::PendingReturn>, CallError>;
+
+    fn dispatch_stream(
+        object: &T,
+        method: MethodId,
+        arguments: &[u8],
+    ) -> Result<Option<dispatch::PendingStream>, CallError>;
 }
 
 pub trait IdOfService<T: ?Sized + Service> {
     fn id() -> TraitId;
+
+    /// Same as `id()`, but fails if the coordinator gave no id to the trait, instead of giving `UNKNOWN_TRAIT`.
+    /// Only an instance of a generic trait may have no id, since the others are checked at the setup.
+    fn try_id() -> Result<TraitId, CallError> {
+        Ok(Self::id())
+    }
 }
 
 #[macro_export]
@@ -156,6 +195,20 @@ macro_rules! service_dispatch {
     };
 }
 
+#[macro_export]
+macro_rules! service_query {
+    ($service_trait: path, $service: expr) => {
+        codechain_fml::query_service::<dyn $service_trait, _>($service)
+    };
+}
+
+#[macro_export]
+macro_rules! service_lookup {
+    ($service_trait: path, $name: expr) => {
+        codechain_fml::lookup::<dyn $service_trait>($name)
+    };
+}
+
 #[macro_export]
 macro_rules! service_id {
     ($service_trait: path) => {
@@ -166,6 +219,14 @@ macro_rules! service_id {
 /// These are set of functions that dispatcher / call stubs generated by the macro would call
 pub mod service_context {
     pub use super::call::call;
+    pub use super::call::call_async;
+    pub use super::call::call_oneway;
+    pub use super::call::call_stream;
     pub use super::call::delete;
-    pub use super::dispatch::register;
+    pub use super::call::try_call;
+    pub use super::call::try_call_async;
+    pub use super::call::try_call_oneway;
+    pub use super::dispatch::{register, register_as};
+    pub use super::local::export_back;
+    pub use super::local::import_back;
 }
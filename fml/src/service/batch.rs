@@ -0,0 +1,139 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::codec::{Codec, CodecKind};
+use crate::context;
+use crate::port::PortId;
+use crate::service::call::encode_arguments;
+use crate::service::{CallError, HandleInstance, MethodId, ServiceObjectId};
+use crate::PacketHeader;
+use std::marker::PhantomData;
+
+/// Calls queued to be sent together in a single message.
+///
+/// All the calls must be made to services imported through the same port.
+/// They are served in the order of queueing, and each of them succeeds or fails on its own.
+/// You would usually queue them with `<Trait>Batch`, which is generated for each service trait.
+#[derive(Default)]
+pub struct Batch {
+    port: Option<(PortId, CodecKind)>,
+    calls: Vec<(ServiceObjectId, MethodId, Vec<u8>)>,
+}
+
+/// A call queued in a `Batch`, of which the result can be taken after sending the batch.
+pub struct Queued<D> {
+    index: usize,
+    _marker: PhantomData<fn() -> D>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.calls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    /// Queues a call to the method, encoding the arguments right away.
+    pub fn queue<S: serde::Serialize, D>(
+        &mut self,
+        handle: &HandleInstance,
+        method: MethodId,
+        args: &S,
+    ) -> Result<Queued<D>, CallError> {
+        let codec = match self.port {
+            Some((port_id, _)) if port_id != handle.port_id_importer => {
+                return Err(CallError::MixedPorts {
+                    batch: port_id,
+                    call: handle.port_id_importer,
+                })
+            }
+            Some((_, codec)) => codec,
+            None => {
+                let context = context::global::get();
+                let port_table = context.read();
+                let port = &port_table
+                    .map
+                    .get(&handle.port_id_importer)
+                    .ok_or(CallError::PortMissing(handle.port_id_importer))?
+                    .2;
+                self.port = Some((handle.port_id_importer, port.codec()));
+                port.codec()
+            }
+        };
+
+        let buffer = encode_arguments(handle.port_id_importer, codec, args)?;
+        self.calls.push((handle.id, method, buffer));
+        Ok(Queued {
+            index: self.calls.len() - 1,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Sends all the queued calls, and waits for all of them.
+    /// This takes the deadline and the cancellation of the current thread, as a single call does.
+    pub fn send(self) -> Result<BatchResults, CallError> {
+        #[cfg(fml_statistics)]
+        {
+            crate::statistics::CALL_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        let (port_id, codec) = match self.port {
+            Some(port) => port,
+            None => {
+                return Ok(BatchResults {
+                    results: Vec::new(),
+                    codec: CodecKind::default(),
+                })
+            }
+        };
+
+        // The port table mustn't be held while waiting, so that it can be written in the meantime.
+        let port = {
+            let context = context::global::get();
+            let port_table = context.read();
+            port_table.map.get(&port_id).ok_or(CallError::PortMissing(port_id))?.2.caller()
+        };
+        let results = port
+            .call_batch(self.calls, crate::deadline::get(), crate::cancel::current().as_ref())?
+            .into_iter()
+            .map(Some)
+            .collect();
+        Ok(BatchResults {
+            results,
+            codec,
+        })
+    }
+}
+
+/// Results of the calls in a `Batch`
+pub struct BatchResults {
+    results: Vec<Option<Result<Vec<u8>, CallError>>>,
+    codec: CodecKind,
+}
+
+impl BatchResults {
+    /// Takes the result of the queued call.
+    pub fn take<D: serde::de::DeserializeOwned>(&mut self, queued: Queued<D>) -> Result<D, CallError> {
+        let result =
+            self.results.get_mut(queued.index).and_then(Option::take).expect("The call is not from this batch");
+        self.codec.decode(&result?[PacketHeader::SIZE..])
+    }
+}
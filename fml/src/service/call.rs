@@ -14,40 +14,180 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::codec::{Codec, CodecKind};
 use crate::context;
-use crate::service::{HandleInstance, MethodId};
+use crate::port::server::port_thread_local;
+use crate::port::PortId;
+use crate::service::stream::Stream;
+use crate::service::{CallError, HandleInstance, MethodId, TraitId};
 use crate::PacketHeader;
+use futures::future::BoxFuture;
 use std::io::Cursor;
 
+/// Returns the codec of the port, without holding the port table.
+pub(crate) fn codec_of(port_id: PortId) -> Result<CodecKind, CallError> {
+    let context = context::global::get();
+    let port_table = context.read();
+    Ok(port_table.map.get(&port_id).ok_or(CallError::PortMissing(port_id))?.2.codec())
+}
+
+/// Encodes the arguments of a call made through the port, reserving PacketHeader::SIZE bytes on the first.
+///
+/// Service objects in the arguments are exported to the same port.
+/// This must not hold the port table, since exporting takes it again.
+pub(crate) fn encode_arguments<S: serde::Serialize>(
+    port_id: PortId,
+    codec: CodecKind,
+    args: &S,
+) -> Result<Vec<u8>, CallError> {
+    let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+    port_thread_local::with_key(port_id, || {
+        codec.encode(
+            {
+                let mut c = Cursor::new(&mut buffer);
+                c.set_position(PacketHeader::SIZE as u64);
+                c
+            },
+            &args,
+        )
+    })?;
+    Ok(buffer)
+}
+
+/// Calls the given method and panics if the call fails.
 pub fn call<S: serde::Serialize, D: serde::de::DeserializeOwned>(
     handle: &HandleInstance,
     method: MethodId,
     args: &S,
 ) -> D {
+    try_call(handle, method, args).unwrap_or_else(|e| panic!("Remote call failed: {}", e))
+}
+
+pub fn try_call<S: serde::Serialize, D: serde::de::DeserializeOwned>(
+    handle: &HandleInstance,
+    method: MethodId,
+    args: &S,
+) -> Result<D, CallError> {
     #[cfg(fml_statistics)]
     {
         crate::statistics::CALL_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     }
 
-    let mut buffer: Vec<u8> = Vec::new();
-    buffer.resize(std::mem::size_of::<PacketHeader>(), 0 as u8);
-    serde_cbor::to_writer(
-        {
-            let mut c = Cursor::new(&mut buffer);
-            c.set_position(std::mem::size_of::<PacketHeader>() as u64);
-            c
-        },
-        &args,
-    )
-    .unwrap();
+    let codec = codec_of(handle.port_id_importer)?;
+    let buffer = encode_arguments(handle.port_id_importer, codec, args)?;
 
     let context = context::global::get();
     let port_table = context.read();
-    let port = &port_table.map.get(&handle.port_id_importer).expect("PortTable corrupted").2;
-    let result = port.call(handle.id, method, buffer);
-    serde_cbor::from_reader(&result[std::mem::size_of::<PacketHeader>()..]).unwrap()
+    let port = &port_table.map.get(&handle.port_id_importer).ok_or(CallError::PortMissing(handle.port_id_importer))?.2;
+    let result = port.call(handle.id, method, buffer, crate::deadline::get(), crate::cancel::current().as_ref())?;
+    codec.decode(&result[PacketHeader::SIZE..])
+}
+
+/// Sends a call to a one-way method, and panics if it can't be sent.
+pub fn call_oneway<S: serde::Serialize>(handle: &HandleInstance, method: MethodId, args: &S) {
+    try_call_oneway(handle, method, args).unwrap_or_else(|e| panic!("Remote call failed: {}", e))
+}
+
+/// Succeeds once the call is sent, since there is no response.
+pub fn try_call_oneway<S: serde::Serialize>(
+    handle: &HandleInstance,
+    method: MethodId,
+    args: &S,
+) -> Result<(), CallError> {
+    #[cfg(fml_statistics)]
+    {
+        crate::statistics::CALL_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    let buffer = encode_arguments(handle.port_id_importer, codec_of(handle.port_id_importer)?, args)?;
+
+    let context = context::global::get();
+    let port_table = context.read();
+    let port = &port_table.map.get(&handle.port_id_importer).ok_or(CallError::PortMissing(handle.port_id_importer))?.2;
+    port.call_oneway(handle.id, method, buffer, crate::deadline::get())
+}
+
+/// Calls the given method without blocking, and the future panics if the call fails.
+pub fn call_async<S: serde::Serialize, D: serde::de::DeserializeOwned + Send + 'static>(
+    handle: &HandleInstance,
+    method: MethodId,
+    args: &S,
+) -> BoxFuture<'static, D> {
+    let result = try_call_async(handle, method, args);
+    Box::pin(async move { result.await.unwrap_or_else(|e| panic!("Remote call failed: {}", e)) })
 }
 
+/// The arguments are encoded right away, and the rest is done by the future.
+/// The future takes the deadline and the cancellation of the current thread, as `try_call()` does.
+pub fn try_call_async<S: serde::Serialize, D: serde::de::DeserializeOwned + Send + 'static>(
+    handle: &HandleInstance,
+    method: MethodId,
+    args: &S,
+) -> BoxFuture<'static, Result<D, CallError>> {
+    #[cfg(fml_statistics)]
+    {
+        crate::statistics::CALL_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    let call = (|| {
+        let codec = codec_of(handle.port_id_importer)?;
+        let buffer = encode_arguments(handle.port_id_importer, codec, args)?;
+
+        let context = context::global::get();
+        let port_table = context.read();
+        let port =
+            &port_table.map.get(&handle.port_id_importer).ok_or(CallError::PortMissing(handle.port_id_importer))?.2;
+        let call = port.call_async(handle.id, method, buffer, crate::deadline::get(), crate::cancel::current());
+        Ok((call, codec))
+    })();
+    Box::pin(async move {
+        let (call, codec) = call?;
+        let result = call.await?;
+        codec.decode(&result[PacketHeader::SIZE..])
+    })
+}
+
+/// Calls a method that returns a stream.
+/// Failures, even those before the call is made, come out of the stream as an error item.
+pub fn call_stream<S: serde::Serialize, D: serde::de::DeserializeOwned + Send + 'static>(
+    handle: &HandleInstance,
+    method: MethodId,
+    args: &S,
+) -> Stream<D> {
+    #[cfg(fml_statistics)]
+    {
+        crate::statistics::CALL_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    let call = (|| {
+        let codec = codec_of(handle.port_id_importer)?;
+        let buffer = encode_arguments(handle.port_id_importer, codec, args)?;
+
+        let context = context::global::get();
+        let port_table = context.read();
+        let port =
+            &port_table.map.get(&handle.port_id_importer).ok_or(CallError::PortMissing(handle.port_id_importer))?.2;
+        let call = port.call_stream(handle.id, method, buffer, crate::deadline::get(), crate::cancel::current());
+        Ok((call, codec))
+    })();
+    match call {
+        Ok((call, codec)) => Stream::remote(call, codec),
+        Err(error) => Stream::from_results(std::iter::once(Err(error))),
+    }
+}
+
+/// Asks the exporter for the object as the given trait. See `Port::query()`.
+pub fn query(handle: &HandleInstance, trait_id: TraitId) -> Result<Option<HandleInstance>, CallError> {
+    // The port table mustn't be held while waiting, so that it can be written in the meantime.
+    let port = {
+        let context = context::global::get();
+        let port_table = context.read();
+        port_table.map.get(&handle.port_id_importer).ok_or(CallError::PortMissing(handle.port_id_importer))?.2.caller()
+    };
+    port.query(handle.id, trait_id, crate::deadline::get(), crate::cancel::current().as_ref())
+}
+
+/// Failures are ignored here, since there is nothing to release if the exporter is gone.
 pub fn delete(handle: &HandleInstance) {
     if context::termination::get().load(std::sync::atomic::Ordering::Relaxed) {
         return
@@ -58,6 +198,7 @@ pub fn delete(handle: &HandleInstance) {
     }
     let context = context::global::get();
     let port_table = context.read();
-    let port = &port_table.map.get(&handle.port_id_importer).expect("PortTable corrupted").2;
-    port.delete(handle.id);
+    if let Some((_, _, port)) = port_table.map.get(&handle.port_id_importer) {
+        port.delete(handle.id).ok();
+    }
 }
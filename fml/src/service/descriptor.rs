@@ -0,0 +1,109 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Descriptions of the service traits known to this module, for tools and logs to show the methods by their names,
+//! such as `HelloRobot::hello(flag: i32) -> String`, rather than the ids.
+
+use super::schema::Shape;
+use super::{MethodId, TraitId};
+use linkme::distributed_slice;
+use serde::{Deserialize, Serialize};
+
+// Every service trait registers a function that describes it with the ids set up.
+#[distributed_slice]
+pub static DSC_REG: [fn() -> ServiceDescriptor] = [..];
+
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceDescriptor {
+    pub name: String,
+    /// Type parameters of a generic trait, which are used in the types of the methods as they are
+    pub type_params: Vec<String>,
+    /// None for a generic trait, of which each instance has its own id
+    pub id: Option<TraitId>,
+    pub doc: String,
+    /// Methods called remotely, in the order of declaration
+    pub methods: Vec<MethodDescriptor>,
+}
+
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct MethodDescriptor {
+    pub name: String,
+    pub id: MethodId,
+    pub doc: String,
+    pub asynchronous: bool,
+    pub oneway: bool,
+    /// Whether it returns a stream
+    pub stream: bool,
+    pub arguments: Vec<ArgumentDescriptor>,
+    /// None if it returns nothing
+    pub output: Option<String>,
+    /// Shape of the return value, or of each item for a stream
+    pub output_shape: Option<Shape>,
+    /// Fingerprint of the signature. See `fingerprints()`.
+    pub fingerprint: u64,
+}
+
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct ArgumentDescriptor {
+    pub name: String,
+    /// The type as it is written in the trait
+    pub ty: String,
+    /// Shape of the argument as it is sent, which is owned even if the type is a reference
+    pub shape: Shape,
+}
+
+impl MethodDescriptor {
+    /// The method as it is declared, such as `hello(flag: i32) -> String`
+    pub fn signature(&self) -> String {
+        let arguments: Vec<String> =
+            self.arguments.iter().map(|argument| format!("{}: {}", argument.name, argument.ty)).collect();
+        match &self.output {
+            Some(output) => format!("{}({}) -> {}", self.name, arguments.join(", "), output),
+            None => format!("{}({})", self.name, arguments.join(", ")),
+        }
+    }
+}
+
+impl ServiceDescriptor {
+    pub fn method(&self, id: MethodId) -> Option<&MethodDescriptor> {
+        self.methods.iter().find(|method| method.id == id)
+    }
+}
+
+/// Descriptors of all the service traits known to this module, sorted by the names
+pub fn descriptors() -> Vec<ServiceDescriptor> {
+    let mut descriptors: Vec<ServiceDescriptor> = DSC_REG.iter().map(|describe| describe()).collect();
+    descriptors.sort_by(|a, b| a.name.cmp(&b.name));
+    descriptors
+}
+
+/// Describes the method as `Trait::method(arguments) -> output`, or returns None if it is unknown.
+///
+/// An instance of a generic trait is shown by its name, such as `Store<String, u64>::get(key: &K) -> Option<V>`.
+pub fn describe_method(trait_id: TraitId, method_id: MethodId) -> Option<String> {
+    let descriptors = descriptors();
+    let (trait_name, descriptor) = match descriptors.iter().find(|descriptor| descriptor.id == Some(trait_id)) {
+        Some(descriptor) => (descriptor.name.clone(), descriptor),
+        None => {
+            let trait_name = super::id::trait_name(trait_id)?;
+            let generic_name = trait_name.split('<').next().unwrap();
+            let descriptor =
+                descriptors.iter().find(|descriptor| descriptor.id.is_none() && descriptor.name == generic_name)?;
+            (trait_name, descriptor)
+        }
+    };
+    Some(format!("{}::{}", trait_name, descriptor.method(method_id)?.signature()))
+}
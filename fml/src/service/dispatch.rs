@@ -16,8 +16,9 @@
 
 use super::table::ServiceObjectTable;
 use super::PortId;
-use super::{HandleInstance, MethodId, Service, ServiceObjectId, UNDECIDED_PORT};
+use super::{CallError, HandleInstance, MethodId, Service, ServiceObjectId, TraitId, UNDECIDED_PORT};
 use crate::context;
+use futures::future::BoxFuture;
 use parking_lot::RwLock;
 use std::sync::Arc;
 
@@ -27,12 +28,56 @@ use std::sync::Arc;
 // to the target method. This process is very specific to each trait,
 // and is generated by the proc macro.
 
+/// An async method being served, which resolves to the encoded return value.
+/// The buffer has PacketHeader::SIZE bytes reserved on the first.
+pub type PendingReturn = BoxFuture<'static, Result<Vec<u8>, CallError>>;
+
+/// Items of a stream being served, each encoded with PacketHeader::SIZE bytes reserved on the first.
+/// The stream ends at the first error.
+pub type PendingStream = Box<dyn Iterator<Item = Result<Vec<u8>, CallError>> + Send>;
+
+/// The calls are made to the object as the trait it was exported as,
+/// which matters only to an object that serves several traits by itself. See `Service::serves()`.
 pub trait ServiceDispatcher: Send + Sync {
-    fn dispatch(&self, method: MethodId, arguments: &[u8], return_buffer: std::io::Cursor<&mut Vec<u8>>);
+    fn dispatch(
+        &self,
+        trait_id: TraitId,
+        method: MethodId,
+        arguments: &[u8],
+        return_buffer: std::io::Cursor<&mut Vec<u8>>,
+    ) -> Result<(), CallError>;
+
+    /// Starts serving the method if it is async, so that the caller can drive it without blocking.
+    /// Returns None for the other methods, which must be served by `dispatch()`.
+    fn dispatch_async(
+        self: Arc<Self>,
+        _trait_id: TraitId,
+        _method: MethodId,
+        _arguments: &[u8],
+    ) -> Result<Option<PendingReturn>, CallError> {
+        Ok(None)
+    }
+
+    /// Starts serving the method if it returns a stream, of which the items are sent one by one.
+    /// Returns None for the other methods.
+    fn dispatch_stream(
+        &self,
+        _trait_id: TraitId,
+        _method: MethodId,
+        _arguments: &[u8],
+    ) -> Result<Option<PendingStream>, CallError> {
+        Ok(None)
+    }
+
+    /// The object served on behalf of, if this is a forwarder.
+    /// A query for the other traits is made to that object, rather than this.
+    fn target(&self) -> Option<Arc<dyn Service>> {
+        None
+    }
 }
 
 pub struct PortDispatcher {
-    service_table: RwLock<ServiceObjectTable>,
+    pub(crate) service_table: RwLock<ServiceObjectTable>,
     id: PortId,
 }
 
@@ -54,18 +99,62 @@ impl PortDispatcher {
         method: MethodId,
         arguments: &[u8],
         return_buffer: std::io::Cursor<&mut Vec<u8>>,
-    ) {
+    ) -> Result<(), CallError> {
         #[cfg(fml_statistics)]
         {
             crate::statistics::DISPATCH_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         }
-        let service_object = self.service_table.read().get(handle.index as usize);
+        let (service_object, trait_id) = self.service_table.read().get(handle)?;
         // NOTE: You must drop the ReadGuard before dispatch (if not deadlock)
-        service_object.dispatch(method, arguments, return_buffer);
+        service_object.dispatch(trait_id, method, arguments, return_buffer)
     }
+
+    pub fn dispatch_async(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        arguments: &[u8],
+    ) -> Result<Option<PendingReturn>, CallError> {
+        let (service_object, trait_id) = self.service_table.read().get(handle)?;
+        let pending = service_object.dispatch_async(trait_id, method, arguments)?;
+        #[cfg(fml_statistics)]
+        {
+            if pending.is_some() {
+                crate::statistics::DISPATCH_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+        Ok(pending)
+    }
+
+    pub fn dispatch_stream(
+        &self,
+        handle: ServiceObjectId,
+        method: MethodId,
+        arguments: &[u8],
+    ) -> Result<Option<PendingStream>, CallError> {
+        let (service_object, trait_id) = self.service_table.read().get(handle)?;
+        let items = service_object.dispatch_stream(trait_id, method, arguments)?;
+        #[cfg(fml_statistics)]
+        {
+            if items.is_some() {
+                crate::statistics::DISPATCH_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+        Ok(items)
+    }
+}
+
+/// Exports the object to the port as the trait it serves first, and returns the handle for the importer.
+///
+/// The object is left untouched, so the exporter may keep its own references to it
+/// and export it again, to the same port or to the others.
+pub fn register(port_id: PortId, handle_to_register: Arc<dyn Service>) -> HandleInstance {
+    let trait_id = handle_to_register.get_trait_id();
+    register_as(port_id, trait_id, handle_to_register)
 }
 
-pub fn register(port_id: PortId, mut handle_to_register: Arc<dyn Service>) -> HandleInstance {
+/// Exports the object to the port as the given trait, which it must serve by itself.
+pub fn register_as(port_id: PortId, trait_id: TraitId, handle_to_register: Arc<dyn Service>) -> HandleInstance {
     #[cfg(fml_statistics)]
     {
         crate::statistics::CREATE_COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
@@ -74,31 +163,44 @@ pub fn register(port_id: PortId, mut handle_to_register: Arc<dyn Service>) -> Ha
     let port_table = context.read();
 
     let err_msg = "The service object you're trying to export is contaminated. It could be an imported handle, not created by you.";
-    assert_eq!(
-        Arc::get_mut(&mut handle_to_register).unwrap().get_handle_mut().port_id_exporter,
-        UNDECIDED_PORT,
-        "{}",
-        err_msg
-    );
-    assert_eq!(
-        Arc::get_mut(&mut handle_to_register).unwrap().get_handle_mut().port_id_importer,
-        UNDECIDED_PORT,
-        "{}",
-        err_msg
-    );
-
-    Arc::get_mut(&mut handle_to_register).unwrap().get_handle_mut().port_id_exporter = port_id;
-    Arc::get_mut(&mut handle_to_register).unwrap().get_handle_mut().port_id_importer =
-        port_table.map.get(&port_id).unwrap().1;
-
-    let port = &port_table.map.get(&port_id).expect("PortTable corrupted").2;
-    port.dispatcher_get().service_table.write().create(handle_to_register).get_handle().careful_clone()
+    assert_eq!(handle_to_register.get_handle().port_id_exporter, UNDECIDED_PORT, "{}", err_msg);
+    assert_eq!(handle_to_register.get_handle().port_id_importer, UNDECIDED_PORT, "{}", err_msg);
+
+    let (_, port_id_importer, port) = port_table.map.get(&port_id).expect("PortTable corrupted");
+    let id = port.dispatcher_get().service_table.write().create(handle_to_register, trait_id);
+    HandleInstance {
+        id,
+        port_id_exporter: port_id,
+        port_id_importer: *port_id_importer,
+        returned: false,
+    }
 }
 
-pub fn delete(port_id: PortId, handle: ServiceObjectId) {
+pub fn delete(port_id: PortId, handle: ServiceObjectId) -> Result<(), CallError> {
+    let object = take(port_id, handle)?;
+    // Dropping a forwarder deletes the object it forwards to, which is a call to another module.
+    drop(object);
+    Ok(())
+}
+
+/// Removes the object from the port, handing the reference the importer had over to the caller.
+pub(crate) fn take(port_id: PortId, handle: ServiceObjectId) -> Result<Arc<dyn Service>, CallError> {
     let context = context::global::get();
     let port_table = context.read();
+    let port = &port_table.map.get(&port_id).ok_or(CallError::PortMissing(port_id))?.2;
+    let object = port.dispatcher_get().service_table.write().remove(handle)?;
+    Ok(object)
+}
 
-    let port = &port_table.map.get(&port_id).expect("PortTable corrupted").2;
-    port.dispatcher_get().service_table.write().remove(handle.index as usize)
+/// Exports the object again as the given trait to the same port, if it implements the trait.
+pub fn query(port_id: PortId, handle: ServiceObjectId, trait_id: TraitId) -> Result<Option<HandleInstance>, CallError> {
+    let context = context::global::get();
+    let object = {
+        let port_table = context.read();
+        let port = &port_table.map.get(&port_id).expect("PortTable corrupted").2;
+        let (object, _) = port.dispatcher_get().service_table.read().get(handle)?;
+        object
+    };
+    let object = object.target().unwrap_or(object);
+    Ok(super::query::export_as(port_id, object, trait_id))
 }
@@ -0,0 +1,947 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Calls to the services by the names of their traits and methods, with the arguments and the results in JSON,
+//! for the tools that are not built with the traits.
+//!
+//! The values are converted as the shapes in the schemas of the modules tell, so it works with any codec.
+//! A handle in a result is kept by the invoker, and shown as `{"handle": 1, "trait": "HelloRobot"}`.
+//! It can be called on and released by the number, or passed back to the module that has exported it.
+//! A 128-bit integer that doesn't fit in 64 bits is shown as a string of the digits, since a JSON number can't hold it.
+
+use super::call::{codec_of, delete, encode_arguments};
+use super::descriptor::{MethodDescriptor, ServiceDescriptor};
+use super::id::IdMap;
+use super::schema::{Schema, Shape, Variant};
+use super::{CallError, HandleInstance, MethodId, ServiceObjectId};
+use crate::codec::CodecKind;
+use crate::context;
+use crate::port::{Caller, PacketHeader, PortId};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use serde::de::{self, DeserializeSeed, Deserializer, Visitor};
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq, SerializeStruct, SerializeTuple, Serializer};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Reason why a call by the names couldn't be made
+#[derive(PartialEq, Debug, Clone)]
+pub enum InvokeError {
+    /// No schema has the trait, or the coordinator has given it no id.
+    UnknownTrait(String),
+    /// The trait has no such method.
+    UnknownMethod(String, String),
+    /// The invoker doesn't hold the handle, which may have been released or passed back already.
+    UnknownHandle(u64),
+    /// The arguments don't fit the method.
+    Arguments(String),
+    Call(CallError),
+}
+
+impl std::fmt::Display for InvokeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InvokeError::UnknownTrait(name) => write!(f, "Unknown trait: {}", name),
+            InvokeError::UnknownMethod(trait_name, name) => write!(f, "Unknown method: {}::{}", trait_name, name),
+            InvokeError::UnknownHandle(handle) => write!(f, "Unknown handle: {}", handle),
+            InvokeError::Arguments(msg) => write!(f, "Invalid arguments: {}", msg),
+            InvokeError::Call(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for InvokeError {}
+
+impl From<CallError> for InvokeError {
+    fn from(error: CallError) -> Self {
+        InvokeError::Call(error)
+    }
+}
+
+struct Held {
+    trait_name: String,
+    handle: HandleInstance,
+}
+
+/// Calls the services of the linked modules by the names, holding the handles it has got.
+///
+/// The handles are deleted when it is dropped.
+pub struct Invoker {
+    services: HashMap<String, ServiceDescriptor>,
+    id_map: IdMap,
+    handles: Mutex<HashMap<u64, Held>>,
+    next_handle: AtomicU64,
+}
+
+impl Invoker {
+    /// The traits are those in the schemas, with the ids given by the coordinator.
+    pub fn new(schemas: Vec<Schema>, id_map: IdMap) -> Self {
+        let services =
+            schemas.into_iter().flat_map(|schema| schema.services).map(|service| (service.name.clone(), service));
+        Invoker {
+            services: services.collect(),
+            id_map,
+            handles: Default::default(),
+            next_handle: AtomicU64::new(1),
+        }
+    }
+
+    /// The traits it can call, sorted by the names
+    pub fn services(&self) -> Vec<&ServiceDescriptor> {
+        let mut services: Vec<&ServiceDescriptor> = self.services.values().collect();
+        services.sort_by(|a, b| a.name.cmp(&b.name));
+        services
+    }
+
+    /// Keeps a handle got in another way, such as the bootstrap, to call it by the names.
+    pub fn adopt(&self, trait_name: &str, handle: HandleInstance) -> Value {
+        let number = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().insert(number, Held {
+            trait_name: trait_name.to_owned(),
+            handle,
+        });
+        serde_json::json!({"handle": number, "trait": trait_name})
+    }
+
+    /// Finds the service published under the name as the trait in the linked modules. See `lookup()`.
+    pub fn lookup(&self, name: &str, trait_name: &str) -> Result<Option<Value>, InvokeError> {
+        self.service(trait_name)?;
+        let trait_id =
+            *self.id_map.trait_map.get(trait_name).ok_or_else(|| InvokeError::UnknownTrait(trait_name.to_owned()))?;
+        // The port table mustn't be held while waiting, so that it can be written in the meantime.
+        let mut ports: Vec<(PortId, Caller)> = {
+            let context = context::global::get();
+            let port_table = context.read();
+            port_table.map.iter().map(|(port_id, (_, _, port))| (*port_id, port.caller())).collect()
+        };
+        ports.sort_by_key(|(port_id, _)| *port_id);
+        for (_, port) in ports {
+            match port.lookup(name, trait_id, crate::deadline::get(), crate::cancel::current().as_ref()) {
+                Ok(Some(handle)) => return Ok(Some(self.adopt(trait_name, handle))),
+                Ok(None) | Err(CallError::PeerGone) => continue,
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Calls the method of the handle, of which the result is null if it returns nothing.
+    /// A stream is collected into an array, until it ends.
+    pub fn invoke(&self, handle: u64, method_name: &str, arguments: &[Value]) -> Result<Value, InvokeError> {
+        let (trait_name, port_id, object_id) = {
+            let handles = self.handles.lock();
+            let held = handles.get(&handle).ok_or(InvokeError::UnknownHandle(handle))?;
+            (held.trait_name.clone(), held.handle.port_id_importer, held.handle.id)
+        };
+        let method = self
+            .service(&trait_name)?
+            .methods
+            .iter()
+            .find(|method| method.name == method_name)
+            .ok_or_else(|| InvokeError::UnknownMethod(trait_name.clone(), method_name.to_owned()))?;
+        if arguments.len() != method.arguments.len() {
+            return Err(InvokeError::Arguments(format!(
+                "{} takes {} arguments, but {} are given",
+                method.name,
+                method.arguments.len(),
+                arguments.len()
+            )))
+        }
+        let method_id = self.method_id(&trait_name, method);
+
+        // The arguments are sent as a tuple, which is a unit if there is none.
+        let (shape, value) = if arguments.is_empty() {
+            (Shape::Unit, Value::Null)
+        } else {
+            (
+                Shape::Tuple(method.arguments.iter().map(|argument| argument.shape.clone()).collect()),
+                Value::Array(arguments.to_vec()),
+            )
+        };
+        let codec = codec_of(port_id)?;
+        let encoding = Encoding {
+            invoker: self,
+            port_id,
+            passed: Default::default(),
+        };
+        let buffer = encode_arguments(port_id, codec, &Shaped {
+            shape: &shape,
+            value: &value,
+            encoding: &encoding,
+        })
+        .map_err(|error| match error {
+            CallError::Encode(msg) => InvokeError::Arguments(msg),
+            error => InvokeError::Call(error),
+        })?;
+        let result = self.call(port_id, object_id, method_id, method, codec, buffer);
+
+        // The exporter owns the handles passed back once it gets the call.
+        // Those of a failed call might not have got there, so they are deleted, which does nothing if they have.
+        let passed: Vec<Held> = {
+            let mut handles = self.handles.lock();
+            encoding.passed.into_inner().iter().filter_map(|number| handles.remove(number)).collect()
+        };
+        if result.is_err() {
+            for held in passed {
+                delete(&held.handle);
+            }
+        }
+        result
+    }
+
+    /// Makes the call with the encoded arguments, and decodes the result.
+    fn call(
+        &self,
+        port_id: PortId,
+        object_id: ServiceObjectId,
+        method_id: MethodId,
+        method: &MethodDescriptor,
+        codec: CodecKind,
+        buffer: Vec<u8>,
+    ) -> Result<Value, InvokeError> {
+        let context = context::global::get();
+        let port_table = context.read();
+        let port = &port_table.map.get(&port_id).ok_or(CallError::PortMissing(port_id))?.2;
+        let deadline = crate::deadline::get();
+        let output = match &method.output_shape {
+            Some(output) => output,
+            None => &Shape::Unit,
+        };
+        if method.stream {
+            let mut call = port.call_stream(object_id, method_id, buffer, deadline, crate::cancel::current());
+            drop(port_table);
+            let mut items = Vec::new();
+            while let Some(item) = futures::executor::block_on(futures::StreamExt::next(&mut call)) {
+                items.push(self.decode(codec, &item?[PacketHeader::SIZE..], output)?);
+            }
+            return Ok(Value::Array(items))
+        }
+        // The port table mustn't be held while waiting, so that it can be written in the meantime.
+        let port = port.caller();
+        drop(port_table);
+        if method.oneway {
+            port.call_oneway(object_id, method_id, buffer, deadline)?;
+            return Ok(Value::Null)
+        }
+        let result = port.call(object_id, method_id, buffer, deadline, crate::cancel::current().as_ref())?;
+        if method.output_shape.is_none() {
+            return Ok(Value::Null)
+        }
+        Ok(self.decode(codec, &result[PacketHeader::SIZE..], output)?)
+    }
+
+    /// Deletes the handle.
+    pub fn release(&self, handle: u64) -> Result<(), InvokeError> {
+        let held = self.handles.lock().remove(&handle).ok_or(InvokeError::UnknownHandle(handle))?;
+        delete(&held.handle);
+        Ok(())
+    }
+
+    /// An instance of a generic trait is described by the generic one.
+    fn service(&self, trait_name: &str) -> Result<&ServiceDescriptor, InvokeError> {
+        let generic_name = trait_name.split('<').next().unwrap();
+        self.services.get(generic_name).ok_or_else(|| InvokeError::UnknownTrait(trait_name.to_owned()))
+    }
+
+    /// The method ids in the schema are the default ones, unless the coordinator gives them.
+    fn method_id(&self, trait_name: &str, method: &MethodDescriptor) -> MethodId {
+        self.id_map.method_map.get(&(trait_name.to_owned(), method.name.clone())).copied().unwrap_or(method.id)
+    }
+
+    fn decode(&self, codec: CodecKind, data: &[u8], shape: &Shape) -> Result<Value, CallError> {
+        let seed = Seed {
+            shape,
+            invoker: self,
+        };
+        match codec {
+            CodecKind::Cbor => seed.deserialize(&mut serde_cbor::Deserializer::from_slice(data)).map_err(decode_error),
+            CodecKind::Bincode => {
+                use bincode::Options;
+                // Same as bincode::deserialize()
+                let options = bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes();
+                options.deserialize_seed(seed, data).map_err(decode_error)
+            }
+            CodecKind::Json => seed.deserialize(&mut serde_json::Deserializer::from_slice(data)).map_err(decode_error),
+        }
+    }
+}
+
+impl Drop for Invoker {
+    fn drop(&mut self) {
+        for (_, held) in self.handles.get_mut().drain() {
+            delete(&held.handle);
+        }
+    }
+}
+
+fn decode_error<E: std::fmt::Display>(error: E) -> CallError {
+    CallError::Decode(error.to_string())
+}
+
+// Serde takes the names of the types, the fields and the variants as static ones, so each is made once.
+// They are never dropped, but there are only as many as the names in the schemas.
+fn intern(name: &str) -> &'static str {
+    static NAMES: OnceCell<Mutex<HashSet<&'static str>>> = OnceCell::new();
+    let mut names = NAMES.get_or_init(Default::default).lock();
+    if let Some(name) = names.get(name) {
+        return name
+    }
+    let name: &'static str = Box::leak(name.to_owned().into_boxed_str());
+    names.insert(name);
+    name
+}
+
+fn intern_all<'a>(names: impl Iterator<Item = &'a String>) -> &'static [&'static str] {
+    static LISTS: OnceCell<Mutex<HashSet<&'static [&'static str]>>> = OnceCell::new();
+    let list: Vec<&'static str> = names.map(|name| intern(name)).collect();
+    let mut lists = LISTS.get_or_init(Default::default).lock();
+    if let Some(list) = lists.get(list.as_slice()) {
+        return list
+    }
+    let list: &'static [&'static str] = Box::leak(list.into_boxed_slice());
+    lists.insert(list);
+    list
+}
+
+/// A 128-bit integer, which is given as a string if it doesn't fit in a JSON number
+fn wide_integer<T: TryFrom<i64> + TryFrom<u64> + FromStr>(value: &Value) -> Option<T> {
+    match value {
+        Value::Number(number) => number
+            .as_i64()
+            .and_then(|x| T::try_from(x).ok())
+            .or_else(|| number.as_u64().and_then(|x| T::try_from(x).ok())),
+        Value::String(digits) => digits.parse().ok(),
+        _ => None,
+    }
+}
+
+/// What the shape is called in the errors
+fn expected(shape: &Shape) -> String {
+    match shape {
+        Shape::Option(inner) => format!("Option<{}>", expected(inner)),
+        Shape::Seq(_) => "sequence".to_owned(),
+        Shape::Tuple(shapes) => format!("tuple of {}", shapes.len()),
+        Shape::Map {
+            ..
+        } => "map".to_owned(),
+        Shape::UnitStruct(name)
+        | Shape::NewtypeStruct(name, _)
+        | Shape::TupleStruct(name, _)
+        | Shape::Struct(name, _)
+        | Shape::Enum(name, _)
+        | Shape::Opaque(name) => name.clone(),
+        Shape::Handle(trait_name) => format!("handle of {}", trait_name),
+        primitive => format!("{:?}", primitive).to_lowercase(),
+    }
+}
+
+/// Handles in the arguments, which can only be passed back to the port they have come from.
+struct Encoding<'a> {
+    invoker: &'a Invoker,
+    port_id: PortId,
+    /// The handles to forget once the arguments are encoded
+    passed: RefCell<Vec<u64>>,
+}
+
+impl<'a> Encoding<'a> {
+    fn pass_back(&self, value: &Value) -> Result<HandleInstance, String> {
+        let number = value.get("handle").and_then(Value::as_u64).ok_or_else(|| format!("{} is not a handle", value))?;
+        let handles = self.invoker.handles.lock();
+        let held = handles.get(&number).ok_or_else(|| format!("Unknown handle: {}", number))?;
+        if held.handle.port_id_importer != self.port_id {
+            return Err(format!("Handle {} can be passed only to the module it is from", number))
+        }
+        self.passed.borrow_mut().push(number);
+        Ok(HandleInstance {
+            id: held.handle.id,
+            port_id_exporter: held.handle.port_id_exporter,
+            port_id_importer: held.handle.port_id_importer,
+            returned: true,
+        })
+    }
+}
+
+/// A value serialized as the shape
+struct Shaped<'a> {
+    shape: &'a Shape,
+    value: &'a Value,
+    encoding: &'a Encoding<'a>,
+}
+
+impl<'a> Shaped<'a> {
+    fn with<'b>(&self, shape: &'b Shape, value: &'b Value) -> Shaped<'b>
+    where
+        'a: 'b, {
+        Shaped {
+            shape,
+            value,
+            encoding: self.encoding,
+        }
+    }
+}
+
+macro_rules! serialize_integer {
+    ($serializer: expr, $method: ident, $type: ty, $value: expr, $as: ident, $error: expr) => {
+        $serializer.$method($value.$as().and_then(|x| <$type>::try_from(x).ok()).ok_or_else($error)?)
+    };
+}
+
+impl<'a> Serialize for Shaped<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = self.value;
+        let mismatch = || <S::Error as ser::Error>::custom(format!("{} doesn't fit {}", value, expected(self.shape)));
+        match self.shape {
+            Shape::Unit => value.as_null().ok_or_else(mismatch).and_then(|_| serializer.serialize_unit()),
+            Shape::Bool => serializer.serialize_bool(value.as_bool().ok_or_else(mismatch)?),
+            Shape::I8 => serialize_integer!(serializer, serialize_i8, i8, value, as_i64, mismatch),
+            Shape::I16 => serialize_integer!(serializer, serialize_i16, i16, value, as_i64, mismatch),
+            Shape::I32 => serialize_integer!(serializer, serialize_i32, i32, value, as_i64, mismatch),
+            Shape::I64 => serialize_integer!(serializer, serialize_i64, i64, value, as_i64, mismatch),
+            Shape::I128 => serializer.serialize_i128(wide_integer(value).ok_or_else(mismatch)?),
+            Shape::U8 => serialize_integer!(serializer, serialize_u8, u8, value, as_u64, mismatch),
+            Shape::U16 => serialize_integer!(serializer, serialize_u16, u16, value, as_u64, mismatch),
+            Shape::U32 => serialize_integer!(serializer, serialize_u32, u32, value, as_u64, mismatch),
+            Shape::U64 => serialize_integer!(serializer, serialize_u64, u64, value, as_u64, mismatch),
+            Shape::U128 => serializer.serialize_u128(wide_integer(value).ok_or_else(mismatch)?),
+            Shape::F32 => serializer.serialize_f32(value.as_f64().ok_or_else(mismatch)? as f32),
+            Shape::F64 => serializer.serialize_f64(value.as_f64().ok_or_else(mismatch)?),
+            Shape::Char => {
+                let mut chars = value.as_str().ok_or_else(mismatch)?.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => serializer.serialize_char(c),
+                    _ => Err(mismatch()),
+                }
+            }
+            Shape::Str => serializer.serialize_str(value.as_str().ok_or_else(mismatch)?),
+            Shape::Bytes => {
+                let bytes: Option<Vec<u8>> = value
+                    .as_array()
+                    .ok_or_else(mismatch)?
+                    .iter()
+                    .map(|byte| byte.as_u64().and_then(|byte| u8::try_from(byte).ok()))
+                    .collect();
+                serializer.serialize_bytes(&bytes.ok_or_else(mismatch)?)
+            }
+            Shape::Option(inner) => match value {
+                Value::Null => serializer.serialize_none(),
+                value => serializer.serialize_some(&self.with(inner, value)),
+            },
+            Shape::Seq(inner) => {
+                let items = value.as_array().ok_or_else(mismatch)?;
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    seq.serialize_element(&self.with(inner, item))?;
+                }
+                seq.end()
+            }
+            Shape::Tuple(shapes) => {
+                let items = value.as_array().filter(|items| items.len() == shapes.len()).ok_or_else(mismatch)?;
+                let mut tuple = serializer.serialize_tuple(shapes.len())?;
+                for (shape, item) in shapes.iter().zip(items) {
+                    tuple.serialize_element(&self.with(shape, item))?;
+                }
+                tuple.end()
+            }
+            Shape::Map {
+                key,
+                value: value_shape,
+            } => {
+                let entries = value.as_object().ok_or_else(mismatch)?;
+                // The keys of a JSON object are strings, so the others are written in JSON there.
+                let keys = entries
+                    .keys()
+                    .map(|name| match **key {
+                        Shape::Str | Shape::Char => Ok(Value::String(name.clone())),
+                        _ => serde_json::from_str(name)
+                            .map_err(|_| ser::Error::custom(format!("Key {} doesn't fit {}", name, expected(key)))),
+                    })
+                    .collect::<Result<Vec<Value>, S::Error>>()?;
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key_value, item) in keys.iter().zip(entries.values()) {
+                    map.serialize_entry(&self.with(key, key_value), &self.with(value_shape, item))?;
+                }
+                map.end()
+            }
+            Shape::UnitStruct(name) => {
+                value.as_null().ok_or_else(mismatch)?;
+                serializer.serialize_unit_struct(intern(name))
+            }
+            Shape::NewtypeStruct(name, inner) => {
+                serializer.serialize_newtype_struct(intern(name), &self.with(inner, value))
+            }
+            Shape::TupleStruct(name, shapes) => {
+                use ser::SerializeTupleStruct;
+                let items = value.as_array().filter(|items| items.len() == shapes.len()).ok_or_else(mismatch)?;
+                let mut tuple = serializer.serialize_tuple_struct(intern(name), shapes.len())?;
+                for (shape, item) in shapes.iter().zip(items) {
+                    tuple.serialize_field(&self.with(shape, item))?;
+                }
+                tuple.end()
+            }
+            Shape::Struct(name, fields) => {
+                let object = value.as_object().ok_or_else(mismatch)?;
+                let mut fields_out = serializer.serialize_struct(intern(name), fields.len())?;
+                for (field, shape) in fields {
+                    fields_out.serialize_field(intern(field), &self.with(shape, field_of(object, field, shape)?))?;
+                }
+                fields_out.end()
+            }
+            Shape::Enum(name, variants) => {
+                // Externally tagged as serde_json does, such as `"Unit"` or `{"Newtype": 1}`
+                let (variant_name, content) = match value {
+                    Value::String(variant_name) => (variant_name, &NULL),
+                    Value::Object(object) if object.len() == 1 => object.iter().next().unwrap(),
+                    _ => return Err(mismatch()),
+                };
+                let index = variants
+                    .iter()
+                    .position(|(candidate, _)| candidate == variant_name)
+                    .ok_or_else(|| ser::Error::custom(format!("{} has no variant {}", name, variant_name)))?;
+                let (name, variant_index, variant_name) = (intern(name), index as u32, intern(variant_name));
+                match &variants[index].1 {
+                    Variant::Unit => {
+                        content.as_null().ok_or_else(mismatch)?;
+                        serializer.serialize_unit_variant(name, variant_index, variant_name)
+                    }
+                    Variant::Newtype(shape) => serializer.serialize_newtype_variant(
+                        name,
+                        variant_index,
+                        variant_name,
+                        &self.with(shape, content),
+                    ),
+                    Variant::Tuple(shapes) => {
+                        use ser::SerializeTupleVariant;
+                        let items =
+                            content.as_array().filter(|items| items.len() == shapes.len()).ok_or_else(mismatch)?;
+                        let mut tuple =
+                            serializer.serialize_tuple_variant(name, variant_index, variant_name, shapes.len())?;
+                        for (shape, item) in shapes.iter().zip(items) {
+                            tuple.serialize_field(&self.with(shape, item))?;
+                        }
+                        tuple.end()
+                    }
+                    Variant::Struct(fields) => {
+                        use ser::SerializeStructVariant;
+                        let object = content.as_object().ok_or_else(mismatch)?;
+                        let mut fields_out =
+                            serializer.serialize_struct_variant(name, variant_index, variant_name, fields.len())?;
+                        for (field, shape) in fields {
+                            fields_out
+                                .serialize_field(intern(field), &self.with(shape, field_of(object, field, shape)?))?;
+                        }
+                        fields_out.end()
+                    }
+                }
+            }
+            Shape::Handle(_) => self.encoding.pass_back(value).map_err(ser::Error::custom)?.serialize(serializer),
+            Shape::Opaque(name) => Err(ser::Error::custom(format!("{} can't be converted", name))),
+        }
+    }
+}
+
+static NULL: Value = Value::Null;
+
+/// A missing field is null, only if it is optional.
+fn field_of<'a, E: ser::Error>(object: &'a Map<String, Value>, field: &str, shape: &Shape) -> Result<&'a Value, E> {
+    match (object.get(field), shape) {
+        (Some(value), _) => Ok(value),
+        (None, Shape::Option(_)) => Ok(&NULL),
+        (None, _) => Err(E::custom(format!("Missing field {}", field))),
+    }
+}
+
+/// Deserializes a value of the shape into JSON.
+#[derive(Clone, Copy)]
+struct Seed<'a> {
+    shape: &'a Shape,
+    invoker: &'a Invoker,
+}
+
+impl<'a> Seed<'a> {
+    fn with<'b>(self, shape: &'b Shape) -> Seed<'b>
+    where
+        'a: 'b, {
+        Seed {
+            shape,
+            invoker: self.invoker,
+        }
+    }
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for Seed<'a> {
+    type Value = Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        match self.shape {
+            Shape::Unit => deserializer.deserialize_unit(self),
+            Shape::Bool => deserializer.deserialize_bool(self),
+            Shape::I8 => deserializer.deserialize_i8(self),
+            Shape::I16 => deserializer.deserialize_i16(self),
+            Shape::I32 => deserializer.deserialize_i32(self),
+            Shape::I64 => deserializer.deserialize_i64(self),
+            Shape::I128 => deserializer.deserialize_i128(self),
+            Shape::U8 => deserializer.deserialize_u8(self),
+            Shape::U16 => deserializer.deserialize_u16(self),
+            Shape::U32 => deserializer.deserialize_u32(self),
+            Shape::U64 => deserializer.deserialize_u64(self),
+            Shape::U128 => deserializer.deserialize_u128(self),
+            Shape::F32 => deserializer.deserialize_f32(self),
+            Shape::F64 => deserializer.deserialize_f64(self),
+            Shape::Char => deserializer.deserialize_char(self),
+            Shape::Str => deserializer.deserialize_string(self),
+            Shape::Bytes => deserializer.deserialize_byte_buf(self),
+            Shape::Option(_) => deserializer.deserialize_option(self),
+            Shape::Seq(_) => deserializer.deserialize_seq(self),
+            Shape::Tuple(shapes) => deserializer.deserialize_tuple(shapes.len(), self),
+            Shape::Map {
+                ..
+            } => deserializer.deserialize_map(self),
+            Shape::UnitStruct(name) => deserializer.deserialize_unit_struct(intern(name), self),
+            Shape::NewtypeStruct(name, _) => deserializer.deserialize_newtype_struct(intern(name), self),
+            Shape::TupleStruct(name, shapes) => deserializer.deserialize_tuple_struct(intern(name), shapes.len(), self),
+            Shape::Struct(name, fields) => {
+                deserializer.deserialize_struct(intern(name), intern_all(fields.iter().map(|(field, _)| field)), self)
+            }
+            Shape::Enum(name, variants) => deserializer.deserialize_enum(
+                intern(name),
+                intern_all(variants.iter().map(|(variant, _)| variant)),
+                self,
+            ),
+            Shape::Handle(trait_name) => {
+                let handle = HandleInstance::deserialize(deserializer)?;
+                Ok(self.invoker.adopt(trait_name, handle))
+            }
+            Shape::Opaque(name) => Err(de::Error::custom(format!("{} can't be converted", name))),
+        }
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for Seed<'a> {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&expected(self.shape))
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::from(v))
+    }
+
+    fn visit_i128<E: de::Error>(self, v: i128) -> Result<Value, E> {
+        let number = i64::try_from(v).map(Value::from).or_else(|_| u64::try_from(v).map(Value::from));
+        Ok(number.unwrap_or_else(|_| Value::String(v.to_string())))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        Ok(Value::from(v))
+    }
+
+    fn visit_u128<E: de::Error>(self, v: u128) -> Result<Value, E> {
+        Ok(u64::try_from(v).map(Value::from).unwrap_or_else(|_| Value::String(v.to_string())))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+        Ok(Value::from(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(v.to_owned()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Value, E> {
+        Ok(Value::String(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(Value::Array(v.iter().map(|&byte| Value::from(byte)).collect()))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        match self.shape {
+            Shape::Option(inner) => self.with(inner).deserialize(deserializer),
+            _ => Err(de::Error::invalid_type(de::Unexpected::Option, &self)),
+        }
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        match self.shape {
+            Shape::NewtypeStruct(_, inner) => self.with(inner).deserialize(deserializer),
+            _ => Err(de::Error::invalid_type(de::Unexpected::NewtypeStruct, &self)),
+        }
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let missing = || de::Error::custom(format!("Too short for {}", expected(self.shape)));
+        match self.shape {
+            Shape::Seq(inner) => {
+                let mut items = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(item) = seq.next_element_seed(self.with(inner))? {
+                    items.push(item);
+                }
+                Ok(Value::Array(items))
+            }
+            Shape::Bytes => {
+                let mut items = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(byte) = seq.next_element::<u8>()? {
+                    items.push(Value::from(byte));
+                }
+                Ok(Value::Array(items))
+            }
+            Shape::Tuple(shapes) | Shape::TupleStruct(_, shapes) => {
+                let mut items = Vec::with_capacity(shapes.len());
+                for shape in shapes {
+                    items.push(seq.next_element_seed(self.with(shape))?.ok_or_else(missing)?);
+                }
+                Ok(Value::Array(items))
+            }
+            // Some formats write a struct as a tuple of the fields.
+            Shape::Struct(_, fields) => {
+                let mut object = Map::new();
+                for (field, shape) in fields {
+                    object.insert(field.clone(), seq.next_element_seed(self.with(shape))?.ok_or_else(missing)?);
+                }
+                Ok(Value::Object(object))
+            }
+            _ => Err(de::Error::invalid_type(de::Unexpected::Seq, &self)),
+        }
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut object = Map::new();
+        match self.shape {
+            Shape::Map {
+                key,
+                value,
+            } => {
+                while let Some(key) = map.next_key_seed(self.with(key))? {
+                    let name = match key {
+                        Value::String(name) => name,
+                        key => key.to_string(),
+                    };
+                    object.insert(name, map.next_value_seed(self.with(value))?);
+                }
+            }
+            Shape::Struct(_, fields) => {
+                while let Some(name) = map.next_key::<String>()? {
+                    match fields.iter().find(|(field, _)| *field == name) {
+                        Some((_, shape)) => {
+                            object.insert(name, map.next_value_seed(self.with(shape))?);
+                        }
+                        None => {
+                            map.next_value::<de::IgnoredAny>()?;
+                        }
+                    }
+                }
+            }
+            _ => return Err(de::Error::invalid_type(de::Unexpected::Map, &self)),
+        }
+        Ok(Value::Object(object))
+    }
+
+    fn visit_enum<A: de::EnumAccess<'de>>(self, data: A) -> Result<Value, A::Error> {
+        use de::VariantAccess;
+        let variants = match self.shape {
+            Shape::Enum(_, variants) => variants,
+            _ => return Err(de::Error::invalid_type(de::Unexpected::Enum, &self)),
+        };
+        let (index, content) = data.variant_seed(VariantSeed(variants))?;
+        let (name, variant) = &variants[index];
+        let value = match variant {
+            Variant::Unit => {
+                content.unit_variant()?;
+                return Ok(Value::String(name.clone()))
+            }
+            Variant::Newtype(shape) => content.newtype_variant_seed(self.with(shape))?,
+            Variant::Tuple(shapes) => {
+                let shape = Shape::Tuple(shapes.clone());
+                content.tuple_variant(shapes.len(), self.with(&shape))?
+            }
+            Variant::Struct(fields) => {
+                let shape = Shape::Struct(name.clone(), fields.clone());
+                content.struct_variant(intern_all(fields.iter().map(|(field, _)| field)), self.with(&shape))?
+            }
+        };
+        let mut object = Map::new();
+        object.insert(name.clone(), value);
+        Ok(Value::Object(object))
+    }
+}
+
+/// Finds the index of a variant, which is given by the name or by the index depending on the format.
+struct VariantSeed<'a>(&'a [(String, Variant)]);
+
+impl<'de, 'a> DeserializeSeed<'de> for VariantSeed<'a> {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for VariantSeed<'a> {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "one of {} variants", self.0.len())
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<usize, E> {
+        usize::try_from(v)
+            .ok()
+            .filter(|&index| index < self.0.len())
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<usize, E> {
+        self.0.iter().position(|(name, _)| name == v).ok_or_else(|| E::unknown_variant(v, &[]))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<usize, E> {
+        match std::str::from_utf8(v) {
+            Ok(v) => self.visit_str(v),
+            Err(_) => Err(E::invalid_value(de::Unexpected::Bytes(v), &self)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+    use crate::shape_of;
+    use serde::Serialize;
+
+    #[derive(PartialEq, Serialize, Deserialize, Debug)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    #[derive(PartialEq, Serialize, Deserialize, Debug)]
+    enum Event {
+        Start,
+        Move(Point),
+        Jump(u8, i8),
+        Stop {
+            at: Point,
+            why: Option<String>,
+        },
+    }
+
+    type Sample = (Vec<Event>, HashMap<u32, String>, char, f64, bool);
+
+    #[test]
+    fn round_trip() {
+        let invoker = Invoker::new(Vec::new(), IdMap {
+            trait_map: HashMap::new(),
+            method_map: HashMap::new(),
+        });
+        let encoding = Encoding {
+            invoker: &invoker,
+            port_id: 0,
+            passed: Default::default(),
+        };
+        let mut map = HashMap::new();
+        map.insert(7, "Seven".to_owned());
+        let sample: Sample = (
+            vec![
+                Event::Start,
+                Event::Move(Point {
+                    x: 1,
+                    y: -2,
+                }),
+                Event::Jump(3, -4),
+                Event::Stop {
+                    at: Point {
+                        x: 5,
+                        y: 6,
+                    },
+                    why: None,
+                },
+            ],
+            map,
+            'A',
+            0.5,
+            true,
+        );
+        // JSON values look the same as serde_json makes them.
+        let json = serde_json::to_value(&sample).unwrap();
+        let shape = shape_of::<Sample>();
+        for codec in CodecKind::ALL.iter() {
+            let encoded = codec
+                .to_vec(&Shaped {
+                    shape: &shape,
+                    value: &json,
+                    encoding: &encoding,
+                })
+                .unwrap();
+            assert_eq!(codec.decode::<Sample>(&encoded).unwrap(), sample);
+            assert_eq!(invoker.decode(*codec, &codec.to_vec(&sample).unwrap(), &shape).unwrap(), json);
+        }
+
+        let encode = |shape: &Shape, value: Value| {
+            CodecKind::Cbor.to_vec(&Shaped {
+                shape,
+                value: &value,
+                encoding: &encoding,
+            })
+        };
+        assert!(encode(&Shape::U8, serde_json::json!(256)).is_err());
+        assert!(encode(&Shape::Str, serde_json::json!(1)).is_err());
+        assert!(encode(&shape_of::<Event>(), serde_json::json!("Fly")).is_err());
+        assert!(encode(&shape_of::<Point>(), serde_json::json!({"x": 1})).is_err());
+        assert!(encode(&shape_of::<Option<Point>>(), Value::Null).is_ok());
+        assert!(encode(&Shape::Handle("Probe".to_owned()), serde_json::json!({"handle": 1})).is_err());
+        assert!(encode(&Shape::Opaque("K".to_owned()), Value::Null).is_err());
+        assert!(encode(&Shape::U128, serde_json::json!(-1)).is_err());
+        assert!(encode(&Shape::I128, serde_json::json!("1.5")).is_err());
+
+        // Those beyond 64 bits are strings.
+        type Wide = (i128, u128, i128, u128);
+        let wide: Wide = (i128::MIN, u128::MAX, i128::from(u64::MAX), 7);
+        let json = serde_json::json!([i128::MIN.to_string(), u128::MAX.to_string(), u64::MAX, 7]);
+        let shape = shape_of::<Wide>();
+        let encoded = CodecKind::Bincode
+            .to_vec(&Shaped {
+                shape: &shape,
+                value: &json,
+                encoding: &encoding,
+            })
+            .unwrap();
+        assert_eq!(CodecKind::Bincode.decode::<Wide>(&encoded).unwrap(), wide);
+        assert_eq!(invoker.decode(CodecKind::Bincode, &encoded, &shape).unwrap(), json);
+
+        let name = "Point".to_owned();
+        assert!(std::ptr::eq(intern("Point"), intern(&name)));
+    }
+}
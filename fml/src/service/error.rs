@@ -0,0 +1,87 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::{MethodId, ServiceObjectId};
+use crate::port::PortId;
+use serde::{Deserialize, Serialize};
+
+/// Reason why a remote call couldn't be completed.
+///
+/// Errors that happen in the exporter's side (e.g. `UnknownMethod`) are
+/// serialized and sent back to the caller, so this must stay serializable.
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub enum CallError {
+    /// There is no port with the given id in the PortTable.
+    PortMissing(PortId),
+    /// The counterparty module has been unlinked or terminated.
+    PeerGone,
+    /// Failed to serialize the arguments or the return value.
+    Encode(String),
+    /// Failed to deserialize the arguments or the return value.
+    Decode(String),
+    /// The service implementation panicked with the given message.
+    RemotePanic(String),
+    /// The call couldn't be completed in time.
+    Timeout,
+    /// The caller cancelled the call.
+    Cancelled,
+    /// The arguments or the result exceeded the maximum message size of the port.
+    TooLarge {
+        size: usize,
+        limit: usize,
+    },
+    /// The exporter doesn't know the requested method.
+    UnknownMethod(MethodId),
+    /// The exporter has no object for the handle, which may have been deleted already.
+    UnknownHandle(ServiceObjectId),
+    /// A call was queued in a batch of another port, which it can't be sent through.
+    MixedPorts {
+        batch: PortId,
+        call: PortId,
+    },
+    /// No service handler of the exporter became free in time.
+    Busy,
+    /// The coordinator gave no id to the trait, which is an instance of a generic trait.
+    UnknownTrait(String),
+}
+
+impl std::fmt::Display for CallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CallError::PortMissing(port_id) => write!(f, "Port {} doesn't exist", port_id),
+            CallError::PeerGone => write!(f, "Counterparty module is gone"),
+            CallError::Encode(msg) => write!(f, "Failed to encode: {}", msg),
+            CallError::Decode(msg) => write!(f, "Failed to decode: {}", msg),
+            CallError::RemotePanic(msg) => write!(f, "Service panicked: {}", msg),
+            CallError::Timeout => write!(f, "Call timed out"),
+            CallError::Cancelled => write!(f, "Call cancelled"),
+            CallError::TooLarge {
+                size,
+                limit,
+            } => write!(f, "Message of {} bytes exceeds the limit of {} bytes", size, limit),
+            CallError::UnknownMethod(method) => write!(f, "Unknown method: {}", method),
+            CallError::UnknownHandle(handle) => write!(f, "Unknown handle: {}", handle.index),
+            CallError::MixedPorts {
+                batch,
+                call,
+            } => write!(f, "Call through port {} can't be queued in a batch for port {}", call, batch),
+            CallError::Busy => write!(f, "No service handler is available"),
+            CallError::UnknownTrait(name) => write!(f, "Unknown trait: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
@@ -0,0 +1,101 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::dispatch::{PendingReturn, PendingStream, ServiceDispatcher};
+use super::{CallError, DispatchService, HandleInstance, IdOfService, MethodId, Service, TraitId, UNDECIDED_PORT};
+use std::sync::Arc;
+
+/// A service object exported again by a module that doesn't serve it by itself, such as an imported one,
+/// or one exported as a trait other than the one it dispatches by itself.
+///
+/// Such an object already has a handle that tells where it is served, or it doesn't know the methods of the trait,
+/// so this is registered in its place, dispatching the calls as `T`.
+/// Calls to this are made to the object as if they were from this module, and
+/// deleting this drops the object, which in turn deletes the one it was imported from.
+pub struct Forwarder<T: ?Sized> {
+    handle: HandleInstance,
+    pub(super) target: Arc<T>,
+}
+
+impl<T: ?Sized + Service + IdOfService<T>> Forwarder<T> {
+    pub fn new(target: Arc<T>) -> Self {
+        Forwarder {
+            handle: Default::default(),
+            target,
+        }
+    }
+
+    /// Whether the object has to be forwarded to be exported as `T`,
+    /// since it is bound to a port already, or it doesn't serve `T` by itself.
+    pub fn is_needed(object: &T) -> bool {
+        object.get_handle().port_id_exporter != UNDECIDED_PORT || !object.serves(<T as IdOfService<T>>::id())
+    }
+}
+
+impl<T: ?Sized + Service> std::fmt::Debug for Forwarder<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Forwarder").field("handle", &self.handle).field("target", &self.target).finish()
+    }
+}
+
+impl<T: ?Sized + Service + DispatchService<T> + IdOfService<T>> ServiceDispatcher for Forwarder<T> {
+    fn dispatch(
+        &self,
+        _trait_id: TraitId,
+        method: MethodId,
+        arguments: &[u8],
+        return_buffer: std::io::Cursor<&mut Vec<u8>>,
+    ) -> Result<(), CallError> {
+        <T as DispatchService<T>>::dispatch(&*self.target, method, arguments, return_buffer)
+    }
+
+    fn dispatch_async(
+        self: Arc<Self>,
+        _trait_id: TraitId,
+        method: MethodId,
+        arguments: &[u8],
+    ) -> Result<Option<PendingReturn>, CallError> {
+        <T as DispatchService<T>>::dispatch_async(self.target.clone(), method, arguments)
+    }
+
+    fn dispatch_stream(
+        &self,
+        _trait_id: TraitId,
+        method: MethodId,
+        arguments: &[u8],
+    ) -> Result<Option<PendingStream>, CallError> {
+        <T as DispatchService<T>>::dispatch_stream(&*self.target, method, arguments)
+    }
+
+    fn target(&self) -> Option<Arc<dyn Service>> {
+        // An imported object can't be cast, since it implements nothing but `T`.
+        intertrait::cast::CastArc::cast::<dyn Service>(self.target.clone()).ok()
+    }
+}
+
+impl<T: ?Sized + Service + DispatchService<T> + IdOfService<T>> Service for Forwarder<T> {
+    fn get_handle(&self) -> &HandleInstance {
+        &self.handle
+    }
+
+    fn get_handle_mut(&mut self) -> &mut HandleInstance {
+        &mut self.handle
+    }
+
+    fn get_trait_id(&self) -> TraitId {
+        <T as IdOfService<T>>::id()
+    }
+}
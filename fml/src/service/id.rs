@@ -14,13 +14,14 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use super::{MethodId, TraitId};
+use super::schema::Shape;
+use super::{CallError, MethodId, TraitId};
 use crate::context::InstanceKey;
 use linkme::distributed_slice;
 use once_cell::sync::OnceCell;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 
 // While you debug or test FML, there are two dangerous issues.
 //
@@ -48,12 +49,13 @@ type TraitIdentifierSetter = fn(id: TraitId);
 pub static TID_REG: [(&'static str, TraitIdentifierSetter)] = [..];
 
 // Id of methods in services.
-// Note that here the two strings mean (trait name, method name)
+// Note that here the two strings mean (trait name, method name), followed by what makes the fingerprint of its signature.
 // Also you can skip calling this, then the method id will be set up for default value
 // decided by the order of declaration.
 type MethodIdentifierSetter = fn(id: MethodId);
+type MethodFingerprinter = fn() -> u64;
 #[distributed_slice]
-pub static MID_REG: [(&'static str, &'static str, MethodIdentifierSetter)] = [..];
+pub static MID_REG: [(&'static str, &'static str, MethodFingerprinter, MethodIdentifierSetter)] = [..];
 
 /// This will be provided by the coordinator.
 #[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
@@ -87,7 +89,7 @@ pub fn setup_identifiers(instance_key: InstanceKey, descriptor: &IdMap) {
     }
     {
         let mut bucket: HashSet<(String, String)> = HashSet::new();
-        for (ident1, ident2, _) in MID_REG {
+        for (ident1, ident2, ..) in MID_REG {
             bucket.insert(((*ident1).to_owned(), (*ident2).to_owned()));
         }
         assert_eq!(bucket.len(), MID_REG.len());
@@ -96,10 +98,11 @@ pub fn setup_identifiers(instance_key: InstanceKey, descriptor: &IdMap) {
     for (trait_name, setter) in TID_REG {
         setter(*descriptor.trait_map.get(*trait_name).expect("Invalid handle descriptor"));
     }
+    set_trait_names(&descriptor.trait_map);
 
     // method ids have default values decided by the order, so it is ok to leave them in an ordinary case.
     if !descriptor.method_map.is_empty() {
-        for (trait_name, method_name, setter) in MID_REG {
+        for (trait_name, method_name, _, setter) in MID_REG {
             setter(
                 *descriptor
                     .method_map
@@ -111,3 +114,165 @@ pub fn setup_identifiers(instance_key: InstanceKey, descriptor: &IdMap) {
 
     ONCE_CHECK.get().unwrap().lock()[instance_key as usize] = true;
 }
+
+// Generic traits have no static ids, so the ids of their instances are looked up by the names.
+static TRAIT_NAMES: OnceCell<RwLock<HashMap<String, TraitId>>> = OnceCell::new();
+
+pub(crate) fn set_trait_names(trait_map: &HashMap<String, TraitId>) {
+    TRAIT_NAMES.get_or_init(Default::default).write().extend(trait_map.iter().map(|(name, id)| (name.clone(), *id)));
+}
+
+/// Name of the trait given the id by the coordinator, such as `Store<String, u64>` for an instance of a generic trait
+pub(crate) fn trait_name(trait_id: TraitId) -> Option<String> {
+    TRAIT_NAMES.get()?.read().iter().find(|(_, id)| **id == trait_id).map(|(name, _)| name.clone())
+}
+
+/// Name of the type without the module paths, such as `Vec<String>` for `alloc::vec::Vec<alloc::string::String>`
+pub fn type_name<T: ?Sized>() -> String {
+    let full = std::any::type_name::<T>();
+    let mut name = String::with_capacity(full.len());
+    // Where the current path segment starts in the name
+    let mut segment = 0;
+    let mut chars = full.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&':') {
+            chars.next();
+            name.truncate(segment);
+        } else {
+            name.push(c);
+            if !(c.is_alphanumeric() || c == '_') {
+                segment = name.len();
+            }
+        }
+    }
+    name
+}
+
+/// Id given to an instance of a generic trait that the coordinator doesn't know.
+/// See `IdOfService::try_id()` for the error.
+pub const UNKNOWN_TRAIT: TraitId = TraitId::MAX;
+
+/// Id of an instance of a generic trait, which the coordinator gives by a name such as `Store<String, u64>`.
+pub fn generic_trait_id(trait_name: &str, type_names: &[String]) -> Result<TraitId, CallError> {
+    let name = format!("{}<{}>", trait_name, type_names.join(", "));
+    TRAIT_NAMES.get_or_init(Default::default).read().get(&name).copied().ok_or(CallError::UnknownTrait(name))
+}
+
+/// Name of a type argument of a generic service trait, as the coordinator writes it in the id map.
+///
+/// This is spelled out here instead of taken from `std::any::type_name()`, which may differ between compilers,
+/// and so between the modules. Implement it for your own types to use them as type arguments.
+pub trait TypeName {
+    fn type_name() -> String;
+}
+
+macro_rules! impl_type_name {
+    ($($ty: ty),*) => {
+        $(impl TypeName for $ty {
+            fn type_name() -> String {
+                stringify!($ty).to_owned()
+            }
+        })*
+    };
+}
+
+impl_type_name!(bool, char, i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize, f32, f64, str, String);
+
+impl<T: ?Sized + TypeName> TypeName for &T {
+    fn type_name() -> String {
+        format!("&{}", T::type_name())
+    }
+}
+
+impl<T: ?Sized + TypeName> TypeName for Box<T> {
+    fn type_name() -> String {
+        format!("Box<{}>", T::type_name())
+    }
+}
+
+macro_rules! impl_type_name_generic {
+    ($name: ident<$($param: ident),*>) => {
+        impl<$($param: TypeName),*> TypeName for $name<$($param),*> {
+            fn type_name() -> String {
+                let params: Vec<String> = vec![$($param::type_name()),*];
+                format!("{}<{}>", stringify!($name), params.join(", "))
+            }
+        }
+    };
+}
+
+impl_type_name_generic!(Vec<T>);
+impl_type_name_generic!(Option<T>);
+impl_type_name_generic!(HashSet<T>);
+impl_type_name_generic!(BTreeSet<T>);
+impl_type_name_generic!(Result<T, E>);
+impl_type_name_generic!(HashMap<K, V>);
+impl_type_name_generic!(BTreeMap<K, V>);
+
+macro_rules! impl_type_name_tuple {
+    ($($param: ident),*) => {
+        impl<$($param: TypeName),*> TypeName for ($($param,)*) {
+            fn type_name() -> String {
+                let params: Vec<String> = vec![$($param::type_name()),*];
+                match params.len() {
+                    1 => format!("({},)", params[0]),
+                    _ => format!("({})", params.join(", ")),
+                }
+            }
+        }
+    };
+}
+
+impl_type_name_tuple!();
+impl_type_name_tuple!(A);
+impl_type_name_tuple!(A, B);
+impl_type_name_tuple!(A, B, C);
+impl_type_name_tuple!(A, B, C, D);
+
+/// (Trait name, method name, fingerprint of the signature)
+pub type MethodFingerprint = (String, String, u64);
+
+/// Hash of the method as it goes through the wire, which must be same in both ends of a call.
+///
+/// This is FNV-1a over the kind of the method and the shapes of its arguments and return value,
+/// so that it changes with a field of a struct in them, but not with how the types are written.
+/// Types that can't be traced and those in a generic trait are compared by their names, as written.
+pub fn fingerprint(asynchronous: bool, oneway: bool, stream: bool, arguments: &[Shape], output: Option<Shape>) -> u64 {
+    serde_cbor::to_vec(&(asynchronous, oneway, stream, arguments, output))
+        .expect("Shapes are always serializable")
+        .into_iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Fingerprints of all the methods known to this module
+pub fn fingerprints() -> Vec<MethodFingerprint> {
+    // Tracing the shapes takes a while, and they never change.
+    static FINGERPRINTS: OnceCell<Vec<MethodFingerprint>> = OnceCell::new();
+    FINGERPRINTS
+        .get_or_init(|| {
+            MID_REG
+                .iter()
+                .map(|(trait_name, method_name, fingerprint, _)| {
+                    ((*trait_name).to_owned(), (*method_name).to_owned(), fingerprint())
+                })
+                .collect()
+        })
+        .clone()
+}
+
+/// Returns the methods known to both, but with different signatures, as `Trait::method`.
+pub fn mismatched_methods(ours: &[MethodFingerprint], theirs: &[MethodFingerprint]) -> Vec<String> {
+    let theirs: HashMap<(&str, &str), u64> = theirs
+        .iter()
+        .map(|(trait_name, method_name, fingerprint)| ((trait_name.as_str(), method_name.as_str()), *fingerprint))
+        .collect();
+    let mut mismatched: Vec<String> = ours
+        .iter()
+        .filter(|(trait_name, method_name, fingerprint)| {
+            theirs.get(&(trait_name.as_str(), method_name.as_str())).map_or(false, |x| x != fingerprint)
+        })
+        .map(|(trait_name, method_name, _)| format!("{}::{}", trait_name, method_name))
+        .collect();
+    mismatched.sort();
+    mismatched
+}
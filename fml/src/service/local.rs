@@ -0,0 +1,76 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A handle may come back to the module that exports the object, after travelling through other modules.
+//! Instead of calling itself over the port, the exporter gets the object back and calls it directly.
+//!
+//! An importer sends the handle back as it is, rather than exporting its proxy through a forwarder.
+//! The reference it had is handed over to the exporter, which removes the object from the port and keeps it by itself.
+//! So the object lives exactly as long as it would, if it were forwarded.
+
+use super::forward::Forwarder;
+use super::{CallError, HandleInstance, Service};
+use crate::port::PortId;
+use std::sync::Arc;
+
+/// Sends the handle of an imported object back, if it is exported to the port it was imported from.
+///
+/// It gives the object back if it is not the one to go back,
+/// or there are other references to it in this module, which still need the handle.
+pub fn export_back<T: ?Sized + Service>(port_id: PortId, mut object: Arc<T>) -> Result<HandleInstance, Arc<T>> {
+    if object.get_handle().port_id_importer != port_id {
+        return Err(object)
+    }
+    let handle = match Arc::get_mut(&mut object) {
+        Some(object) => std::mem::take(object.get_handle_mut()),
+        None => return Err(object),
+    };
+    // The proxy is left without a handle, so dropping it doesn't delete the object.
+    drop(object);
+    Ok(HandleInstance {
+        returned: true,
+        ..handle
+    })
+}
+
+/// Resolves a handle sent back to this module to the object it refers to.
+///
+/// It gives the handle back if it refers to an object in another module.
+/// A handle sent back that refers to no object of this module, or to one of another trait,
+/// is given back as a handle of the peer instead, so that the calls to it fail as to a deleted object.
+pub fn import_back<T: ?Sized + Service + 'static>(handle: HandleInstance) -> Result<Arc<T>, HandleInstance> {
+    if !handle.returned {
+        return Err(handle)
+    }
+    take_back(&handle).map_err(|error| {
+        log::warn!("Failed to import a handle sent back: {}", error);
+        HandleInstance {
+            port_id_importer: handle.port_id_exporter,
+            returned: false,
+            ..handle
+        }
+    })
+}
+
+fn take_back<T: ?Sized + Service + 'static>(handle: &HandleInstance) -> Result<Arc<T>, CallError> {
+    // The importer might have deleted it already.
+    let object = super::dispatch::take(handle.port_id_exporter, handle.id)?;
+    // A forwarder is just a wrapper made when the object was exported.
+    if let Ok(forwarder) = object.clone().arc_any().downcast::<Forwarder<T>>() {
+        return Ok(forwarder.target.clone())
+    }
+    intertrait::cast::CastArc::cast::<T>(object).map_err(|_| CallError::UnknownHandle(handle.id))
+}
@@ -0,0 +1,129 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A module may publish a service under a name, so that the linked modules can find it at any time,
+//! not only through the handles exchanged at the bootstrap.
+//!
+//! Each module keeps the services it publishes by itself.
+//! A lookup asks the linked modules in turn, and the one publishing the name exports the service to the asker.
+//! Once a module unpublishes a name, or goes away, the name is no longer found,
+//! but the handles already looked up keep working as long as the publisher does.
+
+use super::{CallError, ExportService, HandleInstance, IdOfService, ImportService, Service, TraitId};
+use crate::context;
+use crate::port::{Caller, PortId};
+use parking_lot::RwLock;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Exports the published object, which is an `Arc<T>` of the trait it is published as.
+type Exporter = fn(port_id: PortId, object: &(dyn Any + Send + Sync)) -> HandleInstance;
+
+struct Entry {
+    trait_id: TraitId,
+    object: Arc<dyn Any + Send + Sync>,
+    export: Exporter,
+}
+
+/// The services this module publishes
+#[derive(Default)]
+pub struct Names {
+    entries: RwLock<HashMap<String, Entry>>,
+}
+
+fn export<T: ?Sized + Service + ExportService<T>>(port_id: PortId, object: &(dyn Any + Send + Sync)) -> HandleInstance {
+    let object = object.downcast_ref::<Arc<T>>().expect("Published object corrupted");
+    T::export(port_id, object.clone())
+}
+
+/// Publishes the service under the name, as the trait `T`.
+///
+/// Returns false if the name is taken already, in which case you have to unpublish it first.
+pub fn publish<T>(name: &str, object: Arc<T>) -> bool
+where
+    T: ?Sized + Service + ExportService<T> + IdOfService<T> + 'static, {
+    let context = context::global::get();
+    let port_table = context.read();
+    let mut entries = port_table.names.entries.write();
+    if entries.contains_key(name) {
+        return false
+    }
+    entries.insert(name.to_owned(), Entry {
+        trait_id: T::id(),
+        object: Arc::new(object),
+        export: export::<T>,
+    });
+    true
+}
+
+/// Stops publishing the name. Returns false if it is not published.
+///
+/// The modules that have looked it up already keep their handles.
+pub fn unpublish(name: &str) -> bool {
+    let context = context::global::get();
+    let entry = {
+        let port_table = context.read();
+        let mut entries = port_table.names.entries.write();
+        entries.remove(name)
+    };
+    // Dropping the object may delete the one it was imported from, which is a call to another module,
+    // so the port table must be released first.
+    entry.is_some()
+}
+
+/// Finds the service published under the name as the trait `T`,
+/// first in this module, and then in the linked modules in the order of their ports.
+///
+/// A linked module that has gone away is skipped.
+pub fn lookup<T>(name: &str) -> Result<Option<Arc<T>>, CallError>
+where
+    T: ?Sized + Service + ImportService<T> + IdOfService<T> + 'static, {
+    let trait_id = T::try_id()?;
+    let context = context::global::get();
+    // The port table mustn't be held while waiting, so that it can be written in the meantime.
+    let mut ports: Vec<(PortId, Caller)> = {
+        let port_table = context.read();
+        if let Some(entry) = port_table.names.entries.read().get(name) {
+            if entry.trait_id == trait_id {
+                return Ok(entry.object.downcast_ref::<Arc<T>>().cloned())
+            }
+        }
+        port_table.map.iter().map(|(port_id, (_, _, port))| (*port_id, port.caller())).collect()
+    };
+    ports.sort_by_key(|(port_id, _)| *port_id);
+    for (_, port) in ports {
+        match port.lookup(name, trait_id, crate::deadline::get(), crate::cancel::current().as_ref()) {
+            Ok(Some(handle)) => return Ok(Some(T::import(handle))),
+            Ok(None) | Err(CallError::PeerGone) => continue,
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(None)
+}
+
+/// Exports the service published under the name to the port, if it is published as the trait.
+pub fn export_named(port_id: PortId, name: &str, trait_id: TraitId) -> Option<HandleInstance> {
+    let context = context::global::get();
+    let (object, export) = {
+        let port_table = context.read();
+        let entries = port_table.names.entries.read();
+        let entry = entries.get(name).filter(|entry| entry.trait_id == trait_id)?;
+        (entry.object.clone(), entry.export)
+    };
+    // Exporting takes the port table again, so it must be released first.
+    Some(export(port_id, &*object))
+}
@@ -0,0 +1,52 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! An importer may ask whether the object behind a handle implements another service trait,
+//! as a remote version of casting it with intertrait.
+//! The exporter casts the object and exports it again, so the importer gets a separate handle for the trait.
+
+use super::{CallError, HandleInstance, IdOfService, ImportService, Service, TraitId};
+use crate::port::PortId;
+use linkme::distributed_slice;
+use std::sync::Arc;
+
+/// Exports the object as a trait, if it implements the trait.
+type TraitExporter = fn(port_id: PortId, object: Arc<dyn Service>) -> Option<HandleInstance>;
+
+// Every service trait registers how to export an object as it, along with its id.
+#[distributed_slice]
+pub static QRY_REG: [(fn() -> TraitId, TraitExporter)] = [..];
+
+/// Exports the object as the trait of the given id.
+/// Returns None if it doesn't implement the trait, or the trait is unknown to this module.
+pub fn export_as(port_id: PortId, object: Arc<dyn Service>, trait_id: TraitId) -> Option<HandleInstance> {
+    let (_, exporter) = QRY_REG.iter().find(|(id, _)| id() == trait_id)?;
+    exporter(port_id, object)
+}
+
+/// Returns the service as another trait, if the object behind it implements the trait.
+///
+/// An imported service is asked to the exporter, and a local one is just cast.
+/// Note that an object imported from a third module and then exported again is known only as the traits it was imported as.
+pub fn query_service<T, S>(service: &Arc<S>) -> Result<Option<Arc<T>>, CallError>
+where
+    T: ?Sized + Service + ImportService<T> + IdOfService<T>,
+    S: ?Sized + Service, {
+    if service.get_handle().port_id_importer == super::UNDECIDED_PORT {
+        return Ok(intertrait::cast::CastArc::cast::<T>(service.clone()).ok())
+    }
+    Ok(super::call::query(service.get_handle(), T::try_id()?)?.map(T::import))
+}
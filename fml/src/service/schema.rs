@@ -0,0 +1,588 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Shapes of the types in the service traits, as serde sees them, so that a module written elsewhere
+//! can tell what goes through the wire and the changes of it between releases.
+//!
+//! A shape is traced by deserializing the type from a deserializer that makes up the data as asked.
+//! An enum is traced as many times as needed to see all the variants.
+//! A type that deserializes whatever comes, such as `serde_json::Value`, or a recursive one can't be traced.
+
+use super::descriptor::ServiceDescriptor;
+use serde::de::{self, DeserializeSeed, Deserializer, IntoDeserializer, Visitor};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::HashMap;
+
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub enum Shape {
+    Unit,
+    Bool,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    F32,
+    F64,
+    Char,
+    Str,
+    Bytes,
+    Option(Box<Shape>),
+    Seq(Box<Shape>),
+    Tuple(Vec<Shape>),
+    Map {
+        key: Box<Shape>,
+        value: Box<Shape>,
+    },
+    UnitStruct(String),
+    NewtypeStruct(String, Box<Shape>),
+    TupleStruct(String, Vec<Shape>),
+    Struct(String, Vec<(String, Shape)>),
+    Enum(String, Vec<(String, Variant)>),
+    /// A service sent as a handle, by its trait
+    Handle(String),
+    /// A type that couldn't be traced, as it is written
+    Opaque(String),
+}
+
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub enum Variant {
+    Unit,
+    Newtype(Shape),
+    Tuple(Vec<Shape>),
+    Struct(Vec<(String, Shape)>),
+}
+
+/// All the service traits known to this module, with the shapes of their types
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct Schema {
+    pub services: Vec<ServiceDescriptor>,
+}
+
+/// The schema of this module. Compare it with that of another release to find the breaking changes.
+pub fn schema() -> Schema {
+    Schema {
+        services: super::descriptor::descriptors(),
+    }
+}
+
+/// Writes the schema of this module to the file in JSON.
+pub fn write_schema(path: &str) -> std::io::Result<()> {
+    let json = serde_json::to_string_pretty(&schema()).expect("Schema is always serializable");
+    std::fs::write(path, json)
+}
+
+/// The shape of the type, or an opaque one if it can't be traced.
+pub fn shape_of<T: de::DeserializeOwned>() -> Shape {
+    trace::<T>().unwrap_or_else(|_| Shape::Opaque(super::id::type_name::<T>()))
+}
+
+// An enum can't be traced by all its variants at once, so it is traced again until all are seen.
+const MAX_TRACES: usize = 64;
+
+fn trace<T: de::DeserializeOwned>() -> Result<Shape, TraceError> {
+    let _guard = Tracing::start();
+    let mut state = State::default();
+    for _ in 0..MAX_TRACES {
+        let mut shape = None;
+        T::deserialize(Tracer {
+            state: &mut state,
+            out: &mut shape,
+        })?;
+        if state.enums.values().all(|e| e.variants.iter().all(Option::is_some)) {
+            let mut shape = traced(shape);
+            state.fill(&mut shape);
+            return Ok(shape)
+        }
+    }
+    Err(TraceError("Too many variants to trace".to_owned()))
+}
+
+thread_local! {
+    static TRACING: Cell<bool> = Cell::new(false);
+    // Trait of the handle being traced. See `trace_handle()`.
+    static HANDLE_TRAIT: Cell<Option<String>> = Cell::new(None);
+}
+
+struct Tracing;
+
+impl Tracing {
+    fn start() -> Self {
+        TRACING.with(|x| x.set(true));
+        Tracing
+    }
+}
+
+impl Drop for Tracing {
+    fn drop(&mut self) {
+        TRACING.with(|x| x.set(false));
+    }
+}
+
+/// Whether a shape is being traced on this thread, in which case a handle must not be imported.
+pub(crate) fn is_tracing() -> bool {
+    TRACING.with(|x| x.get())
+}
+
+// A unit struct of this name is a handle.
+const HANDLE_MARKER: &str = "$fml::Handle";
+
+/// Lets the tracer see a handle of the trait `T`, in place of the handle instance.
+pub(crate) fn trace_handle<'de, T: ?Sized, D: Deserializer<'de>>(deserializer: D) -> Result<(), D::Error> {
+    HANDLE_TRAIT.with(|x| x.set(Some(super::id::type_name::<T>().trim_start_matches("dyn ").to_owned())));
+    deserializer.deserialize_unit_struct(HANDLE_MARKER, de::IgnoredAny).map(|_| ())
+}
+
+#[derive(Debug)]
+struct TraceError(String);
+
+impl std::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl de::Error for TraceError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        TraceError(msg.to_string())
+    }
+}
+
+#[derive(Default)]
+struct EnumVariants {
+    names: Vec<&'static str>,
+    /// None for the ones not traced yet
+    variants: Vec<Option<Variant>>,
+    /// Which one to trace next, once all are traced
+    next: usize,
+}
+
+#[derive(Default)]
+struct State {
+    enums: HashMap<&'static str, EnumVariants>,
+    /// Names of the containers being traced, to stop at a recursive type
+    stack: Vec<&'static str>,
+}
+
+impl State {
+    fn enter(&mut self, name: &'static str) -> Result<(), TraceError> {
+        if self.stack.contains(&name) {
+            return Err(TraceError(format!("{} is recursive", name)))
+        }
+        self.stack.push(name);
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        self.stack.pop();
+    }
+
+    /// Picks a variant not traced yet, or each of them in turn to reach the enums inside.
+    fn pick(&mut self, name: &'static str, names: &'static [&'static str]) -> usize {
+        let e = self.enums.entry(name).or_insert_with(|| EnumVariants {
+            names: names.to_vec(),
+            variants: vec![None; names.len()],
+            next: 0,
+        });
+        match e.variants.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                e.next = (e.next + 1) % names.len();
+                e.next
+            }
+        }
+    }
+
+    /// Puts the variants into the enums in the shape, which are left empty while tracing.
+    fn fill(&self, shape: &mut Shape) {
+        let fill_all = |shapes: &mut dyn Iterator<Item = &mut Shape>| {
+            for shape in shapes {
+                self.fill(shape)
+            }
+        };
+        match shape {
+            Shape::Option(inner) | Shape::Seq(inner) | Shape::NewtypeStruct(_, inner) => self.fill(inner),
+            Shape::Map {
+                key,
+                value,
+            } => {
+                self.fill(key);
+                self.fill(value)
+            }
+            Shape::Tuple(shapes) | Shape::TupleStruct(_, shapes) => fill_all(&mut shapes.iter_mut()),
+            Shape::Struct(_, fields) => fill_all(&mut fields.iter_mut().map(|(_, shape)| shape)),
+            Shape::Enum(name, variants) => {
+                let e = &self.enums[name.as_str()];
+                *variants = e
+                    .names
+                    .iter()
+                    .zip(e.variants.iter())
+                    .map(|(name, variant)| {
+                        let mut variant = variant.clone().unwrap();
+                        match &mut variant {
+                            Variant::Unit => (),
+                            Variant::Newtype(shape) => self.fill(shape),
+                            Variant::Tuple(shapes) => fill_all(&mut shapes.iter_mut()),
+                            Variant::Struct(fields) => fill_all(&mut fields.iter_mut().map(|(_, shape)| shape)),
+                        }
+                        ((*name).to_owned(), variant)
+                    })
+                    .collect();
+            }
+            _ => (),
+        }
+    }
+}
+
+fn traced(shape: Option<Shape>) -> Shape {
+    shape.unwrap_or_else(|| Shape::Opaque("unknown".to_owned()))
+}
+
+/// Makes up the data as the type asks, writing down what is asked.
+struct Tracer<'a> {
+    state: &'a mut State,
+    out: &'a mut Option<Shape>,
+}
+
+macro_rules! trace_primitive {
+    ($method: ident, $visit: ident, $value: expr, $shape: ident) => {
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+            *self.out = Some(Shape::$shape);
+            visitor.$visit($value)
+        }
+    };
+}
+
+impl<'de, 'a> Deserializer<'de> for Tracer<'a> {
+    type Error = TraceError;
+
+    trace_primitive!(deserialize_bool, visit_bool, false, Bool);
+    trace_primitive!(deserialize_i8, visit_i8, 0, I8);
+    trace_primitive!(deserialize_i16, visit_i16, 0, I16);
+    trace_primitive!(deserialize_i32, visit_i32, 0, I32);
+    trace_primitive!(deserialize_i64, visit_i64, 0, I64);
+    trace_primitive!(deserialize_i128, visit_i128, 0, I128);
+    trace_primitive!(deserialize_u8, visit_u8, 0, U8);
+    trace_primitive!(deserialize_u16, visit_u16, 0, U16);
+    trace_primitive!(deserialize_u32, visit_u32, 0, U32);
+    trace_primitive!(deserialize_u64, visit_u64, 0, U64);
+    trace_primitive!(deserialize_u128, visit_u128, 0, U128);
+    trace_primitive!(deserialize_f32, visit_f32, 0.0, F32);
+    trace_primitive!(deserialize_f64, visit_f64, 0.0, F64);
+    trace_primitive!(deserialize_char, visit_char, 'A', Char);
+    trace_primitive!(deserialize_str, visit_str, "", Str);
+    trace_primitive!(deserialize_string, visit_str, "", Str);
+    trace_primitive!(deserialize_bytes, visit_bytes, &[], Bytes);
+    trace_primitive!(deserialize_byte_buf, visit_bytes, &[], Bytes);
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.out = Some(Shape::Unit);
+        visitor.visit_unit()
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, TraceError> {
+        Err(TraceError("The type takes any data".to_owned()))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut inner = None;
+        let value = visitor.visit_some(Tracer {
+            state: self.state,
+            out: &mut inner,
+        })?;
+        *self.out = Some(Shape::Option(Box::new(traced(inner))));
+        Ok(value)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, name: &'static str, visitor: V) -> Result<V::Value, TraceError> {
+        *self.out = Some(if name == HANDLE_MARKER {
+            Shape::Handle(HANDLE_TRAIT.with(|x| x.take()).unwrap_or_default())
+        } else {
+            Shape::UnitStruct(name.to_owned())
+        });
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.state.enter(name)?;
+        let mut inner = None;
+        let value = visitor.visit_newtype_struct(Tracer {
+            state: self.state,
+            out: &mut inner,
+        })?;
+        self.state.leave();
+        *self.out = Some(Shape::NewtypeStruct(name.to_owned(), Box::new(traced(inner))));
+        Ok(value)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        // A single element is enough to see its shape.
+        let mut access = SeqTracer::new(self.state, 1);
+        let value = visitor.visit_seq(&mut access)?;
+        *self.out = Some(Shape::Seq(Box::new(access.shapes.pop().unwrap_or(Shape::Unit))));
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, TraceError> {
+        let mut access = SeqTracer::new(self.state, len);
+        let value = visitor.visit_seq(&mut access)?;
+        *self.out = Some(Shape::Tuple(access.shapes));
+        Ok(value)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.state.enter(name)?;
+        let mut access = SeqTracer::new(self.state, len);
+        let value = visitor.visit_seq(&mut access)?;
+        let shapes = access.shapes;
+        self.state.leave();
+        *self.out = Some(Shape::TupleStruct(name.to_owned(), shapes));
+        Ok(value)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut access = MapTracer::new(self.state, MapKeys::Traced(1));
+        let value = visitor.visit_map(&mut access)?;
+        let mut entries = access.shapes.into_iter();
+        *self.out = Some(match entries.next() {
+            Some((key, value)) => Shape::Map {
+                key: Box::new(key),
+                value: Box::new(value),
+            },
+            None => Shape::Opaque("map".to_owned()),
+        });
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.state.enter(name)?;
+        let mut access = MapTracer::new(self.state, MapKeys::Fields(fields));
+        let value = visitor.visit_map(&mut access)?;
+        let shapes = access.shapes;
+        self.state.leave();
+        let fields = fields.iter().map(|field| (*field).to_owned()).zip(shapes.into_iter().map(|(_, value)| value));
+        *self.out = Some(Shape::Struct(name.to_owned(), fields.collect()));
+        Ok(value)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.state.enter(name)?;
+        let index = self.state.pick(name, variants);
+        let value = visitor.visit_enum(EnumTracer {
+            state: self.state,
+            name,
+            index,
+        })?;
+        self.state.leave();
+        // The variants are put at the end, when all of them are traced.
+        *self.out = Some(Shape::Enum(name.to_owned(), Vec::new()));
+        Ok(value)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, TraceError> {
+        Err(TraceError("Identifiers are given only as the keys of a struct".to_owned()))
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        visitor.visit_unit()
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+struct SeqTracer<'a> {
+    state: &'a mut State,
+    len: usize,
+    shapes: Vec<Shape>,
+}
+
+impl<'a> SeqTracer<'a> {
+    fn new(state: &'a mut State, len: usize) -> Self {
+        SeqTracer {
+            state,
+            len,
+            shapes: Vec::new(),
+        }
+    }
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for SeqTracer<'a> {
+    type Error = TraceError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, TraceError> {
+        if self.shapes.len() == self.len {
+            return Ok(None)
+        }
+        let mut shape = None;
+        let value = seed.deserialize(Tracer {
+            state: self.state,
+            out: &mut shape,
+        })?;
+        self.shapes.push(traced(shape));
+        Ok(Some(value))
+    }
+}
+
+enum MapKeys {
+    /// Keys are traced as well, for this many entries
+    Traced(usize),
+    Fields(&'static [&'static str]),
+}
+
+struct MapTracer<'a> {
+    state: &'a mut State,
+    keys: MapKeys,
+    /// Shape of the key and the value of each entry
+    shapes: Vec<(Shape, Shape)>,
+    key: Option<Shape>,
+}
+
+impl<'a> MapTracer<'a> {
+    fn new(state: &'a mut State, keys: MapKeys) -> Self {
+        MapTracer {
+            state,
+            keys,
+            shapes: Vec::new(),
+            key: None,
+        }
+    }
+}
+
+impl<'de, 'a> de::MapAccess<'de> for MapTracer<'a> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, TraceError> {
+        let index = self.shapes.len();
+        match self.keys {
+            MapKeys::Traced(len) if index < len => {
+                let mut shape = None;
+                let key = seed.deserialize(Tracer {
+                    state: self.state,
+                    out: &mut shape,
+                })?;
+                self.key = Some(traced(shape));
+                Ok(Some(key))
+            }
+            MapKeys::Fields(fields) if index < fields.len() => {
+                self.key = Some(Shape::Str);
+                seed.deserialize(fields[index].into_deserializer()).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, TraceError> {
+        let mut shape = None;
+        let value = seed.deserialize(Tracer {
+            state: self.state,
+            out: &mut shape,
+        })?;
+        self.shapes.push((traced(self.key.take()), traced(shape)));
+        Ok(value)
+    }
+}
+
+struct EnumTracer<'a> {
+    state: &'a mut State,
+    name: &'static str,
+    index: usize,
+}
+
+impl<'a> EnumTracer<'a> {
+    fn record(self, variant: Variant) {
+        self.state.enums.get_mut(self.name).unwrap().variants[self.index] = Some(variant);
+    }
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for EnumTracer<'a> {
+    type Error = TraceError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), TraceError> {
+        let name = self.state.enums[self.name].names[self.index];
+        let variant = seed.deserialize(name.into_deserializer())?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for EnumTracer<'a> {
+    type Error = TraceError;
+
+    fn unit_variant(self) -> Result<(), TraceError> {
+        self.record(Variant::Unit);
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, TraceError> {
+        let mut shape = None;
+        let value = seed.deserialize(Tracer {
+            state: self.state,
+            out: &mut shape,
+        })?;
+        self.record(Variant::Newtype(traced(shape)));
+        Ok(value)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, TraceError> {
+        let mut access = SeqTracer::new(self.state, len);
+        let value = visitor.visit_seq(&mut access)?;
+        let shapes = access.shapes;
+        self.record(Variant::Tuple(shapes));
+        Ok(value)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        let mut access = MapTracer::new(self.state, MapKeys::Fields(fields));
+        let value = visitor.visit_map(&mut access)?;
+        let shapes = access.shapes;
+        let fields = fields.iter().map(|field| (*field).to_owned()).zip(shapes.into_iter().map(|(_, value)| value));
+        self.record(Variant::Struct(fields.collect()));
+        Ok(value)
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::codec::{self, Codec, CodecKind};
+use crate::port::StreamCall;
+use crate::service::dispatch::PendingStream;
+use crate::service::CallError;
+use crate::PacketHeader;
+use std::io::Cursor;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A sequence of items returned by a service method, which the exporter sends one by one.
+///
+/// The service implementation makes one out of an iterator with `Stream::new()`.
+/// The caller consumes it either as a blocking iterator or as an async stream,
+/// and dropping it before the end cancels the rest of the call.
+pub struct Stream<T> {
+    source: Source<T>,
+}
+
+enum Source<T> {
+    /// Made by the service implementation
+    Local(Box<dyn Iterator<Item = Result<T, CallError>> + Send>),
+    /// Being received from the exporter
+    Remote {
+        call: StreamCall,
+        codec: CodecKind,
+        _marker: PhantomData<fn() -> T>,
+    },
+}
+
+impl<T: Send + 'static> Stream<T> {
+    pub fn new<I>(items: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static, {
+        Self::from_results(items.into_iter().map(Ok))
+    }
+
+    /// Same as `new()`, but the items may fail. The exporter ends the stream at the first error.
+    pub fn from_results<I>(items: I) -> Self
+    where
+        I: IntoIterator<Item = Result<T, CallError>>,
+        I::IntoIter: Send + 'static, {
+        Stream {
+            source: Source::Local(Box::new(items.into_iter())),
+        }
+    }
+}
+
+impl<T> Stream<T> {
+    pub(crate) fn remote(call: StreamCall, codec: CodecKind) -> Self {
+        Stream {
+            source: Source::Remote {
+                call,
+                codec,
+                _marker: PhantomData,
+            },
+        }
+    }
+}
+
+impl<T: serde::Serialize + serde::de::DeserializeOwned + 'static> Stream<T> {
+    /// Encodes the items to be sent by the exporter. This is for the generated code.
+    #[doc(hidden)]
+    pub fn into_pending(self) -> PendingStream {
+        Box::new(self.map(|item| {
+            let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+            codec::encode(
+                {
+                    let mut c = Cursor::new(&mut buffer);
+                    c.set_position(PacketHeader::SIZE as u64);
+                    c
+                },
+                &item?,
+            )?;
+            Ok(buffer)
+        }))
+    }
+}
+
+impl<T: serde::de::DeserializeOwned> futures::Stream for Stream<T> {
+    type Item = Result<T, CallError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match &mut self.source {
+            Source::Local(items) => Poll::Ready(items.next()),
+            Source::Remote {
+                call,
+                codec,
+                ..
+            } => Pin::new(call)
+                .poll_next(cx)
+                .map(|item| item.map(|item| item.and_then(|packet| codec.decode(&packet[PacketHeader::SIZE..])))),
+        }
+    }
+}
+
+impl<T: serde::de::DeserializeOwned> Iterator for Stream<T> {
+    type Item = Result<T, CallError>;
+
+    /// Blocks the thread until the next item arrives.
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.source {
+            Source::Local(items) => items.next(),
+            Source::Remote {
+                ..
+            } => futures::executor::block_on(futures::StreamExt::next(self)),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for Stream<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.source {
+            Source::Local(_) => write!(f, "Stream(local)"),
+            Source::Remote {
+                ..
+            } => write!(f, "Stream(remote)"),
+        }
+    }
+}
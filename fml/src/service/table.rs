@@ -14,52 +14,89 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use super::Service;
-use crate::queue::Queue;
+use super::{CallError, InstanceId, Service, ServiceObjectId, TraitId, UNDECIDED_INDEX};
 use std::sync::Arc;
 
-#[cfg(debug_assertions)]
-const TIMEOUT: std::time::Duration = std::time::Duration::from_millis(1_000_000);
-#[cfg(not(debug_assertions))]
-const TIMEOUT: std::time::Duration = std::time::Duration::from_millis(50);
+struct Entry {
+    object: Option<Arc<dyn Service>>,
+    /// The trait it was exported as, which the calls are made to
+    trait_id: TraitId,
+    /// Bumped whenever the object is removed, so that the old ids don't reach the next one.
+    generation: u32,
+    nonce: u64,
+}
+
+impl Entry {
+    fn is(&self, id: ServiceObjectId) -> bool {
+        self.object.is_some() && self.generation == id.generation && self.nonce == id.nonce
+    }
+}
 
 /// Per-port worst O(1) lookup table of service objects
+///
+/// It grows when all the entries are in use, and reuses the removed ones first.
 pub struct ServiceObjectTable {
-    handles: Vec<Option<Arc<dyn Service>>>,
-    token: Queue<usize>,
+    entries: Vec<Entry>,
+    free: Vec<InstanceId>,
 }
 
 impl ServiceObjectTable {
+    /// Makes a table with room for `size` objects, which grows as needed.
     pub fn new(size: usize) -> Self {
-        let mut handles = Vec::new();
-        let token = Queue::new(size);
-        for i in 0..size {
-            handles.push(None);
-            token.push(i);
-        }
         ServiceObjectTable {
-            handles,
-            token,
+            entries: Vec::with_capacity(size),
+            free: Vec::new(),
         }
     }
 
-    pub fn create(&mut self, mut x: Arc<dyn Service>) -> Arc<dyn Service> {
-        let token = self.token.pop(Some(TIMEOUT)).expect("Too many handle service object created");
-        Arc::get_mut(&mut x).unwrap().get_handle_mut().id.index = token as u16;
-        let slot = &mut self.handles[token];
-        assert!(slot.is_none(), "ServiceObjectTable corrupted");
-        *slot = Some(x.clone());
-        x
+    /// The object may be in other tables, or in this one more than once, each time with its own id and trait.
+    pub fn create(&mut self, x: Arc<dyn Service>, trait_id: TraitId) -> ServiceObjectId {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                assert!(self.entries.len() < UNDECIDED_INDEX as usize, "Too many handle service object created");
+                self.entries.push(Entry {
+                    object: None,
+                    trait_id: 0,
+                    generation: 0,
+                    nonce: 0,
+                });
+                (self.entries.len() - 1) as InstanceId
+            }
+        };
+        let entry = &mut self.entries[index as usize];
+        assert!(entry.object.is_none(), "ServiceObjectTable corrupted");
+        entry.object = Some(x);
+        entry.trait_id = trait_id;
+        entry.nonce = rand::random();
+        ServiceObjectId {
+            index,
+            generation: entry.generation,
+            nonce: entry.nonce,
+        }
     }
 
-    pub fn remove(&mut self, token: usize) {
-        let slot = &mut self.handles[token];
-        assert!(slot.is_some(), "ServiceObjectTable corrupted");
-        slot.take();
-        self.token.push(token);
+    /// Returns the removed object, which the caller should drop after releasing the table.
+    pub fn remove(&mut self, id: ServiceObjectId) -> Result<Arc<dyn Service>, CallError> {
+        let entry = self.entry(id)?;
+        let object = entry.object.take().unwrap();
+        entry.generation = entry.generation.wrapping_add(1);
+        self.free.push(id.index);
+        Ok(object)
     }
 
-    pub fn get(&self, token: usize) -> Arc<dyn Service> {
-        self.handles[token].as_ref().unwrap().clone()
+    /// Returns the object with the trait it was exported as.
+    pub fn get(&self, id: ServiceObjectId) -> Result<(Arc<dyn Service>, TraitId), CallError> {
+        match self.entries.get(id.index as usize) {
+            Some(entry) if entry.is(id) => Ok((entry.object.clone().unwrap(), entry.trait_id)),
+            _ => Err(CallError::UnknownHandle(id)),
+        }
+    }
+
+    fn entry(&mut self, id: ServiceObjectId) -> Result<&mut Entry, CallError> {
+        match self.entries.get_mut(id.index as usize) {
+            Some(entry) if entry.is(id) => Ok(entry),
+            _ => Err(CallError::UnknownHandle(id)),
+        }
     }
 }
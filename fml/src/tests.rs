@@ -18,25 +18,39 @@ use crate as fml;
 use crate as codechain_fml;
 
 pub mod mock;
+mod port;
 mod env_mock {
     pub use super::fml::env_mock::*;
     pub use super::mock as service_context;
 }
 
 use fml::*;
+use futures::channel::oneshot;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
 use std::io::Cursor;
 use std::sync::Arc;
 
 fn distinct_handle(i: u16) -> HandleInstance {
     HandleInstance {
         id: ServiceObjectId {
-            index: i,
+            index: i as u32,
+            generation: 0,
+            nonce: 0,
         },
         port_id_exporter: i,
         port_id_importer: i,
+        returned: false,
     }
 }
 
+/// Writes after the space left for the header, as the dispatcher is given.
+fn after_header(buffer: &mut Vec<u8>) -> Cursor<&mut Vec<u8>> {
+    let mut cursor = Cursor::new(buffer);
+    cursor.set_position(PacketHeader::SIZE as u64);
+    cursor
+}
+
 #[fml_macro::service_adv(env_mock)]
 pub trait TestService: fml::Service {
     /// Make an invitation for a single visit toward itself
@@ -46,6 +60,18 @@ pub trait TestService: fml::Service {
     fn fn2(&self, a2: &u8) -> String;
 
     fn fn3(&self) -> String;
+
+    fn fn4(&self) -> Result<String, CallError>;
+
+    /// Returns the name followed by the length of `a1`
+    async fn fn5(&self, a1: Vec<u8>) -> String;
+
+    /// Does nothing
+    #[fml(oneway)]
+    fn fn6(&self, a1: String);
+
+    /// Returns the name `a1` times
+    fn fn7(&self, a1: u32) -> Stream<String>;
 }
 
 impl mock::TestDefault for SArc<dyn TestService> {
@@ -89,6 +115,330 @@ impl TestService for TestImpl {
     fn fn3(&self) -> String {
         self.name.clone()
     }
+
+    fn fn4(&self) -> Result<String, CallError> {
+        Ok(self.name.clone())
+    }
+
+    fn fn5(&self, a1: Vec<u8>) -> BoxFuture<'_, String> {
+        Box::pin(async move { format!("{}{}", self.name, a1.len()) })
+    }
+
+    fn fn6(&self, _a1: String) {}
+
+    fn fn7(&self, a1: u32) -> Stream<String> {
+        Stream::new(std::iter::repeat(self.name.clone()).take(a1 as usize))
+    }
+}
+
+#[fml_macro::service_adv(env_mock)]
+pub trait Probe: fml::Service {
+    /// Panics if `crash` is true
+    fn touch(&self, crash: bool) -> String;
+
+    /// Sleeps and then returns whether the call has a deadline
+    fn sleep(&self, millis: u64) -> String;
+
+    /// Waits until the call is cancelled, for at most 10 seconds
+    fn spin(&self) -> String;
+
+    /// Describes the arguments, and whether `cow` has been borrowed
+    fn bulk(&self, data: &[u8], text: &str, cow: std::borrow::Cow<[u8]>) -> String;
+
+    /// Waits until another call opens the gate, or opens it returning the number of calls released.
+    /// The waiting ones return whether they have a deadline.
+    async fn gate(&self, open: bool) -> String;
+
+    /// Opens the gate, like gate(true)
+    #[fml(oneway)]
+    fn open(&self);
+
+    /// Counts from 0 until `n`, and then panics if `crash` is true
+    fn count(&self, n: u32, crash: bool) -> Stream<u32>;
+}
+
+/// Calls waiting at Probe::gate()
+static GATE: OnceCell<Mutex<Vec<oneshot::Sender<()>>>> = OnceCell::new();
+
+#[fml_macro::service_impl_adv(env, Probe)]
+pub struct ProbeImpl {
+    pub handle: fml::HandleInstance,
+}
+
+#[cast_to([sync])]
+impl Probe for ProbeImpl {
+    fn touch(&self, crash: bool) -> String {
+        assert!(!crash, "Crashed as requested");
+        "Touched".to_owned()
+    }
+
+    fn sleep(&self, millis: u64) -> String {
+        std::thread::sleep(std::time::Duration::from_millis(millis));
+        format!("{}", fml::deadline::get().is_some())
+    }
+
+    fn spin(&self) -> String {
+        let start = std::time::Instant::now();
+        while !fml::cancel::is_cancelled() {
+            if start.elapsed() > std::time::Duration::from_secs(10) {
+                return "Not cancelled".to_owned()
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        "Cancelled".to_owned()
+    }
+
+    fn bulk(&self, data: &[u8], text: &str, cow: std::borrow::Cow<[u8]>) -> String {
+        let borrowed = matches!(cow, std::borrow::Cow::Borrowed(_));
+        format!("{:?} {} {:?} {}", data, text, cow, borrowed)
+    }
+
+    fn gate(&self, open: bool) -> BoxFuture<'_, String> {
+        Box::pin(async move {
+            if open {
+                let waiting: Vec<_> = GATE.get_or_init(Default::default).lock().drain(..).collect();
+                let count = waiting.len();
+                for waiter in waiting {
+                    waiter.send(()).unwrap();
+                }
+                format!("{}", count)
+            } else {
+                let (send, recv) = oneshot::channel();
+                GATE.get_or_init(Default::default).lock().push(send);
+                recv.await.unwrap();
+                format!("{}", fml::deadline::get().is_some())
+            }
+        })
+    }
+
+    fn open(&self) {
+        futures::executor::block_on(self.gate(true));
+    }
+
+    fn count(&self, n: u32, crash: bool) -> Stream<u32> {
+        let mut next = 0;
+        Stream::new(std::iter::from_fn(move || {
+            if next == n {
+                assert!(!crash, "Crashed as requested");
+                return None
+            }
+            next += 1;
+            Some(next - 1)
+        }))
+    }
+}
+
+#[fml_macro::service_adv(env)]
+pub trait Tally: fml::Service {
+    const UNIT: u32 = 10;
+
+    /// Runs where it is called, reading the tally remotely if it is imported
+    fn read_in_units(&self) -> u32 {
+        self.read() * Self::UNIT
+    }
+
+    fn read(&self) -> u32;
+}
+
+#[fml_macro::service_adv(env)]
+pub trait TallyAdmin: fml::Service {
+    /// Returns the new count
+    fn bump(&self) -> u32;
+}
+
+/// A tally served to read it, and to update it
+#[fml_macro::service_impl_adv(env, Tally, TallyAdmin)]
+pub struct TallyImpl {
+    pub handle: fml::HandleInstance,
+    pub count: std::sync::atomic::AtomicU32,
+}
+
+impl Tally for TallyImpl {
+    fn read(&self) -> u32 {
+        self.count.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+impl TallyAdmin for TallyImpl {
+    fn bump(&self) -> u32 {
+        self.count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1
+    }
+}
+
+#[fml_macro::service_adv(env)]
+pub trait Store<K, V>: fml::Service
+where
+    K: serde::Serialize + serde::de::DeserializeOwned,
+    V: serde::Serialize + serde::de::DeserializeOwned, {
+    /// Returns the previous value
+    fn put(&self, key: K, value: V) -> Option<V>;
+
+    fn get(&self, key: &K) -> Option<V>;
+}
+
+#[fml_macro::service_impl_adv(env, Store<String, u64>)]
+pub struct StoreImpl {
+    pub handle: fml::HandleInstance,
+    pub map: Mutex<std::collections::HashMap<String, u64>>,
+}
+
+impl Store<String, u64> for StoreImpl {
+    fn put(&self, key: String, value: u64) -> Option<u64> {
+        self.map.lock().insert(key, value)
+    }
+
+    fn get(&self, key: &String) -> Option<u64> {
+        self.map.lock().get(key).copied()
+    }
+}
+
+#[test]
+fn consts() {
+    fn units<T: ?Sized + Tally>(count: u32) -> u32 {
+        count * <T as TallyConsts>::UNIT
+    }
+
+    assert_eq!(<dyn Tally>::UNIT, 10);
+    assert_eq!(TallyImpl::UNIT, 10);
+    assert_eq!(units::<TallyImpl>(3), 30);
+    assert_eq!(units::<dyn Tally>(3), 30);
+}
+
+#[test]
+fn shapes() {
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Inner {
+        X,
+        Y(u8),
+    }
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Outer {
+        A,
+        B(Vec<Inner>),
+        C {
+            at: Point,
+        },
+    }
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Tree {
+        Leaf,
+        Node(Box<Tree>, Box<Tree>),
+    }
+
+    assert_eq!(
+        shape_of::<Vec<Option<(u8, String)>>>(),
+        Shape::Seq(Box::new(Shape::Option(Box::new(Shape::Tuple(vec![Shape::U8, Shape::Str])))))
+    );
+    assert_eq!(shape_of::<HashMap<String, u64>>(), Shape::Map {
+        key: Box::new(Shape::Str),
+        value: Box::new(Shape::U64)
+    });
+    let point = Shape::Struct("Point".to_owned(), vec![("x".to_owned(), Shape::I32), ("y".to_owned(), Shape::I32)]);
+    assert_eq!(shape_of::<Point>(), point);
+
+    // Enums are traced by all the variants, even those inside another variant.
+    let inner = Shape::Enum("Inner".to_owned(), vec![
+        ("X".to_owned(), Variant::Unit),
+        ("Y".to_owned(), Variant::Newtype(Shape::U8)),
+    ]);
+    assert_eq!(
+        shape_of::<Outer>(),
+        Shape::Enum("Outer".to_owned(), vec![
+            ("A".to_owned(), Variant::Unit),
+            ("B".to_owned(), Variant::Newtype(Shape::Seq(Box::new(inner)))),
+            ("C".to_owned(), Variant::Struct(vec![("at".to_owned(), point)])),
+        ])
+    );
+
+    // A service is a handle, which isn't imported while tracing.
+    assert_eq!(shape_of::<SArc<dyn TestService>>(), Shape::Handle("TestService".to_owned()));
+    assert_eq!(
+        shape_of::<Option<SArc<dyn TestService>>>(),
+        Shape::Option(Box::new(Shape::Handle("TestService".to_owned())))
+    );
+
+    // These can't be traced.
+    assert_eq!(shape_of::<Tree>(), Shape::Opaque("Tree".to_owned()));
+    assert_eq!(shape_of::<serde_json::Value>(), Shape::Opaque("Value".to_owned()));
+
+    // Borrowed arguments are sent as owned ones, and a stream by its items.
+    let services = schema().services;
+    let probe = services.iter().find(|service| service.name == "Probe").unwrap();
+    let bulk = probe.methods.iter().find(|method| method.name == "bulk").unwrap();
+    let shapes: Vec<&Shape> = bulk.arguments.iter().map(|argument| &argument.shape).collect();
+    let bytes = Shape::Seq(Box::new(Shape::U8));
+    assert_eq!(shapes, vec![&bytes, &Shape::Str, &bytes]);
+    let count = probe.methods.iter().find(|method| method.name == "count").unwrap();
+    assert_eq!(count.output_shape, Some(Shape::U32));
+    let open = probe.methods.iter().find(|method| method.name == "open").unwrap();
+    assert_eq!(open.output_shape, None);
+    let store = services.iter().find(|service| service.name == "Store").unwrap();
+    assert_eq!(store.methods[0].arguments[0].shape, Shape::Opaque("K".to_owned()));
+
+    let json = serde_json::to_string(&schema()).unwrap();
+    assert_eq!(serde_json::from_str::<Schema>(&json).unwrap(), schema());
+}
+
+#[test]
+fn fingerprints() {
+    use crate::service::id::fingerprint;
+    use serde::Deserialize;
+
+    mod old {
+        #[derive(serde::Deserialize)]
+        #[allow(dead_code)]
+        pub struct Foo {
+            pub a: u32,
+        }
+    }
+    mod new {
+        #[derive(serde::Deserialize)]
+        #[allow(dead_code)]
+        pub struct Foo {
+            pub a: u32,
+            pub b: String,
+        }
+    }
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Bytes(Vec<u8>);
+    type Alias = Vec<u8>;
+
+    let sync = |arguments: &[Shape], output: Shape| fingerprint(false, false, false, arguments, Some(output));
+    let f = sync(&[shape_of::<Vec<u8>>(), shape_of::<String>()], Shape::U32);
+    // How the types are written doesn't matter.
+    assert_eq!(f, sync(&[shape_of::<std::vec::Vec<u8>>(), shape_of::<std::string::String>()], Shape::U32));
+    assert_eq!(f, sync(&[shape_of::<Alias>(), shape_of::<String>()], Shape::U32));
+    assert_ne!(f, sync(&[shape_of::<Vec<u16>>(), shape_of::<String>()], Shape::U32));
+    assert_ne!(f, sync(&[shape_of::<String>(), shape_of::<Vec<u8>>()], Shape::U32));
+    assert_ne!(f, sync(&[shape_of::<Bytes>(), shape_of::<String>()], Shape::U32));
+    assert_ne!(f, sync(&[shape_of::<Vec<u8>>(), shape_of::<String>()], Shape::U64));
+    // What a struct is made of does.
+    assert_ne!(sync(&[shape_of::<old::Foo>()], Shape::Unit), sync(&[shape_of::<new::Foo>()], Shape::Unit));
+
+    // So does how the method is called.
+    let arguments = [shape_of::<u32>()];
+    let kinds = [
+        fingerprint(false, false, false, &arguments, None),
+        fingerprint(true, false, false, &arguments, None),
+        fingerprint(false, true, false, &arguments, None),
+        fingerprint(false, false, true, &arguments, None),
+    ];
+    for (i, a) in kinds.iter().enumerate() {
+        assert!(kinds[i + 1..].iter().all(|b| a != b));
+    }
 }
 
 // We enclose the tests so that we can test that te code generated by #[service]
@@ -123,38 +473,36 @@ mod use_cast {
         let si = <dyn TestService as env_mock::ImportService<dyn TestService>>::import(distinct_handle(1234));
         si.fn1("s1".to_owned(), "s2", &[3]);
         {
-            let (op, handle, method, (a1, a2, a3)): (String, HandleInstance, MethodId, (String, String, Vec<u8>)) =
-                serde_cbor::from_slice(&mock::pop_log()).unwrap();
+            let log = mock::pop_log();
+            // Bytes are serialized as such, not as a sequence.
+            let (op, handle, method, (a1, a2, a3)): (
+                String,
+                HandleInstance,
+                MethodId,
+                (String, String, codec::BorrowedBytes),
+            ) = serde_cbor::from_slice(&log).unwrap();
             assert_eq!(op, "call");
             assert_eq!(handle, distinct_handle(1234));
             // This number '7' is very specific to macro implementation.
             assert_eq!(method, 7);
             assert_eq!(a1, "s1");
             assert_eq!(a2, "s2");
-            assert_eq!(a3, &[3]);
+            assert_eq!(&*a3.0, &[3]);
         }
 
         let se: Arc<dyn TestService> = Arc::new(TestImpl {
             handle: distinct_handle(2345),
             name: "Hi".to_owned(),
         });
-        let mut buffer: Vec<u8> = vec![0; std::mem::size_of::<PacketHeader>()];
-        let cursor = {
-            let mut c = Cursor::new(&mut buffer);
-            c.set_position(std::mem::size_of::<PacketHeader>() as u64);
-            c
-        };
-        let mut args: Vec<u8> = vec![0; std::mem::size_of::<PacketHeader>()];
-        let cursor2 = {
-            let mut c = Cursor::new(&mut args);
-            c.set_position(std::mem::size_of::<PacketHeader>() as u64);
-            c
-        };
+        let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+        let cursor = after_header(&mut buffer);
+        let mut args: Vec<u8> = vec![0; PacketHeader::SIZE];
+        let cursor2 = after_header(&mut args);
         serde_cbor::to_writer(cursor2, &("s1", "s2", &[3])).unwrap();
 
-        service_dispatch!(TestService, &*se, 7, &args, cursor);
+        service_dispatch!(TestService, &*se, 7, &args, cursor).unwrap();
         {
-            let _: HandleInstance = serde_cbor::from_slice(&buffer[std::mem::size_of::<PacketHeader>()..]).unwrap();
+            let _: HandleInstance = serde_cbor::from_slice(&buffer[PacketHeader::SIZE..]).unwrap();
             let newly_exported: Arc<dyn TestService> = mock::pop_service_log().cast::<dyn TestService>().unwrap();
             assert_eq!(newly_exported.fn3(), "s1s21");
             let (op, port_id): (String, PortId) = serde_cbor::from_slice(&mock::pop_log()).unwrap();
@@ -169,4 +517,156 @@ mod use_cast {
             assert_eq!(handle, distinct_handle(1234));
         }
     }
+    #[test]
+    fn service_3() {
+        mock::set_key(3);
+
+        let si = <dyn TestService as env_mock::ImportService<dyn TestService>>::import(distinct_handle(1234));
+        // The mock call always fails, and that failure must be delivered as the declared error.
+        assert_eq!(si.fn4(), Err(CallError::PeerGone));
+        {
+            let (op, handle, method, ()): (String, HandleInstance, MethodId, ()) =
+                serde_cbor::from_slice(&mock::pop_log()).unwrap();
+            assert_eq!(op, "try_call");
+            assert_eq!(handle, distinct_handle(1234));
+            assert_eq!(method, 10);
+        }
+
+        let se: Arc<dyn TestService> = Arc::new(TestImpl {
+            handle: distinct_handle(2345),
+            name: "Hi".to_owned(),
+        });
+        let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+        let cursor = after_header(&mut buffer);
+        let args: Vec<u8> = vec![0; PacketHeader::SIZE];
+        assert_eq!(service_dispatch!(TestService, &*se, 1, &args, cursor), Err(CallError::UnknownMethod(1)));
+    }
+
+    #[test]
+    fn service_4() {
+        mock::set_key(4);
+
+        let si = <dyn TestService as env_mock::ImportService<dyn TestService>>::import(distinct_handle(1234));
+        let result = si.fn5(vec![1, 2]);
+        // The call is made before the future is polled.
+        {
+            let (op, handle, method, (a1,)): (String, HandleInstance, MethodId, (Vec<u8>,)) =
+                serde_cbor::from_slice(&mock::pop_log()).unwrap();
+            assert_eq!(op, "call_async");
+            assert_eq!(handle, distinct_handle(1234));
+            assert_eq!(method, 11);
+            assert_eq!(a1, vec![1, 2]);
+        }
+        assert_eq!(futures::executor::block_on(result), "Default");
+
+        let se: Arc<dyn TestService> = Arc::new(TestImpl {
+            handle: distinct_handle(2345),
+            name: "Hi".to_owned(),
+        });
+        let mut args: Vec<u8> = vec![0; PacketHeader::SIZE];
+        serde_cbor::to_writer(&mut args, &(vec![1, 2, 3],)).unwrap();
+        // Dispatched as a future,
+        let pending =
+            <dyn TestService as env_mock::DispatchService<dyn TestService>>::dispatch_async(se.clone(), 11, &args);
+        let buffer = futures::executor::block_on(pending.unwrap().unwrap()).unwrap();
+        assert_eq!(serde_cbor::from_slice::<String>(&buffer[PacketHeader::SIZE..]).unwrap(), "Hi3");
+        // or directly, blocking on it.
+        let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+        let cursor = after_header(&mut buffer);
+        service_dispatch!(TestService, &*se, 11, &args, cursor).unwrap();
+        assert_eq!(serde_cbor::from_slice::<String>(&buffer[PacketHeader::SIZE..]).unwrap(), "Hi3");
+        // Other methods are left to dispatch().
+        assert!(<dyn TestService as env_mock::DispatchService<dyn TestService>>::dispatch_async(se, 9, &args)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn service_5() {
+        mock::set_key(5);
+
+        let si = <dyn TestService as env_mock::ImportService<dyn TestService>>::import(distinct_handle(1234));
+        si.fn6("s1".to_owned());
+        let (op, handle, method, (a1,)): (String, HandleInstance, MethodId, (String,)) =
+            serde_cbor::from_slice(&mock::pop_log()).unwrap();
+        assert_eq!(op, "call_oneway");
+        assert_eq!(handle, distinct_handle(1234));
+        assert_eq!(method, 12);
+        assert_eq!(a1, "s1");
+    }
+
+    #[test]
+    fn service_6() {
+        mock::set_key(6);
+
+        let si = <dyn TestService as env_mock::ImportService<dyn TestService>>::import(distinct_handle(1234));
+        assert_eq!(si.fn7(3).count(), 0);
+        {
+            let (op, handle, method, (a1,)): (String, HandleInstance, MethodId, (u32,)) =
+                serde_cbor::from_slice(&mock::pop_log()).unwrap();
+            assert_eq!(op, "call_stream");
+            assert_eq!(handle, distinct_handle(1234));
+            assert_eq!(method, 13);
+            assert_eq!(a1, 3);
+        }
+
+        let se: Arc<dyn TestService> = Arc::new(TestImpl {
+            handle: distinct_handle(2345),
+            name: "Hi".to_owned(),
+        });
+        let mut args: Vec<u8> = vec![0; PacketHeader::SIZE];
+        serde_cbor::to_writer(&mut args, &(2,)).unwrap();
+        // Each item is encoded on its own.
+        let items = <dyn TestService as env_mock::DispatchService<dyn TestService>>::dispatch_stream(&*se, 13, &args);
+        let items: Vec<String> = items
+            .unwrap()
+            .unwrap()
+            .map(|item| serde_cbor::from_slice(&item.unwrap()[PacketHeader::SIZE..]).unwrap())
+            .collect();
+        assert_eq!(items, vec!["Hi", "Hi"]);
+        // A stream can't be dispatched directly, and the other methods are left to dispatch().
+        let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+        let cursor = after_header(&mut buffer);
+        assert_eq!(service_dispatch!(TestService, &*se, 13, &args, cursor), Err(CallError::UnknownMethod(13)));
+        assert!(<dyn TestService as env_mock::DispatchService<dyn TestService>>::dispatch_stream(&*se, 8, &args)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn service_7() {
+        mock::set_key(7);
+        fml::port::server::port_thread_local::set_key(777);
+
+        // An imported handle is exported again through a forwarder,
+        let si = <dyn TestService as env_mock::ImportService<dyn TestService>>::import(distinct_handle(1234));
+        serde_cbor::to_vec(&SArc::new(si)).unwrap();
+        let (op, port_id): (String, PortId) = serde_cbor::from_slice(&mock::pop_log()).unwrap();
+        assert_eq!(op, "register");
+        assert_eq!(port_id, 777);
+        let forwarder = mock::pop_service_log();
+        assert_eq!(forwarder.get_trait_id(), service_id!(TestService));
+
+        // which makes the calls to the imported one instead.
+        let mut args: Vec<u8> = vec![0; PacketHeader::SIZE];
+        serde_cbor::to_writer(&mut args, &(3,)).unwrap();
+        let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+        let cursor = after_header(&mut buffer);
+        forwarder.dispatch(service_id!(TestService), 8, &args, cursor).unwrap();
+        assert_eq!(serde_cbor::from_slice::<String>(&buffer[PacketHeader::SIZE..]).unwrap(), "Default");
+        {
+            let (op, handle, method, (a1,)): (String, HandleInstance, MethodId, (u8,)) =
+                serde_cbor::from_slice(&mock::pop_log()).unwrap();
+            assert_eq!(op, "call");
+            assert_eq!(handle, distinct_handle(1234));
+            assert_eq!(method, 8);
+            assert_eq!(a1, 3);
+        }
+
+        // Dropping the forwarder drops the imported one.
+        drop(forwarder);
+        let (op, handle): (String, HandleInstance) = serde_cbor::from_slice(&mock::pop_log()).unwrap();
+        assert_eq!(op, "delete");
+        assert_eq!(handle, distinct_handle(1234));
+    }
 }
@@ -0,0 +1,877 @@
+// Copyright 2020 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Tests over actual ports, using the real dispatcher and call path.
+
+use super::*;
+use crate::port::{hello_packet, supported_codecs, Hello};
+use crate::service::id::fingerprints;
+use cbsb::ipc::{intra::Intra, Ipc, IpcRecv, IpcSend};
+use codec::Codec;
+use once_cell::sync::OnceCell;
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// This number '7' is very specific to macro implementation.
+const METHOD_TOUCH: MethodId = 7;
+const METHOD_SLEEP: MethodId = 8;
+const METHOD_SPIN: MethodId = 9;
+const METHOD_BULK: MethodId = 10;
+const METHOD_GATE: MethodId = 11;
+const METHOD_OPEN: MethodId = 12;
+const METHOD_COUNT: MethodId = 13;
+
+fn config(
+    server_threads: usize,
+    call_slots: usize,
+    codec: CodecKind,
+    chunk_size: usize,
+    max_message_size: usize,
+) -> FmlConfig {
+    FmlConfig {
+        server_threads,
+        call_slots,
+        codec,
+        chunk_size,
+        max_message_size,
+    }
+}
+
+fn create_port(id: PortId, ipc_config: Vec<u8>, config: &FmlConfig) -> Port {
+    let (send, recv) = Intra::new(ipc_config).split();
+    Port::new(send, recv, id, Arc::new(PortDispatcher::new(id, 8)), 1, config).unwrap()
+}
+
+/// Sets up a module which talks to itself with two ports connected to each other.
+/// Port 1 exports a Probe, which can be called through port 0.
+fn with_probe<F: FnOnce(&HandleInstance)>(key: InstanceKey, config: FmlConfig, f: F) {
+    // The global context is shared by all tests unless it is the single_process mode.
+    static LOCK: OnceCell<Mutex<()>> = OnceCell::new();
+    let _guard = LOCK.get_or_init(Default::default).lock();
+
+    set_key(key);
+    // Trait ids are given by the coordinator, and they must differ to tell the traits apart.
+    for (i, (_, setter)) in crate::service::id::TID_REG.iter().enumerate() {
+        setter(i as TraitId);
+    }
+    let (ipc_config_a, ipc_config_b) = Intra::arguments_for_both_ends();
+    // Both ends must be linked at the same time for the handshake.
+    let config_ = config.clone();
+    let port_b = std::thread::spawn(move || create_port(1, ipc_config_b, &config_));
+    let mut map = HashMap::new();
+    map.insert(0, ("B".to_owned(), 1, create_port(0, ipc_config_a, &config)));
+    map.insert(1, ("A".to_owned(), 0, port_b.join().unwrap()));
+    global::set(RwLock::new(PortTable {
+        config_fml: config,
+        map,
+        names: Default::default(),
+    }));
+    // Imported services read this when they are dropped.
+    termination::set(Default::default());
+
+    let handle = env::service_context::register(
+        1,
+        Arc::new(ProbeImpl {
+            handle: Default::default(),
+        }),
+    );
+    f(&handle);
+    termination::remove();
+    global::remove();
+}
+
+fn call(handle: &HandleInstance, method: MethodId, args: &impl serde::Serialize) -> Result<String, CallError> {
+    let port_table = global::get().read();
+    let port = &port_table.map.get(&0).unwrap().2;
+    let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+    port.codec().encode(&mut buffer, args).unwrap();
+    let result = port.call(handle.id, method, buffer, deadline::get(), cancel::current().as_ref())?;
+    Ok(port.codec().decode(&result[PacketHeader::SIZE..]).unwrap())
+}
+
+fn call_stream(handle: &HandleInstance, args: &impl serde::Serialize) -> Stream<u32> {
+    let port_table = global::get().read();
+    let port = &port_table.map.get(&0).unwrap().2;
+    let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+    port.codec().encode(&mut buffer, args).unwrap();
+    let call = port.call_stream(handle.id, METHOD_COUNT, buffer, deadline::get(), cancel::current());
+    Stream::remote(call, port.codec())
+}
+
+fn call_async(
+    handle: &HandleInstance,
+    method: MethodId,
+    args: &impl serde::Serialize,
+) -> impl std::future::Future<Output = Result<String, CallError>> {
+    let port_table = global::get().read();
+    let port = &port_table.map.get(&0).unwrap().2;
+    let codec = port.codec();
+    let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+    codec.encode(&mut buffer, args).unwrap();
+    let call = port.call_async(handle.id, method, buffer, deadline::get(), cancel::current());
+    async move { Ok(codec.decode(&call.await?[PacketHeader::SIZE..]).unwrap()) }
+}
+
+#[test]
+fn version_mismatch() {
+    let (ipc_config_a, ipc_config_b) = Intra::arguments_for_both_ends();
+    let config = config(1, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    let (send, recv) = Intra::new(ipc_config_b).split();
+    // Pretend to be a peer from the future
+    let hello = Hello {
+        codecs: supported_codecs(CodecKind::Cbor),
+        methods: fingerprints(),
+    };
+    let mut hello = hello_packet(&hello);
+    hello[3] = PROTOCOL_VERSION + 1;
+    send.send(&hello);
+
+    let (send_a, recv_a) = Intra::new(ipc_config_a).split();
+    let result = Port::new(send_a, recv_a, 0, Arc::new(PortDispatcher::new(0, 8)), 1, &config);
+    match result {
+        Err(ProtocolError::VersionMismatch {
+            ours,
+            theirs,
+        }) => {
+            assert_eq!(ours, PROTOCOL_VERSION);
+            assert_eq!(theirs, PROTOCOL_VERSION + 1);
+        }
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Linked with a wrong peer"),
+    }
+    // It still said hello to the peer.
+    let hello = recv.recv(Some(Duration::from_secs(1))).unwrap();
+    assert_eq!(PacketHeader::read(&hello).unwrap().flags, 0b100);
+}
+
+#[test]
+fn invalid_packet() {
+    let (ipc_config_a, ipc_config_b) = Intra::arguments_for_both_ends();
+    let config = config(1, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    // Play the peer by hand
+    let (send, recv) = Intra::new(ipc_config_b).split();
+    let hello = Hello {
+        codecs: supported_codecs(CodecKind::Cbor),
+        methods: fingerprints(),
+    };
+    send.send(&hello_packet(&hello));
+    let (send_a, recv_a) = Intra::new(ipc_config_a).split();
+    let port = Port::new(send_a, recv_a, 0, Arc::new(PortDispatcher::new(0, 8)), 1, &config).unwrap();
+    recv.recv(Some(Duration::from_secs(1))).unwrap();
+
+    let handle = ServiceObjectId {
+        index: 1,
+        generation: 0,
+        nonce: 0,
+    };
+    let caller = std::thread::spawn(move || {
+        let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+        CodecKind::Cbor.encode(&mut buffer, &()).unwrap();
+        let result = port.call(handle, METHOD_TOUCH, buffer, None, None).unwrap();
+        CodecKind::Cbor.decode::<String>(&result[PacketHeader::SIZE..]).unwrap()
+    });
+    let call = recv.recv(Some(Duration::from_secs(1))).unwrap();
+    let mut header = PacketHeader::read(&call).unwrap();
+
+    // None of these must take the port down.
+    send.send(&[1, 2, 3]);
+    send.send(&vec![0; PacketHeader::SIZE]);
+    let mut response = vec![0; PacketHeader::SIZE];
+    CodecKind::Cbor.encode(&mut response, &"Wrong").unwrap();
+    header.flags = 0;
+    header.write(&mut response);
+    response.push(0);
+    send.send(&response);
+
+    let mut response = vec![0; PacketHeader::SIZE];
+    CodecKind::Cbor.encode(&mut response, &"Right").unwrap();
+    header.write(&mut response);
+    send.send(&response);
+    assert_eq!(caller.join().unwrap(), "Right");
+}
+
+#[test]
+fn codec_negotiation() {
+    let hello = crate::port::supported_codecs;
+    for &a in CodecKind::ALL.iter() {
+        for &b in CodecKind::ALL.iter() {
+            let chosen = crate::port::choose_codec(a, &hello(b)).unwrap();
+            assert_eq!(chosen, crate::port::choose_codec(b, &hello(a)).unwrap());
+            assert_eq!(chosen, std::cmp::min(a, b));
+        }
+    }
+    // A peer that knows only something else
+    assert_eq!(crate::port::choose_codec(CodecKind::Cbor, &[200]), Err(ProtocolError::NoCommonCodec));
+    // A peer that prefers something we don't know, but still supports ours
+    assert_eq!(crate::port::choose_codec(CodecKind::Json, &[200, CodecKind::Json.to_byte()]), Ok(CodecKind::Json));
+}
+
+#[test]
+fn signature_mismatch() {
+    let (ipc_config_a, ipc_config_b) = Intra::arguments_for_both_ends();
+    let config = config(1, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    // Pretend to be a peer built with another definition of Probe::touch()
+    let mut methods = fingerprints();
+    let touch = methods.iter_mut().find(|(trait_name, method_name, _)| trait_name == "Probe" && method_name == "touch");
+    touch.unwrap().2 += 1;
+    // and a method that we don't know, which is fine.
+    methods.push(("Probe".to_owned(), "unknown".to_owned(), 0));
+    let (send, _recv) = Intra::new(ipc_config_b).split();
+    send.send(&hello_packet(&Hello {
+        codecs: supported_codecs(CodecKind::Cbor),
+        methods,
+    }));
+
+    let (send_a, recv_a) = Intra::new(ipc_config_a).split();
+    let result = Port::new(send_a, recv_a, 0, Arc::new(PortDispatcher::new(0, 8)), 1, &config);
+    match result {
+        Err(ProtocolError::SignatureMismatch(methods)) => assert_eq!(methods, vec!["Probe::touch".to_owned()]),
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Linked with a wrong peer"),
+    }
+}
+
+#[test]
+fn remote_panic() {
+    let config = config(1, 1, CodecKind::Json, 1024, 1024 * 1024);
+    with_probe(1, config, |handle| {
+        match call(handle, METHOD_TOUCH, &(true,)) {
+            Err(CallError::RemotePanic(message)) => assert!(message.contains("Crashed as requested")),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        // There is only one service handler, which must have survived.
+        assert_eq!(call(handle, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+        assert_eq!(call(handle, 1_234_567, &(false,)), Err(CallError::UnknownMethod(1_234_567)));
+    });
+}
+
+#[test]
+fn deadline() {
+    let config = config(2, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    with_probe(2, config, |handle| {
+        assert_eq!(call(handle, METHOD_SLEEP, &(0,)).unwrap(), "false");
+        // The service sees the deadline given by the caller.
+        let result = deadline::with_timeout(Duration::from_secs(100), || call(handle, METHOD_SLEEP, &(0,)));
+        assert_eq!(result.unwrap(), "true");
+
+        let result = deadline::with_timeout(Duration::from_millis(10), || call(handle, METHOD_SLEEP, &(300,)));
+        assert_eq!(result, Err(CallError::Timeout));
+        // The only slot becomes available again after the late response, which must not be taken as this one's.
+        assert_eq!(call(handle, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+    });
+}
+
+#[test]
+fn cancellation() {
+    let config = config(1, 1, CodecKind::Bincode, 1024, 1024 * 1024);
+    with_probe(3, config, |handle| {
+        let start = Instant::now();
+        let token = cancel::CancelToken::new();
+        let token_ = token.clone();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            token_.cancel();
+        });
+        let result = cancel::with_token(&token, || call(handle, METHOD_SPIN, &()));
+        assert_eq!(result, Err(CallError::Cancelled));
+        canceller.join().unwrap();
+        assert_eq!(cancel::with_token(&token, || call(handle, METHOD_TOUCH, &(false,))), Err(CallError::Cancelled));
+
+        // The only handler must have stopped spinning, and the only slot must be available again.
+        assert_eq!(call(handle, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    });
+}
+
+#[test]
+fn borrowed_arguments() {
+    let config = config(1, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    with_probe(4, config, |handle| {
+        use codec::AsBytes;
+        let result = call(handle, METHOD_BULK, &(AsBytes(&[1, 2]), "Hello", AsBytes(&[3]))).unwrap();
+        assert_eq!(result, "[1, 2] Hello [3] true");
+    });
+}
+
+#[test]
+fn large_message() {
+    let config = config(1, 2, CodecKind::Cbor, 16, 1024);
+    with_probe(5, config, |handle| {
+        use codec::AsBytes;
+        let data: Vec<u8> = (0..100).collect();
+        let result = call(handle, METHOD_BULK, &(AsBytes(&data), "Hello", AsBytes(&[3]))).unwrap();
+        assert_eq!(result, format!("{:?} Hello [3] true", data));
+
+        // The result is too large.
+        let data = vec![100; 500];
+        match call(handle, METHOD_BULK, &(AsBytes(&data), "Hello", AsBytes(&[3]))) {
+            Err(CallError::TooLarge {
+                size,
+                limit: 1024,
+            }) => assert!(size > 1024),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        // The arguments are too large.
+        let data = vec![100; 2000];
+        match call(handle, METHOD_BULK, &(AsBytes(&data), "Hello", AsBytes(&[3]))) {
+            Err(CallError::TooLarge {
+                size,
+                limit: 1024,
+            }) => assert!(size > 2000),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(call(handle, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+    });
+}
+
+#[test]
+fn async_method() {
+    let config = config(1, 4, CodecKind::Cbor, 1024, 1024 * 1024);
+    with_probe(6, config, |handle| {
+        use futures::executor::block_on;
+        use futures::future::join_all;
+
+        let open_gate = |count: usize| {
+            let mut released = 0;
+            while released < count {
+                released += call(handle, METHOD_GATE, &(true,)).unwrap().parse::<usize>().unwrap();
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        };
+
+        let mut calls: Vec<_> = (0..2).map(|_| call_async(handle, METHOD_GATE, &(false,))).collect();
+        calls.push(deadline::with_timeout(Duration::from_secs(100), || call_async(handle, METHOD_GATE, &(false,))));
+        let waiting = std::thread::spawn(move || block_on(join_all(calls)));
+        // They don't hold the only handler while waiting, so it can serve the one that opens the gate.
+        open_gate(3);
+        let mut results: Vec<String> = waiting.join().unwrap().into_iter().map(Result::unwrap).collect();
+        results.sort();
+        // The deadline is visible in the async method too.
+        assert_eq!(results, vec!["false", "false", "true"]);
+
+        // Cancelled while waiting at the gate
+        let token = cancel::CancelToken::new();
+        let waiting = cancel::with_token(&token, || call_async(handle, METHOD_GATE, &(false,)));
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        });
+        assert_eq!(block_on(waiting), Err(CallError::Cancelled));
+        canceller.join().unwrap();
+        // The slots are all available again, once the abandoned one gets its response.
+        open_gate(1);
+        let calls: Vec<_> = (0..4).map(|_| call_async(handle, METHOD_TOUCH, &(false,))).collect();
+        for result in block_on(join_all(calls)) {
+            assert_eq!(result.unwrap(), "Touched");
+        }
+    });
+}
+
+#[test]
+fn oneway_method() {
+    let config = config(1, 1, CodecKind::Cbor, 16, 1024);
+    with_probe(7, config, |handle| {
+        // This takes the only slot until the gate opens.
+        let waiting = call_async(handle, METHOD_GATE, &(false,));
+        let (send, recv) = crossbeam::channel::bounded(1);
+        let waiting = std::thread::spawn(move || send.send(futures::executor::block_on(waiting)).unwrap());
+
+        let oneway = |method: MethodId, args: &[u8]| {
+            let port_table = global::get().read();
+            let port = &port_table.map.get(&0).unwrap().2;
+            let mut buffer: Vec<u8> = vec![0; PacketHeader::SIZE];
+            buffer.extend_from_slice(args);
+            port.call_oneway(handle.id, method, buffer, None)
+        };
+        // One-way calls don't need a slot, and their failures are never reported.
+        oneway(METHOD_TOUCH, &serde_cbor::to_vec(&(true,)).unwrap()).unwrap();
+        oneway(1_234_567, &serde_cbor::to_vec(&()).unwrap()).unwrap();
+        // Large ones are fragmented.
+        oneway(METHOD_BULK, &serde_cbor::to_vec(&(codec::AsBytes(&[0; 100]), "Hello", codec::AsBytes(&[3]))).unwrap())
+            .unwrap();
+        match oneway(
+            METHOD_BULK,
+            &serde_cbor::to_vec(&(codec::AsBytes(&[0; 2000]), "Hello", codec::AsBytes(&[3]))).unwrap(),
+        ) {
+            Err(CallError::TooLarge {
+                limit: 1024,
+                ..
+            }) => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        let result = loop {
+            oneway(METHOD_OPEN, &serde_cbor::to_vec(&()).unwrap()).unwrap();
+            if let Ok(result) = recv.recv_timeout(Duration::from_millis(10)) {
+                break result
+            }
+        };
+        assert_eq!(result.unwrap(), "false");
+        waiting.join().unwrap();
+        assert_eq!(call(handle, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+    });
+}
+
+#[test]
+fn batch() {
+    let config = config(1, 1, CodecKind::Cbor, 16, 1024);
+    with_probe(8, config, |handle| {
+        // Only the handle matters to the batch, and this one doesn't delete the service when dropped.
+        let probe = ProbeImpl {
+            handle: HandleInstance {
+                id: handle.id,
+                port_id_exporter: handle.port_id_exporter,
+                port_id_importer: handle.port_id_importer,
+                returned: false,
+            },
+        };
+        let mut batch = Batch::new();
+        let mut probe_batch = ProbeBatch::new(&mut batch, &probe);
+        let touched = probe_batch.touch(false).unwrap();
+        let crashed = probe_batch.touch(true).unwrap();
+        let bulk = probe_batch.bulk(&[1, 2], "Hello", std::borrow::Cow::Borrowed(&[3])).unwrap();
+        let opened = probe_batch.gate(true).unwrap();
+        let unknown: Queued<String> = batch.queue(handle, 1_234_567, &()).unwrap();
+        assert_eq!(batch.len(), 5);
+
+        // Each call in the batch succeeds or fails on its own.
+        let mut results = batch.send().unwrap();
+        assert_eq!(results.take(touched).unwrap(), "Touched");
+        match results.take(crashed) {
+            Err(CallError::RemotePanic(message)) => assert!(message.contains("Crashed as requested")),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        assert_eq!(results.take(bulk).unwrap(), "[1, 2] Hello [3] true");
+        assert_eq!(results.take(opened).unwrap(), "0");
+        assert_eq!(results.take(unknown), Err(CallError::UnknownMethod(1_234_567)));
+
+        // Calls through another port can't join it.
+        let mut batch = Batch::new();
+        let _: Queued<String> = batch.queue(handle, METHOD_TOUCH, &(false,)).unwrap();
+        let elsewhere = HandleInstance {
+            id: handle.id,
+            port_id_exporter: 0,
+            port_id_importer: 1,
+            returned: false,
+        };
+        let queued: Result<Queued<String>, CallError> = batch.queue(&elsewhere, METHOD_TOUCH, &(false,));
+        assert_eq!(
+            queued.err(),
+            Some(CallError::MixedPorts {
+                batch: 0,
+                call: 1,
+            })
+        );
+        assert_eq!(batch.len(), 1);
+
+        // A batch takes only one slot, which must be available again.
+        assert_eq!(call(handle, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+    });
+}
+
+#[test]
+fn stream() {
+    let config = config(1, 1, CodecKind::Cbor, 16, 1024);
+    with_probe(9, config, |handle| {
+        // Far more than the window, so the exporter has to wait for the credits.
+        let items: Result<Vec<u32>, CallError> = call_stream(handle, &(100, false)).collect();
+        assert_eq!(items.unwrap(), (0..100).collect::<Vec<_>>());
+        let items: Vec<_> = futures::executor::block_on(futures::StreamExt::collect(call_stream(handle, &(3, false))));
+        assert_eq!(items, vec![Ok(0), Ok(1), Ok(2)]);
+
+        let items: Vec<_> = call_stream(handle, &(2, true)).collect();
+        match &items[..] {
+            [Ok(0), Ok(1), Err(CallError::RemotePanic(message))] => assert!(message.contains("Crashed as requested")),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+
+        // Dropping it early cancels the rest, and the exporter waiting for credits must give up the slot.
+        let mut items = call_stream(handle, &(1_000_000, false));
+        assert_eq!(items.next(), Some(Ok(0)));
+        drop(items);
+        assert_eq!(call(handle, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+
+        // The exporter stops at the deadline while the caller is not consuming.
+        let mut items = deadline::with_timeout(Duration::from_millis(100), || call_stream(handle, &(1_000_000, false)));
+        assert_eq!(items.next(), Some(Ok(0)));
+        std::thread::sleep(Duration::from_millis(300));
+        let items: Vec<_> = items.collect();
+        assert!(items.len() <= 16);
+        assert_eq!(items.last(), Some(&Err(CallError::Timeout)));
+        assert_eq!(call(handle, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+    });
+}
+
+#[test]
+fn export_twice() {
+    let config = config(1, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    with_probe(10, config, |_| {
+        // The exporter keeps the object, and exports it twice over the same port.
+        let probe = Arc::new(ProbeImpl {
+            handle: Default::default(),
+        });
+        let handle_a = env::service_context::register(1, probe.clone());
+        let handle_b = env::service_context::register(1, probe.clone());
+        assert_ne!(handle_a.id, handle_b.id);
+        assert_eq!(Arc::strong_count(&probe), 3);
+
+        assert_eq!(call(&handle_a, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+        assert_eq!(call(&handle_b, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+
+        // Deleting one export leaves the other.
+        crate::service::dispatch::delete(1, handle_a.id).unwrap();
+        assert_eq!(Arc::strong_count(&probe), 2);
+        assert_eq!(call(&handle_b, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+        crate::service::dispatch::delete(1, handle_b.id).unwrap();
+        assert_eq!(Arc::strong_count(&probe), 1);
+    });
+}
+
+#[test]
+fn stale_handle() {
+    let config = config(1, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    with_probe(11, config, |handle| {
+        // The table has room for 8 objects at first, and grows.
+        let handles: Vec<_> = (0..20)
+            .map(|_| {
+                env::service_context::register(
+                    1,
+                    Arc::new(ProbeImpl {
+                        handle: Default::default(),
+                    }),
+                )
+            })
+            .collect();
+        for handle in &handles {
+            assert_eq!(call(handle, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+        }
+
+        // The slot of a deleted object is reused, but its old handle doesn't reach the new one.
+        let stale = handles[3].id;
+        crate::service::dispatch::delete(1, stale).unwrap();
+        let reused = env::service_context::register(
+            1,
+            Arc::new(ProbeImpl {
+                handle: Default::default(),
+            }),
+        );
+        assert_eq!(reused.id.index, stale.index);
+        assert_eq!(call(&reused, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+        let stale_handle = HandleInstance {
+            id: stale,
+            port_id_exporter: 1,
+            port_id_importer: 0,
+            returned: false,
+        };
+        assert_eq!(call(&stale_handle, METHOD_TOUCH, &(false,)), Err(CallError::UnknownHandle(stale)));
+        assert_eq!(crate::service::dispatch::delete(1, stale), Err(CallError::UnknownHandle(stale)));
+
+        // Nor does a made up one.
+        let mut forged = handle.id;
+        forged.nonce = forged.nonce.wrapping_add(1);
+        let forged_handle = HandleInstance {
+            id: forged,
+            port_id_exporter: 1,
+            port_id_importer: 0,
+            returned: false,
+        };
+        assert_eq!(call(&forged_handle, METHOD_TOUCH, &(false,)), Err(CallError::UnknownHandle(forged)));
+        assert_eq!(call(handle, METHOD_TOUCH, &(false,)).unwrap(), "Touched");
+    });
+}
+
+#[test]
+fn query() {
+    let config = config(1, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    with_probe(12, config, |handle| {
+        let tally = Arc::new(TallyImpl {
+            handle: Default::default(),
+            count: Default::default(),
+        });
+        // TallyImpl dispatches both traits by itself, so it is exported as TallyAdmin without a forwarder.
+        let handle_admin = service_export!(TallyAdmin, 1, tally.clone());
+        {
+            let port_table = global::get().read();
+            let dispatcher = port_table.map.get(&1).unwrap().2.dispatcher_get();
+            let (object, trait_id) = dispatcher.service_table.read().get(handle_admin.id).unwrap();
+            assert_eq!(&*object as *const dyn Service as *const u8, &*tally as *const TallyImpl as *const u8);
+            assert_eq!(trait_id, service_id!(TallyAdmin));
+        }
+        let admin = service_import!(TallyAdmin, handle_admin);
+        assert_eq!(admin.bump(), 1);
+
+        // The exporter casts the object, and exports it again.
+        let reader = service_query!(Tally, &admin).unwrap().unwrap();
+        assert_eq!(reader.read(), 1);
+        assert_eq!(reader.read_in_units(), 10);
+        assert_eq!(admin.bump(), 2);
+        assert_eq!(reader.read(), 2);
+        let admin_again = service_query!(TallyAdmin, &reader).unwrap().unwrap();
+        assert_eq!(admin_again.bump(), 3);
+        assert!(service_query!(Probe, &reader).unwrap().is_none());
+
+        // A local object is just cast.
+        let local: Arc<dyn Tally> = tally.clone();
+        assert_eq!(service_query!(TallyAdmin, &local).unwrap().unwrap().bump(), 4);
+        assert!(service_query!(Probe, &local).unwrap().is_none());
+
+        // The probe is not a tally.
+        let probe = HandleInstance {
+            id: handle.id,
+            port_id_exporter: 1,
+            port_id_importer: 0,
+            returned: false,
+        };
+        assert_eq!(crate::service::call::query(&probe, service_id!(Tally)), Ok(None));
+
+        drop((admin, reader, admin_again, local));
+        assert_eq!(Arc::strong_count(&tally), 1);
+    });
+}
+
+#[test]
+fn generic() {
+    let config = config(1, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    with_probe(13, config, |_| {
+        assert_eq!(<Vec<String> as crate::TypeName>::type_name(), "Vec<String>");
+        assert_eq!(<Option<(u8, &str)> as crate::TypeName>::type_name(), "Option<(u8, &str)>");
+        assert_eq!(<HashMap<(u8,), Box<str>> as crate::TypeName>::type_name(), "HashMap<(u8,), Box<str>>");
+
+        // Each instance of a generic trait has its own id.
+        let mut trait_map = HashMap::new();
+        trait_map.insert("Store<String, u64>".to_owned(), 100);
+        trait_map.insert("Store<u32, String>".to_owned(), 101);
+        crate::service::id::set_trait_names(&trait_map);
+        assert_eq!(service_id!(Store<String, u64>), 100);
+        assert_eq!(service_id!(Store<u32, String>), 101);
+        // An instance unknown to the coordinator has no id, which only fails what needs it.
+        assert_eq!(
+            <dyn Store<u8, u64> as crate::service::IdOfService<dyn Store<u8, u64>>>::try_id(),
+            Err(CallError::UnknownTrait("Store<u8, u64>".to_owned()))
+        );
+        assert_eq!(service_id!(Store<u8, u64>), crate::service::id::UNKNOWN_TRAIT);
+
+        let store = service_import!(
+            Store<String, u64>,
+            service_export!(
+                Store<String, u64>,
+                1,
+                Arc::new(StoreImpl {
+                    handle: Default::default(),
+                    map: Default::default(),
+                })
+            )
+        );
+        assert_eq!(store.get_trait_id(), 100);
+        assert_eq!(store.put("a".to_owned(), 1), None);
+        assert_eq!(store.put("a".to_owned(), 2), Some(1));
+        assert_eq!(store.get(&"a".to_owned()), Some(2));
+        assert_eq!(store.get(&"b".to_owned()), None);
+    });
+}
+
+#[test]
+fn local_return() {
+    let config = config(1, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    with_probe(14, config, |_| {
+        let tally = Arc::new(TallyImpl {
+            handle: Default::default(),
+            count: Default::default(),
+        });
+        let admin = service_import!(TallyAdmin, service_export!(TallyAdmin, 1, tally.clone()));
+        assert_eq!(admin.bump(), 1);
+
+        // Another reference to the proxy still needs the handle, so it is forwarded.
+        let kept = admin.clone();
+        let forwarded = service_export!(TallyAdmin, 0, admin);
+        assert!(!forwarded.returned);
+        let admin = service_import!(TallyAdmin, forwarded);
+        assert_eq!(admin.bump(), 2);
+        drop(admin);
+
+        // The last one goes back as it is, and the exporter gets the object itself.
+        let id = kept.get_handle().id;
+        let returned = service_export!(TallyAdmin, 0, kept);
+        assert!(returned.returned);
+        assert_eq!(returned.id, id);
+        let stale = HandleInstance {
+            id,
+            port_id_exporter: returned.port_id_exporter,
+            port_id_importer: returned.port_id_importer,
+            returned: true,
+        };
+        let admin = service_import!(TallyAdmin, returned);
+        assert_eq!(admin.get_handle().port_id_importer, crate::service::UNDECIDED_PORT);
+        assert_eq!(admin.bump(), 3);
+        assert_eq!(Arc::strong_count(&tally), 2);
+
+        // The reference is taken over from the importer, so the port doesn't have it anymore.
+        assert_eq!(crate::service::dispatch::delete(1, id), Err(CallError::UnknownHandle(id)));
+
+        // Sent back again, the handle refers to no object, so the calls to it fail instead.
+        let stale = service_import!(TallyAdmin, stale);
+        assert_eq!(stale.get_handle().port_id_importer, 1);
+        let result: Result<u32, CallError> = crate::service::call::try_call(stale.get_handle(), 7, &());
+        assert!(matches!(result, Err(CallError::UnknownHandle(_))));
+
+        // An object served by itself is resolved as well.
+        let reader = service_import!(Tally, service_export!(Tally, 1, tally.clone()));
+        let reader = service_import!(Tally, service_export!(Tally, 0, reader));
+        assert_eq!(reader.read(), 3);
+        assert_eq!(reader.get_handle().port_id_importer, crate::service::UNDECIDED_PORT);
+
+        drop((admin, reader));
+        assert_eq!(Arc::strong_count(&tally), 1);
+    });
+}
+
+#[test]
+fn naming() {
+    let config = config(1, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    with_probe(15, config, |_| {
+        let tally = Arc::new(TallyImpl {
+            handle: Default::default(),
+            count: Default::default(),
+        });
+        assert!(crate::publish::<dyn TallyAdmin>("tally", tally.clone()));
+        assert!(!crate::publish::<dyn Tally>("tally", tally.clone()));
+
+        // Found in this module, it is the object itself.
+        let admin = service_lookup!(TallyAdmin, "tally").unwrap().unwrap();
+        assert_eq!(admin.bump(), 1);
+        assert_eq!(Arc::strong_count(&tally), 3);
+        drop(admin);
+        assert!(service_lookup!(Tally, "tally").unwrap().is_none());
+        assert!(service_lookup!(TallyAdmin, "nothing").unwrap().is_none());
+
+        // A linked module exports it to the asker.
+        let lookup = |name: &str, trait_id| global::get().read().map[&0].2.lookup(name, trait_id, None, None);
+        let handle = lookup("tally", service_id!(TallyAdmin)).unwrap().unwrap();
+        assert_eq!(handle.port_id_exporter, 1);
+        let admin = service_import!(TallyAdmin, handle);
+        assert_eq!(admin.bump(), 2);
+        assert_eq!(lookup("tally", service_id!(Tally)), Ok(None));
+        assert_eq!(lookup("nothing", service_id!(TallyAdmin)), Ok(None));
+
+        // Unpublishing doesn't affect the handles looked up already.
+        assert!(crate::unpublish("tally"));
+        assert!(!crate::unpublish("tally"));
+        assert_eq!(lookup("tally", service_id!(TallyAdmin)), Ok(None));
+        assert!(service_lookup!(TallyAdmin, "tally").unwrap().is_none());
+        assert_eq!(admin.bump(), 3);
+
+        drop(admin);
+        assert_eq!(Arc::strong_count(&tally), 1);
+    });
+}
+
+#[test]
+fn descriptor() {
+    let config = config(1, 1, CodecKind::Cbor, 1024, 1024 * 1024);
+    with_probe(16, config, |_| {
+        let descriptors = crate::descriptors();
+        let probe = descriptors.iter().find(|descriptor| descriptor.name == "Probe").unwrap();
+        assert_eq!(probe.id, Some(service_id!(Probe)));
+        assert_eq!(probe.methods.len(), 7);
+        let touch = probe.method(METHOD_TOUCH).unwrap();
+        assert_eq!(touch.doc, "Panics if `crash` is true");
+        assert_eq!(touch.arguments[0].name, "crash");
+        assert_eq!(touch.arguments[0].ty, "bool");
+        assert!(probe.methods.iter().any(|method| method.name == "gate" && method.asynchronous));
+        assert!(probe.methods.iter().any(|method| method.name == "open" && method.oneway));
+        assert!(probe.methods.iter().any(|method| method.name == "count" && method.stream));
+
+        let id = service_id!(Probe);
+        assert_eq!(crate::describe_method(id, METHOD_TOUCH).unwrap(), "Probe::touch(crash: bool) -> String");
+        assert_eq!(
+            crate::describe_method(id, METHOD_TOUCH + 3).unwrap(),
+            "Probe::bulk(data: &[u8], text: &str, cow: std::borrow::Cow<[u8]>) -> String"
+        );
+        assert_eq!(crate::describe_method(id, METHOD_TOUCH + 5).unwrap(), "Probe::open()");
+        assert_eq!(crate::describe_method(id, 1234), None);
+
+        // A default method runs locally, so it is not described.
+        let tally = descriptors.iter().find(|descriptor| descriptor.name == "Tally").unwrap();
+        assert_eq!(tally.methods.iter().map(|method| method.name.as_str()).collect::<Vec<_>>(), vec!["read"]);
+
+        // An instance of a generic trait is described by its name.
+        let store = descriptors.iter().find(|descriptor| descriptor.name == "Store").unwrap();
+        assert_eq!(store.id, None);
+        assert_eq!(store.type_params, vec!["K", "V"]);
+        let mut trait_map = HashMap::new();
+        trait_map.insert("Store<u8, u8>".to_owned(), 200);
+        crate::service::id::set_trait_names(&trait_map);
+        assert_eq!(
+            crate::describe_method(200, store.methods[0].id).unwrap(),
+            "Store<u8, u8>::put(key: K, value: V) -> Option<V>"
+        );
+    });
+}
+
+#[test]
+fn dynamic() {
+    use serde_json::json;
+    // Bincode can't tell the types by itself, so the values are converted as their shapes.
+    let config = config(1, 1, CodecKind::Bincode, 1024, 1024 * 1024);
+    with_probe(17, config, |handle| {
+        let trait_map =
+            crate::service::id::TID_REG.iter().enumerate().map(|(i, (name, _))| ((*name).to_owned(), i as TraitId));
+        let invoker = Invoker::new(vec![schema()], IdMap {
+            trait_map: trait_map.collect(),
+            method_map: HashMap::new(),
+        });
+        let probe = invoker.adopt("Probe", HandleInstance {
+            id: handle.id,
+            port_id_exporter: 1,
+            port_id_importer: 0,
+            returned: false,
+        });
+        let probe = probe["handle"].as_u64().unwrap();
+
+        assert_eq!(invoker.invoke(probe, "touch", &[json!(false)]), Ok(json!("Touched")));
+        match invoker.invoke(probe, "touch", &[json!(true)]) {
+            Err(InvokeError::Call(CallError::RemotePanic(msg))) => assert!(msg.contains("Crashed as requested")),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        let bulk = invoker.invoke(probe, "bulk", &[json!([1, 2]), json!("text"), json!([3])]).unwrap();
+        assert!(bulk.as_str().unwrap().starts_with("[1, 2] text [3]"));
+        assert_eq!(invoker.invoke(probe, "count", &[json!(3), json!(false)]), Ok(json!([0, 1, 2])));
+        assert_eq!(invoker.invoke(probe, "open", &[]), Ok(json!(null)));
+
+        assert!(matches!(invoker.invoke(probe, "touch", &[json!("yes")]), Err(InvokeError::Arguments(_))));
+        assert!(matches!(invoker.invoke(probe, "touch", &[]), Err(InvokeError::Arguments(_))));
+        assert!(matches!(invoker.invoke(probe, "fly", &[]), Err(InvokeError::UnknownMethod(..))));
+        assert_eq!(invoker.invoke(probe + 100, "touch", &[json!(false)]), Err(InvokeError::UnknownHandle(probe + 100)));
+
+        // A published service is found by the names as well.
+        let tally = Arc::new(TallyImpl {
+            handle: Default::default(),
+            count: Default::default(),
+        });
+        assert!(crate::publish::<dyn TallyAdmin>("tally", tally.clone()));
+        let admin = invoker.lookup("tally", "TallyAdmin").unwrap().unwrap();
+        assert_eq!(admin["trait"], "TallyAdmin");
+        let admin = admin["handle"].as_u64().unwrap();
+        assert_eq!(invoker.invoke(admin, "bump", &[]), Ok(json!(1)));
+        assert_eq!(invoker.lookup("tally", "Tally"), Ok(None));
+        assert_eq!(invoker.lookup("tally", "Nothing"), Err(InvokeError::UnknownTrait("Nothing".to_owned())));
+        assert!(crate::unpublish("tally"));
+
+        assert_eq!(invoker.release(admin), Ok(()));
+        assert_eq!(invoker.release(admin), Err(InvokeError::UnknownHandle(admin)));
+        assert_eq!(Arc::strong_count(&tally), 1);
+    });
+}